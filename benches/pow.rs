@@ -0,0 +1,34 @@
+//! Times the README's exponentiation example with and without stack caching.
+//!
+//! Run with `cargo bench --bench pow`.
+
+use simple_math_compiler_rs::function::{Function, FunctionBuilder};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const POW: &str = "a 1 b { p2 p2 * s1 1 - } p1";
+const EXPONENT: isize = 10_000_000;
+const RUNS: usize = 20;
+
+/// The fastest of `RUNS` calls, to keep out noise from the rest of the system
+fn time(f: &Function) -> Duration {
+    (0..RUNS).map(|_| {
+        let start = Instant::now();
+        black_box(f.call(&[black_box(3), black_box(EXPONENT)]).unwrap());
+        start.elapsed()
+    }).min().unwrap()
+}
+
+fn main() {
+    let plain = Function::parse(POW).unwrap();
+    let cached = FunctionBuilder::new().stack_caching(true).parse(POW).unwrap();
+    assert_eq!(plain.call(&[3, 1000]), cached.call(&[3, 1000]));
+
+    let plain_time = time(&plain);
+    let cached_time = time(&cached);
+    let per_iteration = |time: Duration| time.as_secs_f64() * 1e9 / EXPONENT as f64;
+    println!("{}, {} iterations:", POW, EXPONENT);
+    println!("  memory stack:  {:.2} ns/iteration", per_iteration(plain_time));
+    println!("  stack caching: {:.2} ns/iteration", per_iteration(cached_time));
+    println!("  speedup: {:.2}x", plain_time.as_secs_f64() / cached_time.as_secs_f64());
+}
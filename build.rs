@@ -13,6 +13,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     code_segments_path.push(target);
     code_segments_path.set_extension("S");
 
+    println!("cargo:rerun-if-changed=function_errors.csv");
+    println!("cargo:rerun-if-changed={}", code_segments_path.display());
+
     let out_dir = std::env::var_os("OUT_DIR").ok_or("OUT_DIR not set")?;
     let out_dir = std::path::Path::new(&out_dir);
 
@@ -78,9 +81,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     function_errors_asm.flush()?;
     function_errors_rs.flush()?;
 
-    cc::Build::new()
-        .include(out_dir)
-        .file(code_segments_path)
-        .compile("code_segments");
+    // x86_64 code is emitted at runtime, so its code segments are only assembled to test against
+    let target_arch = std::env::var("CARGO_CFG_TARGET_ARCH")?;
+    if target_arch != "x86_64" || std::env::var_os("CARGO_FEATURE_ASM_REFERENCE").is_some() {
+        cc::Build::new()
+            .include(out_dir)
+            .file(code_segments_path)
+            .compile("code_segments");
+    }
     Ok(())
 }
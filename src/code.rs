@@ -1,7 +1,7 @@
 use std::{borrow::Cow, collections::HashMap};
 
 pub mod arch;
-pub(crate) use arch::RelocationKind;
+pub(crate) use arch::{peephole, RelocationKind};
 
 mod symbol;
 pub(crate) use symbol::Symbol;
@@ -85,7 +85,25 @@ pub enum AssembleError {
     InvalidRelocation(&'static str)
 }
 
+impl std::fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssembleError::UndefinedSymbol(sym) => write!(f, "Undefined symbol {}", sym),
+            AssembleError::MultiplyDefinedSymbol(sym) => write!(f, "Symbol {} defined multiple times", sym),
+            AssembleError::InvalidRelocation(msg) => write!(f, "Invalid relocation: {}", msg),
+        }
+    }
+}
+
 impl Relocatable {
+    /// An empty `Relocatable` that defines `symbol` at its start
+    pub(crate) fn label(symbol: Symbol) -> Self {
+        Self {
+            symbols: vec![(symbol, 0)],
+            ..Self::default()
+        }
+    }
+
     pub fn assemble(&self) -> Result<Vec<u8>, AssembleError> {
         enum Value {
             Relative(usize),
@@ -0,0 +1,246 @@
+use crate::code::{AssembleError, Relocatable};
+
+/// The peephole pass only knows x86_64 instructions
+pub(crate) fn peephole(code: Relocatable) -> Relocatable {
+    code
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RelocationKind {
+    None = 0,
+    Abs64 = 257,
+    MovwG0 = 264,
+    MovwG1 = 266,
+    AdrpAdd = 275,
+    CondBr19 = 280,
+    Jump26 = 282,
+}
+
+/// The 4-byte little-endian instruction at `location`
+fn instruction_at(data: &mut [u8], location: usize) -> Result<&mut [u8; 4], AssembleError> {
+    Ok(data.get_mut(location..location+4)
+        .ok_or(AssembleError::InvalidRelocation("Attempted to apply relocation past end of section"))?
+        .try_into().unwrap())
+}
+
+/// Replaces the `width` bits of the instruction at `location` starting at bit `shift` with the low bits of `field`
+fn set_field(data: &mut [u8], location: usize, shift: u32, width: u32, field: u32) -> Result<(), AssembleError> {
+    let instruction = instruction_at(data, location)?;
+    let mask = ((1u32 << width) - 1) << shift;
+    let value = (u32::from_le_bytes(*instruction) & !mask) | ((field << shift) & mask);
+    *instruction = value.to_le_bytes();
+    Ok(())
+}
+
+/// `value >> 2` as a signed `bits`-bit field, for branches
+fn branch_offset(value: isize, bits: u32) -> Result<u32, AssembleError> {
+    if value & 3 != 0 {
+        return Err(AssembleError::InvalidRelocation("Relative relocation cut off low bits"));
+    }
+    let offset = value >> 2;
+    let limit = 1isize << (bits - 1);
+    if !(-limit..limit).contains(&offset) {
+        return Err(AssembleError::InvalidRelocation("Relative relocation difference too large"));
+    }
+    Ok(offset as u32)
+}
+
+impl RelocationKind {
+    /// Addend has already been applied to value
+    pub(crate) fn apply_relative(self, data: &mut [u8], location: usize, value: usize) -> Result<(), AssembleError> {
+        use RelocationKind::*;
+        let difference: isize = if location < value {
+            (value - location).try_into().ok().ok_or(AssembleError::InvalidRelocation("Relative relocation difference too large"))?
+        } else {
+            -(location - value).try_into().ok().ok_or(AssembleError::InvalidRelocation("Relative relocation difference too large"))?
+        };
+        match self {
+            None => Ok(()),
+            Jump26 => {
+                // Low 26 bits of the instruction contain bits 27-2 of the pc-relative address (sign-extended)
+                set_field(data, location, 0, 26, branch_offset(difference, 26)?)
+            },
+            CondBr19 => {
+                // Bits 23-5 of the instruction contain bits 20-2 of the pc-relative address (sign-extended)
+                set_field(data, location, 5, 19, branch_offset(difference, 19)?)
+            },
+            AdrpAdd => {
+                // adrp at location loads the page of the value relative to the page of location,
+                // and the add after it adds the low 12 bits of the value.
+                // The code is mapped at a page boundary, so offsets within it have the same page offsets as addresses
+                let page_difference = (value & !0xFFF) as isize - (location & !0xFFF) as isize;
+                let pages = page_difference >> 12;
+                if !(-(1 << 20)..(1 << 20)).contains(&pages) {
+                    return Err(AssembleError::InvalidRelocation("Relative relocation difference too large"));
+                }
+                instruction_at(data, location + 4)?;
+                // immlo is bits 30-29 and immhi is bits 23-5
+                set_field(data, location, 29, 2, pages as u32)?;
+                set_field(data, location, 5, 19, (pages >> 2) as u32)?;
+                set_field(data, location + 4, 10, 12, (value & 0xFFF) as u32)
+            },
+            Abs64 | MovwG0 | MovwG1 => Err(AssembleError::InvalidRelocation("Cannot apply direct relocation for relative symbol")),
+        }
+    }
+    pub(crate) fn apply_absolute(self, data: &mut [u8], location: usize, value: isize) -> Result<(), AssembleError> {
+        use RelocationKind::*;
+        match self {
+            None => Ok(()),
+            Jump26 | CondBr19 | AdrpAdd => Err(AssembleError::InvalidRelocation("Cannot apply relative relocation for absolute symbol")),
+            Abs64 => {
+                let bytes: &mut [u8; 8] = data.get_mut(location..location+8)
+                    .ok_or(AssembleError::InvalidRelocation("Attempted to apply relocation past end of section"))?
+                    .try_into().unwrap();
+                *bytes = i64::to_le_bytes(value as i64);
+                Ok(())
+            },
+            MovwG0 | MovwG1 => {
+                // The pair of 16-bit halves of a 32-bit value, which is sign- or zero-extended by the code using it.
+                // Bits 20-5 of the movz/movk instruction contain the half
+                let value: u32 = i32::try_from(value).map(|value| value as u32)
+                    .or_else(|_| u32::try_from(value))
+                    .ok().ok_or(AssembleError::InvalidRelocation("Movw relocation value out of range"))?;
+                let half = if self == MovwG0 { value & 0xFFFF } else { value >> 16 };
+                set_field(data, location, 5, 16, half)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn jump26() {
+        use super::*;
+        // b . ; bl .
+        let mut data = [0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x94];
+        RelocationKind::Jump26.apply_relative(&mut data, 0, 0x100).unwrap();
+        assert_eq!(data[..4], [0x40, 0x00, 0x00, 0x14]);
+        RelocationKind::Jump26.apply_relative(&mut data, 4, 0).unwrap();
+        assert_eq!(data[4..], [0xff, 0xff, 0xff, 0x97]);
+        RelocationKind::Jump26.apply_relative(&mut data, 0, 0x7fffffc).unwrap();
+        assert_eq!(data[..4], [0xff, 0xff, 0xff, 0x15]);
+
+        for value in [2, 0x8000000] {
+            assert!(matches!(
+                RelocationKind::Jump26.apply_relative(&mut data, 0, value),
+                Err(AssembleError::InvalidRelocation(_))
+            ));
+        }
+        assert!(matches!(
+            RelocationKind::Jump26.apply_relative(&mut data, 6, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+        assert!(matches!(
+            RelocationKind::Jump26.apply_absolute(&mut data, 0, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+    }
+
+    #[test]
+    fn cond_br19() {
+        use super::*;
+        // cbz x0,. ; b.ge .
+        let mut data = [0x00, 0x00, 0x00, 0xb4, 0x0a, 0x00, 0x00, 0x54];
+        RelocationKind::CondBr19.apply_relative(&mut data, 0, 0x40).unwrap();
+        assert_eq!(data[..4], [0x00, 0x02, 0x00, 0xb4]);
+        RelocationKind::CondBr19.apply_relative(&mut data, 4, 0).unwrap();
+        assert_eq!(data[4..], [0xea, 0xff, 0xff, 0x54]);
+        RelocationKind::CondBr19.apply_relative(&mut data, 0, 0xffffc).unwrap();
+        assert_eq!(data[..4], [0xe0, 0xff, 0x7f, 0xb4]);
+
+        for value in [2, 0x100000] {
+            assert!(matches!(
+                RelocationKind::CondBr19.apply_relative(&mut data, 0, value),
+                Err(AssembleError::InvalidRelocation(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn adrp_add() {
+        use super::*;
+        // adrp x0,. ; add x0,x0,#0
+        let mut data = vec![0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x91];
+        RelocationKind::AdrpAdd.apply_relative(&mut data, 0, 0x10).unwrap();
+        assert_eq!(data, [0x00, 0x00, 0x00, 0x90, 0x00, 0x40, 0x00, 0x91]);
+        // adrp x0,.+0x1000 ; add x0,x0,#0x234
+        RelocationKind::AdrpAdd.apply_relative(&mut data, 0, 0x1234).unwrap();
+        assert_eq!(data, [0x00, 0x00, 0x00, 0xb0, 0x00, 0xd0, 0x08, 0x91]);
+        // adrp x0,.-0x2000 ; add x0,x0,#0xff8
+        data.splice(0..0, vec![0; 0x3000]);
+        RelocationKind::AdrpAdd.apply_relative(&mut data, 0x3000, 0x1ff8).unwrap();
+        assert_eq!(data[0x3000..], [0xe0, 0xff, 0xff, 0xd0, 0x00, 0xe0, 0x3f, 0x91]);
+
+        assert!(matches!(
+            RelocationKind::AdrpAdd.apply_relative(&mut data, 0x3004, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+        assert!(matches!(
+            RelocationKind::AdrpAdd.apply_absolute(&mut data, 0, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+    }
+
+    #[test]
+    fn movw() {
+        use super::*;
+        // movz w1,#0 ; movk w1,#0,lsl#16
+        let mut data = [0x01, 0x00, 0x80, 0x52, 0x01, 0x00, 0xa0, 0x72];
+        RelocationKind::MovwG0.apply_absolute(&mut data, 0, 0x12345678).unwrap();
+        RelocationKind::MovwG1.apply_absolute(&mut data, 4, 0x12345678).unwrap();
+        assert_eq!(data, [0x01, 0xcf, 0x8a, 0x52, 0x81, 0x46, 0xa2, 0x72]);
+        RelocationKind::MovwG0.apply_absolute(&mut data, 0, -88).unwrap();
+        RelocationKind::MovwG1.apply_absolute(&mut data, 4, -88).unwrap();
+        assert_eq!(data, [0x01, 0xf5, 0x9f, 0x52, 0xe1, 0xff, 0xbf, 0x72]);
+        RelocationKind::MovwG1.apply_absolute(&mut data, 4, 0xffffffff).unwrap();
+        assert_eq!(data[4..], [0xe1, 0xff, 0xbf, 0x72]);
+
+        for value in [0x100000000, -0x80000001] {
+            assert!(matches!(
+                RelocationKind::MovwG0.apply_absolute(&mut data, 0, value),
+                Err(AssembleError::InvalidRelocation(_))
+            ));
+        }
+        assert!(matches!(
+            RelocationKind::MovwG0.apply_relative(&mut data, 0, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+    }
+
+    #[test]
+    fn abs64() {
+        use super::*;
+        let mut data = [0; 12];
+        RelocationKind::Abs64.apply_absolute(&mut data, 4, -2).unwrap();
+        assert_eq!(data, [0, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert!(matches!(
+            RelocationKind::Abs64.apply_absolute(&mut data, 8, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+        assert!(matches!(
+            RelocationKind::Abs64.apply_relative(&mut data, 0, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+    }
+
+    // Relocatables hold the host's relocation kinds
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn abort_branches() {
+        use super::*;
+        use crate::code::{Relocatable, Relocation, Symbol};
+        // b.vs 0f ; b . ; 0: cbz x3,. ; <abort>
+        let mut code = Relocatable {
+            data: vec![0x46, 0x00, 0x00, 0x54, 0x00, 0x00, 0x00, 0x14, 0x03, 0x00, 0x00, 0xb4].into(),
+            relocations: vec![
+                Relocation::new(4, RelocationKind::Jump26, Symbol::abort(), 0),
+                Relocation::new(8, RelocationKind::CondBr19, Symbol::abort(), 0),
+            ],
+            ..Relocatable::default()
+        };
+        code += Relocatable::label(Symbol::abort());
+        assert_eq!(code.assemble().unwrap(), [0x46, 0x00, 0x00, 0x54, 0x02, 0x00, 0x00, 0x14, 0x23, 0x00, 0x00, 0xb4]);
+    }
+}
@@ -1,4 +1,9 @@
-use crate::code::AssembleError;
+use crate::code::{AssembleError, Relocatable};
+
+/// The peephole pass only knows x86_64 instructions
+pub(crate) fn peephole(code: Relocatable) -> Relocatable {
+    code
+}
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -84,4 +89,52 @@ impl RelocationKind {
             },
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn jump24() {
+        use super::*;
+        // bvs .
+        let mut data = [0xfe, 0xff, 0xff, 0x6a, 0xfe, 0xff, 0xff, 0xea];
+        RelocationKind::Jump24.apply_relative(&mut data, 0, 0x100).unwrap();
+        assert_eq!(data[..4], [0x40, 0x00, 0x00, 0x6a]);
+        RelocationKind::Jump24.apply_relative(&mut data, 4, 0).unwrap();
+        assert_eq!(data[4..], [0xff, 0xff, 0xff, 0xea]);
+        RelocationKind::Jump24.apply_relative(&mut data, 0, 0x1fffffc).unwrap();
+        assert_eq!(data[..4], [0xff, 0xff, 0x7f, 0x6a]);
+
+        for value in [2, 0x2000000] {
+            assert!(matches!(
+                RelocationKind::Jump24.apply_relative(&mut data, 0, value),
+                Err(AssembleError::InvalidRelocation(_))
+            ));
+        }
+        assert!(matches!(
+            RelocationKind::Jump24.apply_relative(&mut data, 6, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+        assert!(matches!(
+            RelocationKind::Jump24.apply_absolute(&mut data, 0, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+    }
+
+    #[test]
+    fn abort_branches() {
+        use super::*;
+        use crate::code::{Relocatable, Relocation, Symbol};
+        // bvs . ; bne . ; <abort>
+        let mut code = Relocatable {
+            data: vec![0xfe, 0xff, 0xff, 0x6a, 0xfe, 0xff, 0xff, 0x1a].into(),
+            relocations: vec![
+                Relocation::new(0, RelocationKind::Jump24, Symbol::abort(), -8),
+                Relocation::new(4, RelocationKind::Jump24, Symbol::abort(), -8),
+            ],
+            ..Relocatable::default()
+        };
+        code += Relocatable::label(Symbol::abort());
+        assert_eq!(code.assemble().unwrap(), [0x00, 0x00, 0x00, 0x6a, 0xff, 0xff, 0xff, 0x1a]);
+    }
+}
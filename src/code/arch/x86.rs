@@ -1,4 +1,9 @@
-use crate::code::AssembleError;
+use crate::code::{AssembleError, Relocatable};
+
+/// The peephole pass only knows x86_64 instructions
+pub(crate) fn peephole(code: Relocatable) -> Relocatable {
+    code
+}
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -31,6 +36,60 @@ impl RelocationKind {
         }
     }
     pub(crate) fn apply_absolute(self, data: &mut [u8], location: usize, value: isize) -> Result<(), AssembleError> {
-        todo!()
+        use RelocationKind::*;
+        match self {
+            None => Ok(()),
+            Direct32 => {
+                let reloc_slice: &mut [u8; 4] = data.get_mut(location..location+4)
+                    .ok_or(AssembleError::InvalidRelocation("Attempted to apply relocation past end of section"))?
+                    .try_into().unwrap();
+                // Addresses are unsigned and immediates are signed, so accept either range
+                let actual_value: u32 = match i32::try_from(value) {
+                    Ok(value) => value as u32,
+                    Err(_) => value.try_into().ok().ok_or(AssembleError::InvalidRelocation("Direct32 relocation value out of range"))?,
+                };
+                *reloc_slice = u32::to_ne_bytes(actual_value);
+                Ok(())
+            },
+            Pc32 => Err(AssembleError::InvalidRelocation("Cannot apply relative relocation for absolute symbol")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn direct32() {
+        use super::*;
+        let mut data = [0x90u8; 6];
+        RelocationKind::Direct32.apply_absolute(&mut data, 1, 0x12345678).unwrap();
+        assert_eq!(data, [0x90, 0x78, 0x56, 0x34, 0x12, 0x90]);
+        RelocationKind::Direct32.apply_absolute(&mut data, 2, -2).unwrap();
+        assert_eq!(data, [0x90, 0x78, 0xfe, 0xff, 0xff, 0xff]);
+        assert!(matches!(
+            RelocationKind::Direct32.apply_absolute(&mut data, 3, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+        assert!(matches!(
+            RelocationKind::Pc32.apply_absolute(&mut data, 1, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+    }
+
+    #[test]
+    fn absolute_symbols() {
+        use super::*;
+        use crate::code::{Relocatable, Relocation, Symbol};
+        let symbol = Symbol::new_local();
+        let code = Relocatable {
+            data: vec![0u8; 8].into(),
+            abs_symbols: vec![(symbol.clone(), 0x1000)],
+            relocations: vec![
+                Relocation::new(0, RelocationKind::Direct32, symbol.clone(), 0x10),
+                Relocation::new(4, RelocationKind::Direct32, symbol, -0x1001),
+            ],
+            ..Relocatable::default()
+        };
+        assert_eq!(code.assemble().unwrap(), [0x10, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff]);
     }
 }
\ No newline at end of file
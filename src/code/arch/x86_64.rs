@@ -1,5 +1,8 @@
 use crate::code::AssembleError;
 
+mod peephole;
+pub(crate) use peephole::peephole;
+
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum RelocationKind {
@@ -7,7 +10,10 @@ pub(crate) enum RelocationKind {
     Direct64 = 1,
     Pc32 = 2,
 
+    // No command emits 32-bit absolute addresses yet, but the kinds are kept and tested alongside their ELF numbers
+    #[allow(dead_code)]
     Direct32 = 10,
+    #[allow(dead_code)]
     Direct32S = 11,
 }
 
@@ -34,6 +40,115 @@ impl RelocationKind {
         }
     }
     pub(crate) fn apply_absolute(self, data: &mut [u8], location: usize, value: isize) -> Result<(), AssembleError> {
-        todo!()
+        use RelocationKind::*;
+        match self {
+            None => Ok(()),
+            Direct64 => {
+                let reloc_slice: &mut [u8; 8] = data.get_mut(location..location+8)
+                    .ok_or(AssembleError::InvalidRelocation("Attempted to apply relocation past end of section"))?
+                    .try_into().unwrap();
+                *reloc_slice = i64::to_ne_bytes(value as i64);
+                Ok(())
+            },
+            Direct32 => {
+                let reloc_slice: &mut [u8; 4] = data.get_mut(location..location+4)
+                    .ok_or(AssembleError::InvalidRelocation("Attempted to apply relocation past end of section"))?
+                    .try_into().unwrap();
+                // Zero-extended when loaded, so the value must fit in a u32
+                let actual_value: u32 = value.try_into().ok().ok_or(AssembleError::InvalidRelocation("Direct32 relocation value out of range"))?;
+                *reloc_slice = u32::to_ne_bytes(actual_value);
+                Ok(())
+            },
+            Direct32S => {
+                let reloc_slice: &mut [u8; 4] = data.get_mut(location..location+4)
+                    .ok_or(AssembleError::InvalidRelocation("Attempted to apply relocation past end of section"))?
+                    .try_into().unwrap();
+                // Sign-extended when loaded, so the value must fit in an i32
+                let actual_value: i32 = value.try_into().ok().ok_or(AssembleError::InvalidRelocation("Direct32S relocation value out of range"))?;
+                *reloc_slice = i32::to_ne_bytes(actual_value);
+                Ok(())
+            },
+            Pc32 => Err(AssembleError::InvalidRelocation("Cannot apply relative relocation for absolute symbol")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn direct64() {
+        use super::*;
+        let mut data = [0x90u8; 10];
+        RelocationKind::Direct64.apply_absolute(&mut data, 1, 0x1122334455667788).unwrap();
+        assert_eq!(data, [0x90, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x90]);
+        RelocationKind::Direct64.apply_absolute(&mut data, 2, -2).unwrap();
+        assert_eq!(data, [0x90, 0x88, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert!(matches!(
+            RelocationKind::Direct64.apply_absolute(&mut data, 3, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+    }
+
+    #[test]
+    fn direct32() {
+        use super::*;
+        let mut data = [0x90u8; 6];
+        RelocationKind::Direct32.apply_absolute(&mut data, 1, 0xdeadbeef).unwrap();
+        assert_eq!(data, [0x90, 0xef, 0xbe, 0xad, 0xde, 0x90]);
+        RelocationKind::Direct32.apply_absolute(&mut data, 2, 0).unwrap();
+        assert_eq!(data, [0x90, 0xef, 0x00, 0x00, 0x00, 0x00]);
+        for value in [-1, 1 << 32] {
+            assert!(matches!(
+                RelocationKind::Direct32.apply_absolute(&mut data, 1, value),
+                Err(AssembleError::InvalidRelocation(_))
+            ));
+        }
+        assert!(matches!(
+            RelocationKind::Direct32.apply_absolute(&mut data, 3, 0),
+            Err(AssembleError::InvalidRelocation(_))
+        ));
+    }
+
+    #[test]
+    fn direct32s() {
+        use super::*;
+        let mut data = [0x90u8; 6];
+        RelocationKind::Direct32S.apply_absolute(&mut data, 1, -2).unwrap();
+        assert_eq!(data, [0x90, 0xfe, 0xff, 0xff, 0xff, 0x90]);
+        RelocationKind::Direct32S.apply_absolute(&mut data, 2, 0x7fffffff).unwrap();
+        assert_eq!(data, [0x90, 0xfe, 0xff, 0xff, 0xff, 0x7f]);
+        for value in [0x80000000, -0x80000001] {
+            assert!(matches!(
+                RelocationKind::Direct32S.apply_absolute(&mut data, 1, value),
+                Err(AssembleError::InvalidRelocation(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn absolute_symbols() {
+        use super::*;
+        use crate::code::{Relocatable, Relocation, Symbol};
+        let symbol = Symbol::new_local();
+        let code = Relocatable {
+            data: vec![0u8; 16].into(),
+            abs_symbols: vec![(symbol.clone(), 0x1000)],
+            relocations: vec![
+                Relocation::new(0, RelocationKind::Direct64, symbol.clone(), 0x10),
+                Relocation::new(8, RelocationKind::Direct32, symbol.clone(), 0),
+                Relocation::new(12, RelocationKind::Direct32S, symbol.clone(), -0x1001),
+            ],
+            ..Relocatable::default()
+        };
+        assert_eq!(code.assemble().unwrap(), [
+            0x10, 0x10, 0, 0, 0, 0, 0, 0,
+            0x00, 0x10, 0, 0,
+            0xff, 0xff, 0xff, 0xff,
+        ]);
+        let code = Relocatable {
+            relocations: vec![Relocation::new(0, RelocationKind::Direct32, symbol, -0x1001)],
+            ..code
+        };
+        assert!(matches!(code.assemble(), Err(AssembleError::InvalidRelocation(_))));
     }
 }
\ No newline at end of file
@@ -0,0 +1,289 @@
+use crate::code::Relocatable;
+
+/// What the peephole pass needs to know about an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// `push %reg`
+    PushReg(u8),
+    /// `push mem`, whose REX prefix (0 if none) and ModRM byte start at the given offset
+    PushMem { rex: u8, modrm: usize },
+    /// `pop %reg`
+    PopReg(u8),
+    /// A jump or call, whose offset starts at the given offset and is one byte long if `short`
+    Branch { offset: usize, short: bool },
+    Other,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Instruction {
+    start: usize,
+    end: usize,
+    kind: Kind,
+}
+
+/// The length of a ModRM byte and what follows it, or `None` for `%rip`-relative operands, which cannot be moved
+fn modrm_len(bytes: &[u8]) -> Option<usize> {
+    let modrm = *bytes.first()?;
+    let (mode, rm) = (modrm >> 6, modrm & 7);
+    if mode == 3 {
+        return Some(1);
+    }
+    let mut len = 1;
+    if rm == 4 {
+        let sib = *bytes.get(1)?;
+        len += 1;
+        if mode == 0 && sib & 7 == 5 {
+            len += 4;
+        }
+    } else if mode == 0 && rm == 5 {
+        return None;
+    }
+    len += match mode {
+        1 => 1,
+        2 => 4,
+        _ => 0,
+    };
+    Some(len)
+}
+
+/// Decodes the instruction at the start of `bytes`, if it is one `Emitter` can produce
+fn decode(bytes: &[u8]) -> Option<(usize, Kind)> {
+    if bytes.starts_with(&[0xF3, 0x0F, 0x1E, 0xFA]) {
+        return Some((4, Kind::Other));
+    }
+    let rex = match bytes.first()? {
+        &rex @ 0x40..=0x4F => rex,
+        _ => 0,
+    };
+    let opcode_start = (rex != 0) as usize;
+    let opcode = *bytes.get(opcode_start)?;
+    let rest = opcode_start + 1;
+    let with_modrm = |immediate: usize| Some((rest + modrm_len(&bytes[rest..])? + immediate, Kind::Other));
+    let (len, kind) = match opcode {
+        0x50..=0x57 => (rest, Kind::PushReg((opcode - 0x50) | (rex & 1) << 3)),
+        0x58..=0x5F => (rest, Kind::PopReg((opcode - 0x58) | (rex & 1) << 3)),
+        0x01 | 0x03 | 0x09 | 0x0B | 0x21 | 0x23 | 0x29 | 0x2B | 0x31 | 0x33 | 0x39 | 0x3B
+            | 0x85 | 0x89 | 0x8B | 0x8D | 0x8F | 0xD3 => with_modrm(0)?,
+        // `0xF7 /0` takes an immediate, and is never emitted
+        0xF7 if bytes.get(rest)? >> 3 & 7 != 0 => with_modrm(0)?,
+        0xFF => {
+            let (len, _) = with_modrm(0)?;
+            let modrm = bytes[rest];
+            match modrm >> 3 & 7 {
+                6 if modrm >> 6 != 3 => (len, Kind::PushMem { rex, modrm: rest }),
+                _ => (len, Kind::Other),
+            }
+        },
+        0x83 => with_modrm(1)?,
+        0x81 | 0xC7 => with_modrm(4)?,
+        0xB8..=0xBF if rex & 8 != 0 => (rest + 8, Kind::Other),
+        0xB8..=0xBF => (rest + 4, Kind::Other),
+        0x99 | 0xC3 => (rest, Kind::Other),
+        0x70..=0x7F | 0xE2 | 0xEB => (rest + 1, Kind::Branch { offset: rest, short: true }),
+        0xE8 | 0xE9 => (rest + 4, Kind::Branch { offset: rest, short: false }),
+        0x0F => match *bytes.get(rest)? {
+            0x80..=0x8F => (rest + 5, Kind::Branch { offset: rest + 1, short: false }),
+            0xAF | 0x90..=0x9F => (rest + 1 + modrm_len(&bytes[rest + 1..])?, Kind::Other),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    (len <= bytes.len()).then_some((len, kind))
+}
+
+/// The code which does the same as `push`, then `pop %reg`
+fn replacement(data: &[u8], push: &Instruction, reg: u8) -> Option<Vec<u8>> {
+    let rex_r = ((reg >= 8) as u8) << 2;
+    match push.kind {
+        Kind::PushReg(source) if source == reg => Some(vec![]),
+        // mov %source,%reg
+        Kind::PushReg(source) => Some(vec![0x48 | ((source >= 8) as u8) << 2 | (reg >= 8) as u8, 0x89, 0xC0 | (source & 7) << 3 | (reg & 7)]),
+        // mov mem,%reg, keeping the index and base bits of the prefix
+        Kind::PushMem { rex, modrm } => {
+            let modrm_byte = data[push.start + modrm];
+            let mut code = vec![0x48 | rex_r | (rex & 3), 0x8B, modrm_byte & 0xC7 | (reg & 7) << 3];
+            code.extend_from_slice(&data[push.start + modrm + 1..push.end]);
+            Some(code)
+        },
+        _ => None,
+    }
+}
+
+/// One pass over `code`, or `None` if it could not be decoded or nothing changed
+fn peephole_pass(code: &Relocatable) -> Option<Relocatable> {
+    let data = &code.data[..];
+    let mut instructions = vec![];
+    let mut start = 0;
+    while start < data.len() {
+        let (len, kind) = decode(&data[start..])?;
+        instructions.push(Instruction { start, end: start + len, kind });
+        start += len;
+    }
+    let relocated = |location: usize| code.relocations.iter().any(|relocation| relocation.location == location);
+    // Where each jump which is not relocated goes
+    let mut targets = vec![None; instructions.len()];
+    for (target, instruction) in targets.iter_mut().zip(&instructions) {
+        if let Kind::Branch { offset, short } = instruction.kind {
+            let location = instruction.start + offset;
+            if relocated(location) {
+                continue;
+            }
+            let displacement = match short {
+                true => data[location] as i8 as isize,
+                false => i32::from_le_bytes(data[location..location + 4].try_into().unwrap()) as isize,
+            };
+            let destination = instruction.end.checked_add_signed(displacement).filter(|&destination| destination <= data.len())?;
+            *target = Some(destination);
+        }
+    }
+    // A pop which is jumped to or labelled must stay where it is
+    let is_entered = |location: usize| {
+        code.symbols.iter().any(|&(_, symbol_location)| symbol_location == location)
+            || targets.contains(&Some(location))
+    };
+    let has_relocation = |start: usize, end: usize| {
+        code.relocations.iter().any(|relocation| (start..end).contains(&relocation.location))
+    };
+
+    let mut new_data = Vec::with_capacity(data.len());
+    // The new start of each instruction
+    let mut new_starts = Vec::with_capacity(instructions.len() + 1);
+    let mut changed = false;
+    let mut index = 0;
+    while index < instructions.len() {
+        let instruction = &instructions[index];
+        if let (Kind::PushReg(_) | Kind::PushMem { .. }, Some(&Instruction { start, end, kind: Kind::PopReg(reg) })) = (instruction.kind, instructions.get(index + 1)) {
+            if !is_entered(start) && !has_relocation(instruction.start, end) {
+                if let Some(code) = replacement(data, instruction, reg) {
+                    new_starts.push(new_data.len());
+                    new_data.extend_from_slice(&code);
+                    new_starts.push(new_data.len());
+                    changed = true;
+                    index += 2;
+                    continue;
+                }
+            }
+        }
+        new_starts.push(new_data.len());
+        new_data.extend_from_slice(&data[instruction.start..instruction.end]);
+        index += 1;
+    }
+    if !changed {
+        return None;
+    }
+    new_starts.push(new_data.len());
+
+    // Positions within an instruction keep their offset from its start
+    let new_location = |location: usize| {
+        let index = instructions.partition_point(|instruction| instruction.start <= location).saturating_sub(1);
+        match instructions.get(index) {
+            Some(instruction) if location < instruction.end => new_starts[index] + (location - instruction.start),
+            _ => new_starts[instructions.len()],
+        }
+    };
+    for ((instruction, target), &new_start) in instructions.iter().zip(&targets).zip(&new_starts) {
+        let (Kind::Branch { offset, short }, Some(target)) = (instruction.kind, target) else { continue };
+        let new_end = new_start + (instruction.end - instruction.start);
+        let displacement = new_location(*target) as isize - new_end as isize;
+        let location = new_start + offset;
+        if short {
+            new_data[location] = i8::try_from(displacement).ok()? as u8;
+        } else {
+            let displacement = i32::try_from(displacement).ok()?;
+            new_data[location..location + 4].copy_from_slice(&displacement.to_le_bytes());
+        }
+    }
+
+    let mut new_code = code.clone();
+    new_code.data = new_data.into();
+    for (_, location) in &mut new_code.symbols {
+        *location = new_location(*location);
+    }
+    for relocation in &mut new_code.relocations {
+        relocation.location = new_location(relocation.location);
+    }
+    Some(new_code)
+}
+
+/// Removes `push` instructions which are immediately popped into a register, moving the value
+/// into the register instead, and keeps the symbols, relocations, and jumps in `code` pointing at the same instructions.
+///
+/// Pairs whose `pop` is labelled or jumped to are kept. `code` is returned unchanged if it has instructions the pass does not know.
+pub(crate) fn peephole(mut code: Relocatable) -> Relocatable {
+    // Removing a pair can leave a `push` right before another `pop`
+    while let Some(new_code) = peephole_pass(&code) {
+        code = new_code;
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn pairs() {
+        use super::*;
+        use crate::code::{Relocation, RelocationKind, Symbol};
+        use crate::emit::{AluOp, Cond, Emitter, Mem, Reg::*};
+        let label = Symbol::new_local();
+        let mut e = Emitter::new();
+        e.push(Rax);
+        e.pop(Rax);
+        let label_location = e.position();
+        e.push(Mem::disp(Rbp, -8));
+        e.pop(R9);
+        let skip = e.jcc_short(Cond::E);
+        e.push(Rcx);
+        e.push(R8);
+        e.pop(R8);
+        e.pop(Rdx);
+        e.bind(skip);
+        e.alu_imm(AluOp::Add, Mem::base(Rsp), 5);
+        e.jmp();
+        let jump_location = e.position() - 4;
+        let code = Relocatable {
+            data: e.finish().into(),
+            symbols: vec![(label.clone(), label_location)],
+            relocations: vec![Relocation::new(jump_location, RelocationKind::Pc32, label.clone(), -4)],
+            ..Relocatable::default()
+        };
+        let code = peephole(code);
+
+        let mut e = Emitter::new();
+        e.mov_load(R9, Mem::disp(Rbp, -8));
+        let skip = e.jcc_short(Cond::E);
+        e.mov(Rdx, Rcx);
+        e.bind(skip);
+        e.alu_imm(AluOp::Add, Mem::base(Rsp), 5);
+        e.jmp();
+        let jump_location = e.position() - 4;
+        assert_eq!(code.data, e.finish());
+        assert_eq!(code.symbols, [(label.clone(), 0)]);
+        assert_eq!(code.relocations, [Relocation::new(jump_location, RelocationKind::Pc32, label, -4)]);
+    }
+
+    #[test]
+    fn entered_pops_are_kept() {
+        use super::*;
+        use crate::code::Symbol;
+        use crate::emit::{Cond, Emitter, Reg::*};
+        // A labelled pop, and a pop which is jumped to
+        let mut e = Emitter::new();
+        e.push(Rax);
+        let label_location = e.position();
+        e.pop(Rcx);
+        let skip = e.jcc_short(Cond::E);
+        e.push(Rax);
+        e.bind(skip);
+        e.pop(Rcx);
+        let data = e.finish();
+        let code = peephole(Relocatable {
+            data: data.clone().into(),
+            symbols: vec![(Symbol::new_local(), label_location)],
+            ..Relocatable::default()
+        });
+        assert_eq!(code.data, data);
+        // Unknown instructions leave the code unchanged
+        let data = vec![0x50, 0x59, 0x90];
+        assert_eq!(peephole(Relocatable::from(data.clone())).data, data);
+    }
+}
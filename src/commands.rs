@@ -1,28 +1,211 @@
 use crate::code::*;
+use crate::ir::BinaryOp;
+use crate::function::{FunctionCreateError, FunctionCreateErrorKind};
+use std::ops::Range;
 
 #[derive(Debug, Clone)]
 pub(crate) struct Command {
     pub(crate) param_count: usize,
     pub(crate) return_count: usize,
     pub(crate) required_stack_depth: usize,
+    /// Number of hidden frame slots (e.g. loop counters) the command needs reserved in the function's frame
+    pub(crate) frame_slots: usize,
+    /// Jumps out of the command to an enclosing loop, such as `break`
+    pub(crate) branches: Vec<Branch>,
+    /// Whether control never reaches the end of the command
+    pub(crate) diverges: bool,
     pub(crate) code: Relocatable,
     pub(crate) data: Relocatable,
+    /// Byte range of the program text this command was parsed from, for error reporting
+    pub(crate) span: Option<Range<usize>>,
+    /// What the command does, if it is simple enough to be compiled together with its neighbours
+    /// with the top of the stack kept in registers (see `cache_stack`)
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+    pub(crate) op: Option<StackOp>,
+}
+
+/// A command which only moves values on the stack or computes with them, without branching or failing
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StackOp {
+    /// Pushes the `index`th (1-based) argument
+    PushArgument(usize),
+    PushValue(isize),
+    /// `p<index>`; `dup` and `over` are indices 0 and 1
+    PushStackIndex(i32),
+    /// `s<index>`
+    PopStackIndex(i32),
+    /// Pops two values and pushes the result of the operation on them
+    Binary(BinaryOp),
+    /// Applies an operation to the top of the stack and an immediate operand
+    BinaryImmediate(BinaryOp, i32),
+    Not,
+    LogicalNot,
+    /// Pops and discards this many values
+    Drop(i32),
+    Swap,
+    Rot,
+}
+
+/// A jump from inside a command to a label outside of it
+#[derive(Debug, Clone)]
+pub(crate) struct Branch {
+    pub(crate) target: Symbol,
+    /// Change in stack depth between the start of the command and the jump
+    pub(crate) stack_difference: isize,
+}
+
+/// The labels that `break` and `continue` in a loop jump to
+#[derive(Debug, Clone)]
+pub(crate) struct LoopLabels {
+    pub(crate) break_symbol: Symbol,
+    pub(crate) continue_symbol: Symbol,
+}
+
+impl LoopLabels {
+    pub(crate) fn new() -> Self {
+        LoopLabels {
+            break_symbol: Symbol::new_local(),
+            continue_symbol: Symbol::new_local(),
+        }
+    }
+}
+
+/// A user-defined word, which commands call as a subroutine
+#[derive(Debug, Clone)]
+pub(crate) struct Word {
+    pub(crate) symbol: Symbol,
+    pub(crate) param_count: usize,
+    pub(crate) return_count: usize,
+    /// Frame slots used by the word's body
+    pub(crate) frame_slots: usize,
+}
+
+impl Word {
+    /// The word `symbol` with body `body`, whose stack effect is inferred from the body
+    /// or, if given, `stack_effect` (parameters and returns), which must agree with the body
+    pub(crate) fn new(symbol: Symbol, stack_effect: Option<(usize, usize)>, body: &Block) -> Result<Word, FunctionCreateError> {
+        let (param_count, return_count) = match stack_effect {
+            None => (
+                body.required_stack_depth,
+                (body.required_stack_depth as isize + body.stack_difference).max(0) as usize,
+            ),
+            Some((param_count, return_count)) => {
+                if param_count < body.required_stack_depth
+                    || (!body.diverges && return_count as isize - param_count as isize != body.stack_difference) {
+                    return Err(FunctionCreateErrorKind::WordStackEffectMismatch.into());
+                }
+                (param_count, return_count)
+            },
+        };
+        Ok(Word {
+            symbol,
+            param_count,
+            return_count,
+            frame_slots: body.frame_slots,
+        })
+    }
 }
 
 macro_rules! make_no_value_static {
     ($NAME:ident, $code:ident, $params:expr, $returns:expr, $required_depth:expr) => {
+        make_no_value_static!(@op None, $NAME, $code, $params, $returns, $required_depth);
+    };
+    ($NAME:ident, $code:ident, $params:expr, $returns:expr, $required_depth:expr, $op:expr) => {
+        make_no_value_static!(@op Some($op), $NAME, $code, $params, $returns, $required_depth);
+    };
+    (@op $op:expr, $NAME:ident, $code:ident, $params:expr, $returns:expr, $required_depth:expr) => {
         lazy_static::lazy_static! {
             pub(crate) static ref $NAME: Command = Command {
                 param_count: $params,
                 return_count: $returns,
                 required_stack_depth: $required_depth,
+                frame_slots: 0,
+                branches: vec![],
+                diverges: false,
                 code: $code().into(),
                 data: (&[][..]).into(),
+                span: None,
+                op: $op,
             };
         }
     }
 }
 
+/// A sequence of commands run one after another, such as the body of a loop
+pub(crate) struct Block {
+    /// How deep the stack must be before the block runs
+    pub(crate) required_stack_depth: usize,
+    /// Net change in stack depth from running the block
+    pub(crate) stack_difference: isize,
+    pub(crate) frame_slots: usize,
+    /// Jumps out of the block, with depths relative to the start of the block
+    pub(crate) branches: Vec<Branch>,
+    pub(crate) diverges: bool,
+    pub(crate) code: Relocatable,
+    pub(crate) data: Relocatable,
+}
+
+impl Block {
+    pub(crate) fn new(commands: Vec<Command>) -> Block {
+        let mut block = Block {
+            required_stack_depth: 0,
+            stack_difference: 0,
+            frame_slots: 0,
+            branches: vec![],
+            diverges: false,
+            code: Relocatable::default(),
+            data: Relocatable::default(),
+        };
+        for command in commands {
+            // TODO: handle overflows
+            let command_required_depth = command.param_count.max(command.required_stack_depth) as isize;
+            if (block.required_stack_depth as isize + block.stack_difference) < command_required_depth {
+                block.required_stack_depth = (command_required_depth - block.stack_difference) as usize;
+            }
+
+            let stack_difference = block.stack_difference;
+            block.branches.extend(command.branches.into_iter().map(
+                |Branch { target, stack_difference: difference }| Branch { target, stack_difference: stack_difference + difference }
+            ));
+            block.frame_slots = block.frame_slots.max(command.frame_slots);
+            block.diverges |= command.diverges;
+
+            block.stack_difference -= command.param_count as isize;
+            block.stack_difference += command.return_count as isize;
+
+            block.code += command.code;
+            block.data += command.data;
+        }
+        block
+    }
+
+    /// Removes the block's jumps to `labels`, checking that every path back to the start of the loop
+    /// leaves the stack at the depth it started at, and that every `break` leaves it at the same depth.
+    ///
+    /// Returns the depth at the `break`s, or `None` if there are none.
+    pub(crate) fn resolve_loop_exits(&mut self, labels: &LoopLabels) -> Result<Option<isize>, FunctionCreateError> {
+        if !self.diverges && self.stack_difference != 0 {
+            return Err(FunctionCreateErrorKind::LoopChangedStackDepth.into());
+        }
+        let mut break_depth = None;
+        for branch in std::mem::take(&mut self.branches) {
+            if branch.target == labels.continue_symbol {
+                if branch.stack_difference != 0 {
+                    return Err(FunctionCreateErrorKind::LoopChangedStackDepth.into());
+                }
+            } else if branch.target == labels.break_symbol {
+                if *break_depth.get_or_insert(branch.stack_difference) != branch.stack_difference {
+                    return Err(FunctionCreateErrorKind::MismatchedLoopExitDepths.into());
+                }
+            } else {
+                self.branches.push(branch);
+            }
+        }
+        Ok(break_depth)
+    }
+}
+
 mod arch;
 pub(crate) use arch::*;
 
@@ -53,9 +236,9 @@ mod tests {
         println!("{:?}", super::POP_STACK_INDEX(3));
         println!("{:?}", super::POP_STACK_INDEX(-3));
 
-        println!("{:?}", super::WHILE_LOOP(vec![]));
+        println!("{:?}", super::WHILE_LOOP(super::LoopLabels::new(), vec![]));
 
-        if let Ok(command) = super::WHILE_LOOP(vec![]) {
+        if let Ok(command) = super::WHILE_LOOP(super::LoopLabels::new(), vec![]) {
             let code_and_data = command.code + command.data;
             for byte in code_and_data.assemble().unwrap() {
                 print!("{:02x} ", byte);
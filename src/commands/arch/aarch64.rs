@@ -0,0 +1,662 @@
+use crate::{raw_code::*, code::{Relocatable, Relocation, RelocationKind, Symbol}, function::{FunctionCreateError, FunctionCreateErrorKind}};
+use crate::commands::{Block, Branch, Command, LoopLabels, Word};
+use crate::ir::BinaryOp;
+
+make_no_value_static!(PUSH_A, push_a_code, 0, 1, 0);
+make_no_value_static!(PUSH_B, push_b_code, 0, 1, 0);
+make_no_value_static!(PUSH_C, push_c_code, 0, 1, 0);
+make_no_value_static!(PUSH_D, push_d_code, 0, 1, 0);
+make_no_value_static!(PUSH_E, push_e_code, 0, 1, 0);
+make_no_value_static!(PUSH_F, push_f_code, 0, 1, 0);
+
+make_no_value_static!(ADD, add_code, 2, 1, 2);
+make_no_value_static!(SUBTRACT, subtract_code, 2, 1, 2);
+make_no_value_static!(MULTIPLY, multiply_code, 2, 1, 2);
+
+make_no_value_static!(LESS, signed_less_code, 2, 1, 2);
+make_no_value_static!(GREATER, signed_greater_code, 2, 1, 2);
+make_no_value_static!(LESS_EQUAL, signed_less_equal_code, 2, 1, 2);
+make_no_value_static!(GREATER_EQUAL, signed_greater_equal_code, 2, 1, 2);
+make_no_value_static!(ULESS, unsigned_less_code, 2, 1, 2);
+make_no_value_static!(UGREATER, unsigned_greater_code, 2, 1, 2);
+make_no_value_static!(ULESS_EQUAL, unsigned_less_equal_code, 2, 1, 2);
+make_no_value_static!(UGREATER_EQUAL, unsigned_greater_equal_code, 2, 1, 2);
+make_no_value_static!(EQUAL, equal_code, 2, 1, 2);
+make_no_value_static!(NOT_EQUAL, not_equal_code, 2, 1, 2);
+make_no_value_static!(LOGICAL_NOT, logical_not_code, 1, 1, 1);
+
+make_no_value_static!(AND, bitwise_and_code, 2, 1, 2);
+make_no_value_static!(OR, bitwise_or_code, 2, 1, 2);
+make_no_value_static!(XOR, bitwise_xor_code, 2, 1, 2);
+make_no_value_static!(NOT, bitwise_not_code, 1, 1, 1);
+make_no_value_static!(SHIFT_LEFT, shift_left_code, 2, 1, 2);
+make_no_value_static!(SHIFT_RIGHT, arithmetic_shift_right_code, 2, 1, 2);
+make_no_value_static!(USHIFT_RIGHT, logical_shift_right_code, 2, 1, 2);
+
+make_no_value_static!(DUP, dup_code, 1, 2, 1);
+make_no_value_static!(DROP, drop_code, 1, 0, 1);
+make_no_value_static!(SWAP, swap_code, 2, 2, 2);
+make_no_value_static!(ROT, rot_code, 3, 3, 3);
+make_no_value_static!(OVER, over_code, 2, 3, 2);
+
+macro_rules! make_checked_static {
+    ($NAME:ident, $code:ident) => {
+        lazy_static::lazy_static! {
+            pub(crate) static ref $NAME: Command = {
+                let (code, branch_location) = $code();
+
+                Command {
+                    param_count: 2,
+                    return_count: 1,
+                    required_stack_depth: 2,
+                    frame_slots: 0,
+                    branches: vec![],
+                    diverges: false,
+                    code: Relocatable {
+                        data: code.into(),
+                        symbols: vec![],
+                        abs_symbols: vec![],
+                        relocations: vec![Relocation::new(branch_location, RelocationKind::Jump26, Symbol::abort(), 0)],
+                    },
+                    data: (&[][..]).into(),
+                    span: None,
+                    op: None,
+                }
+            };
+        }
+    }
+}
+
+make_checked_static!(CHECKED_ADD, signed_add_checked_code);
+make_checked_static!(CHECKED_UADD, unsigned_add_checked_code);
+make_checked_static!(CHECKED_SUBTRACT, signed_subtract_checked_code);
+make_checked_static!(CHECKED_USUBTRACT, unsigned_subtract_checked_code);
+make_checked_static!(CHECKED_MULTIPLY, signed_multiply_checked_code);
+make_checked_static!(CHECKED_UMULTIPLY, unsigned_multiply_checked_code);
+make_checked_static!(CHECKED_SHIFT_LEFT, shift_left_checked_code);
+make_checked_static!(CHECKED_SHIFT_RIGHT, arithmetic_shift_right_checked_code);
+make_checked_static!(CHECKED_USHIFT_RIGHT, logical_shift_right_checked_code);
+
+/// Division commands, which branch to the abort code on division by zero (and, if signed, `MIN / -1`)
+macro_rules! make_division_static {
+    ($NAME:ident, $code:ident, $return_count:literal) => {
+        lazy_static::lazy_static! {
+            pub(crate) static ref $NAME: Command = {
+                let (code, branch_locations) = $code();
+
+                let abort_sym = Symbol::abort();
+
+                let relocations = branch_locations.into_iter().map(|loc| {
+                    Relocation::new(loc, RelocationKind::Jump26, abort_sym.clone(), 0)
+                }).collect();
+
+                Command {
+                    param_count: 2,
+                    return_count: $return_count,
+                    required_stack_depth: 2,
+                    frame_slots: 0,
+                    branches: vec![],
+                    diverges: false,
+                    code: Relocatable {
+                        data: code.into(),
+                        symbols: vec![],
+                        abs_symbols: vec![],
+                        relocations,
+                    },
+                    data: (&[][..]).into(),
+                    span: None,
+                    op: None,
+                }
+            };
+        }
+    }
+}
+
+make_division_static!(DIVIDE, signed_divide_code, 1);
+make_division_static!(MOD, signed_mod_code, 1);
+make_division_static!(DIVMOD, signed_divmod_code, 2);
+make_division_static!(UDIVIDE, unsigned_divide_code, 1);
+make_division_static!(UMOD, unsigned_mod_code, 1);
+make_division_static!(UDIVMOD, unsigned_divmod_code, 2);
+
+/// Pushes the `index`th (1-based) argument
+#[allow(non_snake_case)]
+pub(crate) fn PUSH_ARGUMENT(index: usize) -> Command {
+    match index {
+        1 => return PUSH_A.clone(),
+        2 => return PUSH_B.clone(),
+        3 => return PUSH_C.clone(),
+        4 => return PUSH_D.clone(),
+        5 => return PUSH_E.clone(),
+        6 => return PUSH_F.clone(),
+        _ => {},
+    }
+    let (code, low_value_loc, high_value_loc) = push_argument_code();
+    Command {
+        param_count: 0,
+        return_count: 1,
+        required_stack_depth: 0,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code: movz_movk_code(code, low_value_loc, high_value_loc, argument_offset(index)),
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+/// The offset from the frame pointer of the `index`th (1-based) argument
+fn argument_offset(index: usize) -> isize {
+    if index <= 8 {
+        // The first eight arguments are saved below the frame pointer
+        -80 + 8 * (index - 1) as isize
+    } else {
+        // The rest are passed on the stack, above the saved frame pointer and link register
+        16 + 8 * (index - 9) as isize
+    }
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn PUSH_VALUE(value: isize) -> Command {
+    let (code, data) = literal_code(push_value_code(), Relocatable::from(value.to_le_bytes().to_vec()));
+    Command {
+        param_count: 0,
+        return_count: 1,
+        required_stack_depth: 0,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code,
+        data,
+        span: None,
+        op: None,
+    }
+}
+
+
+#[allow(non_snake_case)]
+pub(crate) fn PUSH_STACK_INDEX(stack_index: i32) -> Command {
+    let (code, low_value_loc, high_value_loc) = if stack_index >= 0 {
+        push_stack_index_code()
+    } else {
+        push_negative_stack_index_code()
+    };
+
+    let local_constant_symbol = Symbol::new_local();
+
+    let movz_reloc = Relocation::new(
+        low_value_loc,
+        RelocationKind::MovwG0,
+        local_constant_symbol.clone(),
+        0,
+    );
+    let movk_reloc = Relocation::new(
+        high_value_loc,
+        RelocationKind::MovwG1,
+        local_constant_symbol.clone(),
+        0,
+    );
+    
+    let required_stack_depth: usize = if stack_index >= 0 {
+        stack_index as usize + 1
+    } else {
+        (-stack_index) as usize
+    };
+
+    Command {
+        param_count: 0,
+        return_count: 1,
+        required_stack_depth,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code: Relocatable {
+            data: code.into(),
+            symbols: vec![],
+            abs_symbols: vec![(local_constant_symbol, stack_index as isize)],
+            relocations: vec![movz_reloc, movk_reloc],
+        },
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn POP_STACK_INDEX(stack_index: i32) -> Command {
+    let (code, low_value_loc, high_value_loc) = if stack_index >= 0 {
+        pop_stack_index_code()
+    } else {
+        pop_negative_stack_index_code()
+    };
+
+    let local_constant_symbol = Symbol::new_local();
+
+    let movz_reloc = Relocation::new(
+        low_value_loc,
+        RelocationKind::MovwG0,
+        local_constant_symbol.clone(),
+        0,
+    );
+    let movk_reloc = Relocation::new(
+        high_value_loc,
+        RelocationKind::MovwG1,
+        local_constant_symbol.clone(),
+        0,
+    );
+    
+    let required_stack_depth: usize = if stack_index >= 0 {
+        stack_index as usize + 2
+    } else {
+        (-stack_index) as usize + 1
+    };
+
+    Command {
+        param_count: 1,
+        return_count: 0,
+        required_stack_depth,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code: Relocatable {
+            data: code.into(),
+            symbols: vec![],
+            abs_symbols: vec![(local_constant_symbol, stack_index as isize)],
+            relocations: vec![movz_reloc, movk_reloc],
+        },
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn DROP_N(count: i32) -> Command {
+    let (code, low_value_loc, high_value_loc) = drop_n_code();
+
+    let local_constant_symbol = Symbol::new_local();
+
+    let movz_reloc = Relocation::new(
+        low_value_loc,
+        RelocationKind::MovwG0,
+        local_constant_symbol.clone(),
+        0,
+    );
+    let movk_reloc = Relocation::new(
+        high_value_loc,
+        RelocationKind::MovwG1,
+        local_constant_symbol.clone(),
+        0,
+    );
+
+    Command {
+        param_count: count as usize,
+        return_count: 0,
+        required_stack_depth: count as usize,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code: Relocatable {
+            data: code.into(),
+            symbols: vec![],
+            abs_symbols: vec![(local_constant_symbol, count as isize)],
+            relocations: vec![movz_reloc, movk_reloc],
+        },
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+/// Immediate operands are only merged into operations on x86_64
+#[allow(non_snake_case)]
+pub(crate) fn BINARY_IMMEDIATE(_op: BinaryOp, _value: isize) -> Option<Command> {
+    None
+}
+
+/// Stack caching is only implemented on x86_64, so commands are compiled as they are
+pub(crate) fn cache_stack(commands: Vec<Command>) -> Vec<Command> {
+    commands
+}
+
+/// Sets up the function's frame, reserving `frame_slots` frame slots
+#[allow(non_snake_case)]
+pub(crate) fn FUNCTION_HEADER(frame_slots: usize) -> Relocatable {
+    let (code, low_value_loc, high_value_loc) = function_header_code();
+    // sp must stay 16-byte aligned
+    movz_movk_code(code, low_value_loc, high_value_loc, ((frame_slots * 8 + 15) & !15) as isize)
+}
+
+/// Sets the call depth to zero; the function's frame must have `max_call_depth + 1` slots past its own for the return stack
+#[allow(non_snake_case)]
+pub(crate) fn CALL_STACK_INIT() -> Relocatable {
+    Relocatable::from(call_stack_init_code())
+}
+
+/// Copies the top `count` values of the stack into the buffer whose address is on top of the stack
+#[allow(non_snake_case)]
+pub(crate) fn STORE_RESULTS(count: usize) -> Relocatable {
+    let (code, low_value_loc, high_value_loc) = store_results_code();
+    movz_movk_code(code, low_value_loc, high_value_loc, count as isize)
+}
+
+/// Loads the address of frame slot `slot` into x2
+fn frame_slot_address(slot: usize) -> Relocatable {
+    let (code, low_value_loc, high_value_loc) = frame_slot_address_code();
+    // Frame slots are below the eight saved arguments
+    movz_movk_code(code, low_value_loc, high_value_loc, -88 - (slot * 8) as isize)
+}
+
+/// `code` with its `movz`/`movk` pair loading `value`
+fn movz_movk_code(code: &'static [u8], low_value_loc: usize, high_value_loc: usize, value: isize) -> Relocatable {
+    let local_constant_symbol = Symbol::new_local();
+    Relocatable {
+        data: code.into(),
+        symbols: vec![],
+        abs_symbols: vec![(local_constant_symbol.clone(), value)],
+        relocations: vec![
+            Relocation::new(low_value_loc, RelocationKind::MovwG0, local_constant_symbol.clone(), 0),
+            Relocation::new(high_value_loc, RelocationKind::MovwG1, local_constant_symbol, 0),
+        ],
+    }
+}
+
+/// `code` with its `adrp`/`add` pair loading the address of `literal`, and the data section holding `literal`
+fn literal_code((code, adrp_loc): (&'static [u8], usize), literal: Relocatable) -> (Relocatable, Relocatable) {
+    let literal_symbol = Symbol::new_local();
+    let code = Relocatable {
+        data: code.into(),
+        symbols: vec![],
+        abs_symbols: vec![],
+        relocations: vec![Relocation::new(adrp_loc, RelocationKind::AdrpAdd, literal_symbol.clone(), 0)],
+    };
+    (code, Relocatable::label(literal_symbol) + literal)
+}
+
+/// `code` with its unconditional branch instruction (`b` or `bl`) pointed at `target`
+fn branch_code((code, branch_loc): (&'static [u8], usize), target: Symbol) -> Relocatable {
+    Relocatable {
+        data: code.into(),
+        symbols: vec![],
+        abs_symbols: vec![],
+        relocations: vec![Relocation::new(branch_loc, RelocationKind::Jump26, target, 0)],
+    }
+}
+
+/// `code` with its conditional branch instruction (`b.cond`, `cbz` or `cbnz`) pointed at `target`
+fn cond_branch_code((code, branch_loc): (&'static [u8], usize), target: Symbol) -> Relocatable {
+    Relocatable {
+        data: code.into(),
+        symbols: vec![],
+        abs_symbols: vec![],
+        relocations: vec![Relocation::new(branch_loc, RelocationKind::CondBr19, target, 0)],
+    }
+}
+#[allow(non_snake_case)]
+pub(crate) fn WHILE_LOOP(labels: LoopLabels, commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let mut body = Block::new(commands);
+    if body.resolve_loop_exits(&labels)?.is_some_and(|depth| depth != 0) {
+        // Skipping the loop leaves the stack as it was, so breaking out of it must too
+        return Err(FunctionCreateErrorKind::MismatchedLoopExitDepths.into());
+    }
+    let body_symbol = Symbol::new_local();
+
+    let mut code = cond_branch_code(while_loop_header_code(), labels.break_symbol.clone());
+    code += Relocatable::label(body_symbol.clone());
+    code += body.code;
+    code += Relocatable::label(labels.continue_symbol);
+    code += cond_branch_code(while_loop_footer_code(), body_symbol);
+    code += Relocatable::label(labels.break_symbol);
+
+    Ok(Command {
+        param_count: 0,
+        return_count: 0,
+        required_stack_depth: body.required_stack_depth.max(1),
+        frame_slots: body.frame_slots,
+        branches: body.branches,
+        diverges: false,
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    })
+}
+
+/// Runs `commands` repeatedly until they `break`
+#[allow(non_snake_case)]
+pub(crate) fn LOOP(labels: LoopLabels, commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let mut body = Block::new(commands);
+    let break_depth = body.resolve_loop_exits(&labels)?;
+
+    let mut code = Relocatable::label(labels.continue_symbol.clone());
+    code += body.code;
+    code += branch_code(jump_code(), labels.continue_symbol);
+    code += Relocatable::label(labels.break_symbol);
+
+    let stack_difference = break_depth.unwrap_or(0);
+    Ok(Command {
+        param_count: (-stack_difference).max(0) as usize,
+        return_count: stack_difference.max(0) as usize,
+        required_stack_depth: body.required_stack_depth,
+        frame_slots: body.frame_slots,
+        branches: body.branches,
+        diverges: break_depth.is_none(),
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    })
+}
+
+/// Pops a count, then runs `commands` that many times, keeping the count in a frame slot
+#[allow(non_snake_case)]
+pub(crate) fn COUNTED_LOOP(labels: LoopLabels, commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let mut body = Block::new(commands);
+    if body.resolve_loop_exits(&labels)?.is_some_and(|depth| depth != 0) {
+        return Err(FunctionCreateErrorKind::MismatchedLoopExitDepths.into());
+    }
+    // Loops nested in this one use the slots below this one
+    let counter_slot = body.frame_slots;
+    let body_symbol = Symbol::new_local();
+
+    let mut code = frame_slot_address(counter_slot);
+    code += branch_code(counted_loop_header_code(), labels.continue_symbol.clone());
+    code += Relocatable::label(body_symbol.clone());
+    code += body.code;
+    code += Relocatable::label(labels.continue_symbol);
+    code += frame_slot_address(counter_slot);
+    code += cond_branch_code(counted_loop_footer_code(), body_symbol);
+    code += Relocatable::label(labels.break_symbol);
+
+    Ok(Command {
+        param_count: 1,
+        return_count: 0,
+        required_stack_depth: 1 + body.required_stack_depth,
+        frame_slots: counter_slot + 1,
+        branches: shift_branches(body.branches, -1),
+        diverges: false,
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    })
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn BREAK(labels: &LoopLabels) -> Command {
+    jump_command(labels.break_symbol.clone())
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn CONTINUE(labels: &LoopLabels) -> Command {
+    jump_command(labels.continue_symbol.clone())
+}
+
+fn jump_command(target: Symbol) -> Command {
+    Command {
+        param_count: 0,
+        return_count: 0,
+        required_stack_depth: 0,
+        frame_slots: 0,
+        branches: vec![Branch { target: target.clone(), stack_difference: 0 }],
+        diverges: true,
+        code: branch_code(jump_code(), target),
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+fn shift_branches(branches: Vec<Branch>, offset: isize) -> Vec<Branch> {
+    branches.into_iter().map(
+        |Branch { target, stack_difference }| Branch { target, stack_difference: stack_difference + offset }
+    ).collect()
+}
+
+/// Defines a word with body `commands`, giving the word and a command that skips over its code.
+///
+/// Calls nested more than `max_call_depth` deep, or made with more than `max_stack_depth` values on the stack,
+/// abort with `StackOverflow`.
+#[allow(non_snake_case)]
+pub(crate) fn DEFINE_WORD(symbol: Symbol, stack_effect: Option<(usize, usize)>, commands: Vec<Command>, max_call_depth: usize, max_stack_depth: usize) -> Result<(Word, Command), FunctionCreateError> {
+    let body = Block::new(commands);
+    let word = Word::new(symbol, stack_effect, &body)?;
+    let end_symbol = Symbol::new_local();
+
+    let (limit_code, low_value_loc, high_value_loc) = word_entry_limit_code();
+    let (stack_limit_code, stack_low_value_loc, stack_high_value_loc) = word_stack_limit_code();
+
+    let mut code = branch_code(jump_code(), end_symbol.clone());
+    code += Relocatable::label(word.symbol.clone());
+    code += movz_movk_code(limit_code, low_value_loc, high_value_loc, max_call_depth as isize);
+    code += branch_code(word_entry_code(), Symbol::abort());
+    code += movz_movk_code(stack_limit_code, stack_low_value_loc, stack_high_value_loc, (max_stack_depth * 16) as isize);
+    code += branch_code(word_stack_check_code(), Symbol::abort());
+    code += body.code;
+    code += Relocatable::from(word_exit_code());
+    code += Relocatable::label(end_symbol);
+
+    let command = Command {
+        param_count: 0,
+        return_count: 0,
+        required_stack_depth: 0,
+        frame_slots: word.frame_slots,
+        branches: vec![],
+        diverges: false,
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    };
+    Ok((word, command))
+}
+
+/// Calls `word`
+#[allow(non_snake_case)]
+pub(crate) fn CALL_WORD(word: &Word) -> Command {
+    Command {
+        param_count: word.param_count,
+        return_count: word.return_count,
+        required_stack_depth: word.param_count,
+        // The caller's own frame slots (e.g. loop counters) must not overlap the word's.
+        // A recursive call is made before the word's frame slots are known, so it has none (see `Parser::parse_definition`)
+        frame_slots: word.frame_slots,
+        branches: vec![],
+        diverges: false,
+        code: branch_code(call_word_code(), word.symbol.clone()),
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+/// Calls the extern function at the absolute address `symbol` with the top `arity` values of the stack,
+/// deepest first, and pushes its result
+#[allow(non_snake_case)]
+pub(crate) fn CALL_EXTERN(symbol: Symbol, arity: usize) -> Command {
+    let arg_codes = [extern_arg_1_code, extern_arg_2_code, extern_arg_3_code, extern_arg_4_code, extern_arg_5_code, extern_arg_6_code];
+    assert!(arity <= arg_codes.len(), "extern functions take at most {} arguments", arg_codes.len());
+    let arg_code = |index: usize| {
+        let (code, low_value_loc, high_value_loc) = arg_codes[index]();
+        movz_movk_code(code, low_value_loc, high_value_loc, (16 * (arity - 1 - index)) as isize)
+    };
+    // All six arguments go in x0-x5
+    let mut code = Relocatable::default();
+    for index in 0..arity {
+        code += arg_code(index);
+    }
+    // The function's address is a literal in the data section
+    let address = Relocatable {
+        data: vec![0; 8].into(),
+        symbols: vec![],
+        abs_symbols: vec![],
+        relocations: vec![Relocation::new(0, RelocationKind::Abs64, symbol, 0)],
+    };
+    let (call_code, data) = literal_code(call_extern_code(), address);
+    code += call_code;
+    if arity > 0 {
+        code += DROP_N(arity as i32).code;
+    }
+    code += Relocatable::from(push_extern_result_code());
+    Command {
+        param_count: arity,
+        return_count: 1,
+        required_stack_depth: arity,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code,
+        data,
+        span: None,
+        op: None,
+    }
+}
+
+/// Pops the top of the stack and runs `then_commands` if it is nonzero, or `else_commands` otherwise
+#[allow(non_snake_case)]
+pub(crate) fn IF_ELSE(then_commands: Vec<Command>, else_commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let has_else = !else_commands.is_empty();
+    let then_block = Block::new(then_commands);
+    let else_block = Block::new(else_commands);
+    // An arm that always jumps out of a loop can leave the stack at any depth
+    let stack_difference = match (then_block.diverges, else_block.diverges) {
+        (false, false) if then_block.stack_difference != else_block.stack_difference => {
+            return Err(FunctionCreateErrorKind::MismatchedBranchStackDepths.into());
+        },
+        (true, false) => else_block.stack_difference,
+        _ => then_block.stack_difference,
+    };
+
+    let else_symbol = Symbol::new_local();
+    let end_symbol = Symbol::new_local();
+
+    let mut code = cond_branch_code(if_header_code(), else_symbol.clone());
+    code += then_block.code;
+    if has_else {
+        code += branch_code(if_else_code(), end_symbol.clone());
+    }
+    code += Relocatable::label(else_symbol);
+    code += else_block.code;
+    code += Relocatable::label(end_symbol);
+
+    let mut data = then_block.data;
+    data += else_block.data;
+
+    // The arms start after the condition is popped
+    let mut branches = shift_branches(then_block.branches, -1);
+    branches.extend(shift_branches(else_block.branches, -1));
+
+    Ok(Command {
+        param_count: 1 + (-stack_difference).max(0) as usize,
+        return_count: stack_difference.max(0) as usize,
+        required_stack_depth: 1 + then_block.required_stack_depth.max(else_block.required_stack_depth),
+        frame_slots: then_block.frame_slots.max(else_block.frame_slots),
+        branches,
+        diverges: then_block.diverges && else_block.diverges,
+        code,
+        data,
+        span: None,
+        op: None,
+    })
+}
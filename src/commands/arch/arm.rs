@@ -1,5 +1,6 @@
-use crate::{raw_code::*, code::{Relocatable, Relocation, RelocationKind, Symbol}, function::FunctionCreateError};
-use crate::commands::Command;
+use crate::{raw_code::*, code::{Relocatable, Relocation, RelocationKind, Symbol}, function::{FunctionCreateError, FunctionCreateErrorKind}};
+use crate::commands::{Block, Branch, Command, LoopLabels, Word};
+use crate::ir::BinaryOp;
 
 make_no_value_static!(PUSH_A, push_a_code, 0, 1, 0);
 make_no_value_static!(PUSH_B, push_b_code, 0, 1, 0);
@@ -11,56 +12,138 @@ make_no_value_static!(PUSH_F, push_f_code, 0, 1, 0);
 make_no_value_static!(ADD, add_code, 2, 1, 2);
 make_no_value_static!(SUBTRACT, subtract_code, 2, 1, 2);
 make_no_value_static!(MULTIPLY, multiply_code, 2, 1, 2);
-// make_no_value_static!(DIVIDE, signed_divide_code, 2, 1, 2);
-// make_no_value_static!(MOD, signed_mod_code, 2, 1, 2);
-make_no_value_static!(UDIVIDE, unsigned_divide_code, 2, 1, 2);
-make_no_value_static!(UMOD, unsigned_mod_code, 2, 1, 2);
-
-lazy_static::lazy_static!{
-    pub(crate) static ref DIVIDE: Command = {
-        let (code, branch_locations) = signed_divide_code();
-
-        let abort_sym = Symbol::abort();
-
-        let relocations = branch_locations.into_iter().map(|loc| {
-            Relocation::new(loc, RelocationKind::Jump24, abort_sym.clone(), -8)
-        }).collect();
-
-        Command {
-            param_count: 2,
-            return_count: 1,
-            required_stack_depth: 2,
-            code: Relocatable {
-                data: code.into(),
-                symbols: vec![],
-                abs_symbols: vec![],
-                relocations,
-            },
-            data: (&[][..]).into(),
+
+make_no_value_static!(LESS, signed_less_code, 2, 1, 2);
+make_no_value_static!(GREATER, signed_greater_code, 2, 1, 2);
+make_no_value_static!(LESS_EQUAL, signed_less_equal_code, 2, 1, 2);
+make_no_value_static!(GREATER_EQUAL, signed_greater_equal_code, 2, 1, 2);
+make_no_value_static!(ULESS, unsigned_less_code, 2, 1, 2);
+make_no_value_static!(UGREATER, unsigned_greater_code, 2, 1, 2);
+make_no_value_static!(ULESS_EQUAL, unsigned_less_equal_code, 2, 1, 2);
+make_no_value_static!(UGREATER_EQUAL, unsigned_greater_equal_code, 2, 1, 2);
+make_no_value_static!(EQUAL, equal_code, 2, 1, 2);
+make_no_value_static!(NOT_EQUAL, not_equal_code, 2, 1, 2);
+make_no_value_static!(LOGICAL_NOT, logical_not_code, 1, 1, 1);
+
+make_no_value_static!(AND, bitwise_and_code, 2, 1, 2);
+make_no_value_static!(OR, bitwise_or_code, 2, 1, 2);
+make_no_value_static!(XOR, bitwise_xor_code, 2, 1, 2);
+make_no_value_static!(NOT, bitwise_not_code, 1, 1, 1);
+make_no_value_static!(SHIFT_LEFT, shift_left_code, 2, 1, 2);
+make_no_value_static!(SHIFT_RIGHT, arithmetic_shift_right_code, 2, 1, 2);
+make_no_value_static!(USHIFT_RIGHT, logical_shift_right_code, 2, 1, 2);
+
+make_no_value_static!(DUP, dup_code, 1, 2, 1);
+make_no_value_static!(DROP, drop_code, 1, 0, 1);
+make_no_value_static!(SWAP, swap_code, 2, 2, 2);
+make_no_value_static!(ROT, rot_code, 3, 3, 3);
+make_no_value_static!(OVER, over_code, 2, 3, 2);
+
+macro_rules! make_checked_static {
+    ($NAME:ident, $code:ident) => {
+        lazy_static::lazy_static! {
+            pub(crate) static ref $NAME: Command = {
+                let (code, branch_location) = $code();
+
+                Command {
+                    param_count: 2,
+                    return_count: 1,
+                    required_stack_depth: 2,
+                    frame_slots: 0,
+                    branches: vec![],
+                    diverges: false,
+                    code: Relocatable {
+                        data: code.into(),
+                        symbols: vec![],
+                        abs_symbols: vec![],
+                        relocations: vec![Relocation::new(branch_location, RelocationKind::Jump24, Symbol::abort(), -8)],
+                    },
+                    data: (&[][..]).into(),
+                    span: None,
+                    op: None,
+                }
+            };
         }
-    };
-    pub(crate) static ref MOD: Command = {
-        let (code, branch_locations) = signed_mod_code();
-
-        let abort_sym = Symbol::abort();
-
-        let relocations = branch_locations.into_iter().map(|loc| {
-            Relocation::new(loc, RelocationKind::Jump24, abort_sym.clone(), -8)
-        }).collect();
-
-        Command {
-            param_count: 2,
-            return_count: 1,
-            required_stack_depth: 2,
-            code: Relocatable {
-                data: code.into(),
-                symbols: vec![],
-                abs_symbols: vec![],
-                relocations,
-            },
-            data: (&[][..]).into(),
+    }
+}
+
+make_checked_static!(CHECKED_ADD, signed_add_checked_code);
+make_checked_static!(CHECKED_UADD, unsigned_add_checked_code);
+make_checked_static!(CHECKED_SUBTRACT, signed_subtract_checked_code);
+make_checked_static!(CHECKED_USUBTRACT, unsigned_subtract_checked_code);
+make_checked_static!(CHECKED_MULTIPLY, signed_multiply_checked_code);
+make_checked_static!(CHECKED_UMULTIPLY, unsigned_multiply_checked_code);
+make_checked_static!(CHECKED_SHIFT_LEFT, shift_left_checked_code);
+make_checked_static!(CHECKED_SHIFT_RIGHT, arithmetic_shift_right_checked_code);
+make_checked_static!(CHECKED_USHIFT_RIGHT, logical_shift_right_checked_code);
+
+/// Division commands, which branch to the abort code on division by zero (and, if signed, `MIN / -1`)
+macro_rules! make_division_static {
+    ($NAME:ident, $code:ident, $return_count:literal) => {
+        lazy_static::lazy_static! {
+            pub(crate) static ref $NAME: Command = {
+                let (code, branch_locations) = $code();
+
+                let abort_sym = Symbol::abort();
+
+                let relocations = branch_locations.into_iter().map(|loc| {
+                    Relocation::new(loc, RelocationKind::Jump24, abort_sym.clone(), -8)
+                }).collect();
+
+                Command {
+                    param_count: 2,
+                    return_count: $return_count,
+                    required_stack_depth: 2,
+                    frame_slots: 0,
+                    branches: vec![],
+                    diverges: false,
+                    code: Relocatable {
+                        data: code.into(),
+                        symbols: vec![],
+                        abs_symbols: vec![],
+                        relocations,
+                    },
+                    data: (&[][..]).into(),
+                    span: None,
+                    op: None,
+                }
+            };
         }
-    };
+    }
+}
+
+make_division_static!(DIVIDE, signed_divide_code, 1);
+make_division_static!(MOD, signed_mod_code, 1);
+make_division_static!(DIVMOD, signed_divmod_code, 2);
+make_division_static!(UDIVIDE, unsigned_divide_code, 1);
+make_division_static!(UMOD, unsigned_mod_code, 1);
+make_division_static!(UDIVMOD, unsigned_divmod_code, 2);
+
+/// Pushes the `index`th (1-based) argument
+#[allow(non_snake_case)]
+pub(crate) fn PUSH_ARGUMENT(index: usize) -> Command {
+    match index {
+        1 => return PUSH_A.clone(),
+        2 => return PUSH_B.clone(),
+        3 => return PUSH_C.clone(),
+        4 => return PUSH_D.clone(),
+        5 => return PUSH_E.clone(),
+        6 => return PUSH_F.clone(),
+        _ => {},
+    }
+    let (code, low_value_loc, high_value_loc) = push_argument_code();
+    Command {
+        param_count: 0,
+        return_count: 1,
+        required_stack_depth: 0,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code: movw_movt_code(code, low_value_loc, high_value_loc, (32 + (index - 4) * 4) as isize),
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
 }
 
 #[allow(non_snake_case)]
@@ -85,6 +168,9 @@ pub(crate) fn PUSH_VALUE(value: isize) -> Command {
         param_count: 0,
         return_count: 1,
         required_stack_depth: 0,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
         code: Relocatable {
             data: code.into(),
             symbols: vec![],
@@ -92,6 +178,8 @@ pub(crate) fn PUSH_VALUE(value: isize) -> Command {
             relocations: vec![movw_reloc, movt_reloc],
         },
         data: Relocatable::default(),
+        span: None,
+        op: None,
     }
 }
 
@@ -129,6 +217,9 @@ pub(crate) fn PUSH_STACK_INDEX(stack_index: i32) -> Command {
         param_count: 0,
         return_count: 1,
         required_stack_depth,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
         code: Relocatable {
             data: code.into(),
             symbols: vec![],
@@ -136,6 +227,8 @@ pub(crate) fn PUSH_STACK_INDEX(stack_index: i32) -> Command {
             relocations: vec![movw_reloc, movt_reloc],
         },
         data: Relocatable::default(),
+        span: None,
+        op: None,
     }
 }
 
@@ -172,6 +265,9 @@ pub(crate) fn POP_STACK_INDEX(stack_index: i32) -> Command {
         param_count: 1,
         return_count: 0,
         required_stack_depth,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
         code: Relocatable {
             data: code.into(),
             symbols: vec![],
@@ -179,77 +275,379 @@ pub(crate) fn POP_STACK_INDEX(stack_index: i32) -> Command {
             relocations: vec![movw_reloc, movt_reloc],
         },
         data: Relocatable::default(),
+        span: None,
+        op: None,
     }
 }
 
+#[allow(non_snake_case)]
+pub(crate) fn DROP_N(count: i32) -> Command {
+    let (code, low_value_loc, high_value_loc) = drop_n_code();
 
+    let local_constant_symbol = Symbol::new_local();
 
-fn new_while_loop_header_footer() -> (Relocatable, Relocatable) {
-    let header_branch_symbol = Symbol::new_local();
-    let footer_branch_symbol = Symbol::new_local();
+    let movw_reloc = Relocation::new(
+        low_value_loc,
+        RelocationKind::Movw,
+        local_constant_symbol.clone(),
+        0,
+    );
+    let movt_reloc = Relocation::new(
+        high_value_loc,
+        RelocationKind::Movt,
+        local_constant_symbol.clone(),
+        0,
+    );
 
-    let (header_code, header_branch_loc) = while_loop_header_code();
+    Command {
+        param_count: count as usize,
+        return_count: 0,
+        required_stack_depth: count as usize,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code: Relocatable {
+            data: code.into(),
+            symbols: vec![],
+            abs_symbols: vec![(local_constant_symbol, count as isize)],
+            relocations: vec![movw_reloc, movt_reloc],
+        },
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
 
-    let header_code = Relocatable {
-        data: header_code.into(),
-        symbols: vec![(header_branch_symbol.clone(), header_code.len())],
-        abs_symbols: vec![],
-        relocations: vec![Relocation::new(header_branch_loc, RelocationKind::Jump24, footer_branch_symbol.clone(), -8)],
-    };
+/// Immediate operands are only merged into operations on x86_64
+#[allow(non_snake_case)]
+pub(crate) fn BINARY_IMMEDIATE(_op: BinaryOp, _value: isize) -> Option<Command> {
+    None
+}
 
+/// Stack caching is only implemented on x86_64, so commands are compiled as they are
+pub(crate) fn cache_stack(commands: Vec<Command>) -> Vec<Command> {
+    commands
+}
 
-    let (footer_code, footer_branch_loc) = while_loop_footer_code();
+/// Sets up the function's frame, reserving `frame_slots` frame slots
+#[allow(non_snake_case)]
+pub(crate) fn FUNCTION_HEADER(frame_slots: usize) -> Relocatable {
+    let (code, low_value_loc, high_value_loc) = function_header_code();
+    movw_movt_code(code, low_value_loc, high_value_loc, (frame_slots * 4) as isize)
+}
 
-    let footer_code = Relocatable {
-        data: footer_code.into(),
-        symbols: vec![(footer_branch_symbol.clone(), footer_code.len())],
-        abs_symbols: vec![],
-        relocations: vec![Relocation::new(footer_branch_loc, RelocationKind::Jump24, header_branch_symbol.clone(), -8)],
-    };
+/// Sets the call depth to zero; the function's frame must have `max_call_depth + 1` slots past its own for the return stack
+#[allow(non_snake_case)]
+pub(crate) fn CALL_STACK_INIT() -> Relocatable {
+    Relocatable::from(call_stack_init_code())
+}
 
-    (header_code, footer_code)
-}
-
-#[allow(non_snake_case)]
-pub(crate) fn WHILE_LOOP(commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
-    let (mut code, footer_code) = new_while_loop_header_footer();
-    let mut data = Relocatable::default();
-    let mut required_stack_depth: usize = 1;
-    let mut stack_difference: isize = 0;
-    for command in commands {
-        dbg!(&command);
-        let Command {
-            param_count: command_params,
-            return_count: command_returns,
-            required_stack_depth: command_required_depth,
-            code: command_code,
-            data: command_data,
-        } = command;
-        // TODO: handle overflows
-        if ((required_stack_depth as isize + stack_difference) as usize) < command_params {
-            required_stack_depth = (command_params as isize - stack_difference) as usize;
-        }
-        if ((required_stack_depth as isize + stack_difference) as usize) < command_required_depth {
-            required_stack_depth = (command_required_depth as isize - stack_difference) as usize;
-        }
+/// Copies the top `count` values of the stack into the buffer whose address is on top of the stack
+#[allow(non_snake_case)]
+pub(crate) fn STORE_RESULTS(count: usize) -> Relocatable {
+    let (code, low_value_loc, high_value_loc) = store_results_code();
+    movw_movt_code(code, low_value_loc, high_value_loc, count as isize)
+}
 
-        stack_difference -= command_params as isize;
-        stack_difference += command_returns as isize;
+/// Loads the address of frame slot `slot` into r2
+fn frame_slot_address(slot: usize) -> Relocatable {
+    let (code, low_value_loc, high_value_loc) = frame_slot_address_code();
+    // Frame slots are below the six saved parameters
+    movw_movt_code(code, low_value_loc, high_value_loc, -28 - (slot * 4) as isize)
+}
 
-        code += command_code;
-        data += command_data;
+/// `code` with its `movw`/`movt` pair loading `value`
+fn movw_movt_code(code: &'static [u8], low_value_loc: usize, high_value_loc: usize, value: isize) -> Relocatable {
+    let local_constant_symbol = Symbol::new_local();
+    Relocatable {
+        data: code.into(),
+        symbols: vec![],
+        abs_symbols: vec![(local_constant_symbol.clone(), value)],
+        relocations: vec![
+            Relocation::new(low_value_loc, RelocationKind::Movw, local_constant_symbol.clone(), 0),
+            Relocation::new(high_value_loc, RelocationKind::Movt, local_constant_symbol, 0),
+        ],
     }
-    code += footer_code;
+}
 
-    if stack_difference != 0 {
-        return Err(FunctionCreateError::LoopChangedStackDepth);
+/// `code` with its branch instruction pointed at `target`
+fn branch_code((code, branch_loc): (&'static [u8], usize), target: Symbol) -> Relocatable {
+    Relocatable {
+        data: code.into(),
+        symbols: vec![],
+        abs_symbols: vec![],
+        relocations: vec![Relocation::new(branch_loc, RelocationKind::Jump24, target, -8)],
     }
-    
+}
+#[allow(non_snake_case)]
+pub(crate) fn WHILE_LOOP(labels: LoopLabels, commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let mut body = Block::new(commands);
+    if body.resolve_loop_exits(&labels)?.is_some_and(|depth| depth != 0) {
+        // Skipping the loop leaves the stack as it was, so breaking out of it must too
+        return Err(FunctionCreateErrorKind::MismatchedLoopExitDepths.into());
+    }
+    let body_symbol = Symbol::new_local();
+
+    let mut code = branch_code(while_loop_header_code(), labels.break_symbol.clone());
+    code += Relocatable::label(body_symbol.clone());
+    code += body.code;
+    code += Relocatable::label(labels.continue_symbol);
+    code += branch_code(while_loop_footer_code(), body_symbol);
+    code += Relocatable::label(labels.break_symbol);
+
     Ok(Command {
         param_count: 0,
         return_count: 0,
-        required_stack_depth,
+        required_stack_depth: body.required_stack_depth.max(1),
+        frame_slots: body.frame_slots,
+        branches: body.branches,
+        diverges: false,
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    })
+}
+
+/// Runs `commands` repeatedly until they `break`
+#[allow(non_snake_case)]
+pub(crate) fn LOOP(labels: LoopLabels, commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let mut body = Block::new(commands);
+    let break_depth = body.resolve_loop_exits(&labels)?;
+
+    let mut code = Relocatable::label(labels.continue_symbol.clone());
+    code += body.code;
+    code += branch_code(jump_code(), labels.continue_symbol);
+    code += Relocatable::label(labels.break_symbol);
+
+    let stack_difference = break_depth.unwrap_or(0);
+    Ok(Command {
+        param_count: (-stack_difference).max(0) as usize,
+        return_count: stack_difference.max(0) as usize,
+        required_stack_depth: body.required_stack_depth,
+        frame_slots: body.frame_slots,
+        branches: body.branches,
+        diverges: break_depth.is_none(),
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    })
+}
+
+/// Pops a count, then runs `commands` that many times, keeping the count in a frame slot
+#[allow(non_snake_case)]
+pub(crate) fn COUNTED_LOOP(labels: LoopLabels, commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let mut body = Block::new(commands);
+    if body.resolve_loop_exits(&labels)?.is_some_and(|depth| depth != 0) {
+        return Err(FunctionCreateErrorKind::MismatchedLoopExitDepths.into());
+    }
+    // Loops nested in this one use the slots below this one
+    let counter_slot = body.frame_slots;
+    let body_symbol = Symbol::new_local();
+
+    let mut code = frame_slot_address(counter_slot);
+    code += branch_code(counted_loop_header_code(), labels.continue_symbol.clone());
+    code += Relocatable::label(body_symbol.clone());
+    code += body.code;
+    code += Relocatable::label(labels.continue_symbol);
+    code += frame_slot_address(counter_slot);
+    code += branch_code(counted_loop_footer_code(), body_symbol);
+    code += Relocatable::label(labels.break_symbol);
+
+    Ok(Command {
+        param_count: 1,
+        return_count: 0,
+        required_stack_depth: 1 + body.required_stack_depth,
+        frame_slots: counter_slot + 1,
+        branches: shift_branches(body.branches, -1),
+        diverges: false,
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    })
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn BREAK(labels: &LoopLabels) -> Command {
+    jump_command(labels.break_symbol.clone())
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn CONTINUE(labels: &LoopLabels) -> Command {
+    jump_command(labels.continue_symbol.clone())
+}
+
+fn jump_command(target: Symbol) -> Command {
+    Command {
+        param_count: 0,
+        return_count: 0,
+        required_stack_depth: 0,
+        frame_slots: 0,
+        branches: vec![Branch { target: target.clone(), stack_difference: 0 }],
+        diverges: true,
+        code: branch_code(jump_code(), target),
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+fn shift_branches(branches: Vec<Branch>, offset: isize) -> Vec<Branch> {
+    branches.into_iter().map(
+        |Branch { target, stack_difference }| Branch { target, stack_difference: stack_difference + offset }
+    ).collect()
+}
+
+/// Defines a word with body `commands`, giving the word and a command that skips over its code.
+///
+/// Calls nested more than `max_call_depth` deep, or made with more than `max_stack_depth` values on the stack,
+/// abort with `StackOverflow`.
+#[allow(non_snake_case)]
+pub(crate) fn DEFINE_WORD(symbol: Symbol, stack_effect: Option<(usize, usize)>, commands: Vec<Command>, max_call_depth: usize, max_stack_depth: usize) -> Result<(Word, Command), FunctionCreateError> {
+    let body = Block::new(commands);
+    let word = Word::new(symbol, stack_effect, &body)?;
+    let end_symbol = Symbol::new_local();
+
+    let (limit_code, low_value_loc, high_value_loc) = word_entry_limit_code();
+    let (stack_limit_code, stack_low_value_loc, stack_high_value_loc) = word_stack_limit_code();
+
+    let mut code = branch_code(jump_code(), end_symbol.clone());
+    code += Relocatable::label(word.symbol.clone());
+    code += movw_movt_code(limit_code, low_value_loc, high_value_loc, max_call_depth as isize);
+    code += branch_code(word_entry_code(), Symbol::abort());
+    code += movw_movt_code(stack_limit_code, stack_low_value_loc, stack_high_value_loc, (max_stack_depth * 4) as isize);
+    code += branch_code(word_stack_check_code(), Symbol::abort());
+    code += body.code;
+    code += Relocatable::from(word_exit_code());
+    code += Relocatable::label(end_symbol);
+
+    let command = Command {
+        param_count: 0,
+        return_count: 0,
+        required_stack_depth: 0,
+        frame_slots: word.frame_slots,
+        branches: vec![],
+        diverges: false,
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    };
+    Ok((word, command))
+}
+
+/// Calls `word`
+#[allow(non_snake_case)]
+pub(crate) fn CALL_WORD(word: &Word) -> Command {
+    Command {
+        param_count: word.param_count,
+        return_count: word.return_count,
+        required_stack_depth: word.param_count,
+        // The caller's own frame slots (e.g. loop counters) must not overlap the word's.
+        // A recursive call is made before the word's frame slots are known, so it has none (see `Parser::parse_definition`)
+        frame_slots: word.frame_slots,
+        branches: vec![],
+        diverges: false,
+        code: branch_code(call_word_code(), word.symbol.clone()),
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+/// Calls the extern function at the absolute address `symbol` with the top `arity` values of the stack,
+/// deepest first, and pushes its result
+#[allow(non_snake_case)]
+pub(crate) fn CALL_EXTERN(symbol: Symbol, arity: usize) -> Command {
+    let arg_codes = [extern_arg_1_code, extern_arg_2_code, extern_arg_3_code, extern_arg_4_code, extern_arg_5_code, extern_arg_6_code];
+    assert!(arity <= arg_codes.len(), "extern functions take at most {} arguments", arg_codes.len());
+    let arg_code = |index: usize| {
+        let (code, low_value_loc, high_value_loc) = arg_codes[index]();
+        movw_movt_code(code, low_value_loc, high_value_loc, (4 * (arity - 1 - index)) as isize)
+    };
+    // The first four arguments go in r0-r3, and the rest on the stack
+    let mut code = Relocatable::default();
+    for index in 0..arity.min(4) {
+        code += arg_code(index);
+    }
+    code += Relocatable::from(call_extern_setup_code());
+    for index in 4..arity {
+        code += arg_code(index);
+    }
+    let (call_code, low_value_loc, high_value_loc) = call_extern_code();
+    code += Relocatable {
+        data: call_code.into(),
+        symbols: vec![],
+        abs_symbols: vec![],
+        relocations: vec![
+            Relocation::new(low_value_loc, RelocationKind::Movw, symbol.clone(), 0),
+            Relocation::new(high_value_loc, RelocationKind::Movt, symbol, 0),
+        ],
+    };
+    if arity > 0 {
+        code += DROP_N(arity as i32).code;
+    }
+    code += Relocatable::from(push_extern_result_code());
+    Command {
+        param_count: arity,
+        return_count: 1,
+        required_stack_depth: arity,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code,
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+/// Pops the top of the stack and runs `then_commands` if it is nonzero, or `else_commands` otherwise
+#[allow(non_snake_case)]
+pub(crate) fn IF_ELSE(then_commands: Vec<Command>, else_commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let has_else = !else_commands.is_empty();
+    let then_block = Block::new(then_commands);
+    let else_block = Block::new(else_commands);
+    // An arm that always jumps out of a loop can leave the stack at any depth
+    let stack_difference = match (then_block.diverges, else_block.diverges) {
+        (false, false) if then_block.stack_difference != else_block.stack_difference => {
+            return Err(FunctionCreateErrorKind::MismatchedBranchStackDepths.into());
+        },
+        (true, false) => else_block.stack_difference,
+        _ => then_block.stack_difference,
+    };
+
+    let else_symbol = Symbol::new_local();
+    let end_symbol = Symbol::new_local();
+
+    let mut code = branch_code(if_header_code(), else_symbol.clone());
+    code += then_block.code;
+    if has_else {
+        code += branch_code(if_else_code(), end_symbol.clone());
+    }
+    code += Relocatable::label(else_symbol);
+    code += else_block.code;
+    code += Relocatable::label(end_symbol);
+
+    let mut data = then_block.data;
+    data += else_block.data;
+
+    // The arms start after the condition is popped
+    let mut branches = shift_branches(then_block.branches, -1);
+    branches.extend(shift_branches(else_block.branches, -1));
+
+    Ok(Command {
+        param_count: 1 + (-stack_difference).max(0) as usize,
+        return_count: stack_difference.max(0) as usize,
+        required_stack_depth: 1 + then_block.required_stack_depth.max(else_block.required_stack_depth),
+        frame_slots: then_block.frame_slots.max(else_block.frame_slots),
+        branches,
+        diverges: then_block.diverges && else_block.diverges,
         code,
-        data, 
+        data,
+        span: None,
+        op: None,
     })
 }
@@ -1,33 +1,127 @@
-use crate::{raw_code::*, code::{Relocatable, Symbol, RelocationKind, Relocation}, function::FunctionCreateError};
-use crate::commands::Command;
+use std::{convert::TryInto, ops::Range};
+use crate::{raw_code::*, code::{Relocatable, Symbol, RelocationKind, Relocation}, function::{FunctionCreateError, FunctionCreateErrorKind}};
+use crate::commands::{Block, Branch, Command, LoopLabels, StackOp, Word};
+use crate::ir::BinaryOp;
 
-make_no_value_static!(PUSH_A, push_a_code, 0, 1, 0);
-make_no_value_static!(PUSH_B, push_b_code, 0, 1, 0);
-make_no_value_static!(PUSH_C, push_c_code, 0, 1, 0);
-make_no_value_static!(PUSH_D, push_d_code, 0, 1, 0);
-make_no_value_static!(PUSH_E, push_e_code, 0, 1, 0);
-make_no_value_static!(PUSH_F, push_f_code, 0, 1, 0);
+make_no_value_static!(PUSH_A, push_a_code, 0, 1, 0, StackOp::PushArgument(1));
+make_no_value_static!(PUSH_B, push_b_code, 0, 1, 0, StackOp::PushArgument(2));
+make_no_value_static!(PUSH_C, push_c_code, 0, 1, 0, StackOp::PushArgument(3));
+make_no_value_static!(PUSH_D, push_d_code, 0, 1, 0, StackOp::PushArgument(4));
+make_no_value_static!(PUSH_E, push_e_code, 0, 1, 0, StackOp::PushArgument(5));
+make_no_value_static!(PUSH_F, push_f_code, 0, 1, 0, StackOp::PushArgument(6));
 
-make_no_value_static!(ADD, add_code, 2, 1, 2);
-make_no_value_static!(SUBTRACT, subtract_code, 2, 1, 2);
-make_no_value_static!(MULTIPLY, multiply_code, 2, 1, 2);
-make_no_value_static!(DIVIDE, signed_divide_code, 2, 1, 2);
-make_no_value_static!(MOD, signed_mod_code, 2, 1, 2);
-make_no_value_static!(UDIVIDE, unsigned_divide_code, 2, 1, 2);
-make_no_value_static!(UMOD, unsigned_mod_code, 2, 1, 2);
+/// Commands which jump to the abort code when they fail
+macro_rules! make_checked_static {
+    ($NAME:ident, $code:ident, $params:expr, $returns:expr, $required_depth:expr) => {
+        lazy_static::lazy_static! {
+            pub(crate) static ref $NAME: Command = Command {
+                param_count: $params,
+                return_count: $returns,
+                required_stack_depth: $required_depth,
+                frame_slots: 0,
+                branches: vec![],
+                diverges: false,
+                code: abort_code($code()),
+                data: (&[][..]).into(),
+                span: None,
+                op: None,
+            };
+        }
+    }
+}
+
+make_no_value_static!(ADD, add_code, 2, 1, 2, StackOp::Binary(BinaryOp::Add));
+make_no_value_static!(SUBTRACT, subtract_code, 2, 1, 2, StackOp::Binary(BinaryOp::Subtract));
+make_no_value_static!(MULTIPLY, multiply_code, 2, 1, 2, StackOp::Binary(BinaryOp::Multiply));
+make_checked_static!(CHECKED_ADD, signed_add_checked_code, 2, 1, 2);
+make_checked_static!(CHECKED_UADD, unsigned_add_checked_code, 2, 1, 2);
+make_checked_static!(CHECKED_SUBTRACT, signed_subtract_checked_code, 2, 1, 2);
+make_checked_static!(CHECKED_USUBTRACT, unsigned_subtract_checked_code, 2, 1, 2);
+make_checked_static!(CHECKED_MULTIPLY, signed_multiply_checked_code, 2, 1, 2);
+make_checked_static!(CHECKED_UMULTIPLY, unsigned_multiply_checked_code, 2, 1, 2);
+make_checked_static!(DIVIDE, signed_divide_code, 2, 1, 2);
+make_checked_static!(MOD, signed_mod_code, 2, 1, 2);
+make_checked_static!(DIVMOD, signed_divmod_code, 2, 2, 2);
+make_checked_static!(UDIVIDE, unsigned_divide_code, 2, 1, 2);
+make_checked_static!(UMOD, unsigned_mod_code, 2, 1, 2);
+make_checked_static!(UDIVMOD, unsigned_divmod_code, 2, 2, 2);
+
+make_no_value_static!(LESS, signed_less_code, 2, 1, 2, StackOp::Binary(BinaryOp::Less));
+make_no_value_static!(GREATER, signed_greater_code, 2, 1, 2, StackOp::Binary(BinaryOp::Greater));
+make_no_value_static!(LESS_EQUAL, signed_less_equal_code, 2, 1, 2, StackOp::Binary(BinaryOp::LessEqual));
+make_no_value_static!(GREATER_EQUAL, signed_greater_equal_code, 2, 1, 2, StackOp::Binary(BinaryOp::GreaterEqual));
+make_no_value_static!(ULESS, unsigned_less_code, 2, 1, 2, StackOp::Binary(BinaryOp::ULess));
+make_no_value_static!(UGREATER, unsigned_greater_code, 2, 1, 2, StackOp::Binary(BinaryOp::UGreater));
+make_no_value_static!(ULESS_EQUAL, unsigned_less_equal_code, 2, 1, 2, StackOp::Binary(BinaryOp::ULessEqual));
+make_no_value_static!(UGREATER_EQUAL, unsigned_greater_equal_code, 2, 1, 2, StackOp::Binary(BinaryOp::UGreaterEqual));
+make_no_value_static!(EQUAL, equal_code, 2, 1, 2, StackOp::Binary(BinaryOp::Equal));
+make_no_value_static!(NOT_EQUAL, not_equal_code, 2, 1, 2, StackOp::Binary(BinaryOp::NotEqual));
+make_no_value_static!(LOGICAL_NOT, logical_not_code, 1, 1, 1, StackOp::LogicalNot);
+
+make_no_value_static!(AND, bitwise_and_code, 2, 1, 2, StackOp::Binary(BinaryOp::And));
+make_no_value_static!(OR, bitwise_or_code, 2, 1, 2, StackOp::Binary(BinaryOp::Or));
+make_no_value_static!(XOR, bitwise_xor_code, 2, 1, 2, StackOp::Binary(BinaryOp::Xor));
+make_no_value_static!(NOT, bitwise_not_code, 1, 1, 1, StackOp::Not);
+make_no_value_static!(SHIFT_LEFT, shift_left_code, 2, 1, 2, StackOp::Binary(BinaryOp::ShiftLeft));
+make_no_value_static!(SHIFT_RIGHT, arithmetic_shift_right_code, 2, 1, 2, StackOp::Binary(BinaryOp::ShiftRight));
+make_no_value_static!(USHIFT_RIGHT, logical_shift_right_code, 2, 1, 2, StackOp::Binary(BinaryOp::UShiftRight));
+make_checked_static!(CHECKED_SHIFT_LEFT, shift_left_checked_code, 2, 1, 2);
+make_checked_static!(CHECKED_SHIFT_RIGHT, arithmetic_shift_right_checked_code, 2, 1, 2);
+make_checked_static!(CHECKED_USHIFT_RIGHT, logical_shift_right_checked_code, 2, 1, 2);
+
+make_no_value_static!(DUP, dup_code, 1, 2, 1, StackOp::PushStackIndex(0));
+make_no_value_static!(DROP, drop_code, 1, 0, 1, StackOp::Drop(1));
+make_no_value_static!(SWAP, swap_code, 2, 2, 2, StackOp::Swap);
+make_no_value_static!(ROT, rot_code, 3, 3, 3, StackOp::Rot);
+make_no_value_static!(OVER, over_code, 2, 3, 2, StackOp::PushStackIndex(1));
+
+/// Pushes the `index`th (1-based) argument
+#[allow(non_snake_case)]
+pub(crate) fn PUSH_ARGUMENT(index: usize) -> Command {
+    match index {
+        1 => return PUSH_A.clone(),
+        2 => return PUSH_B.clone(),
+        3 => return PUSH_C.clone(),
+        4 => return PUSH_D.clone(),
+        5 => return PUSH_E.clone(),
+        6 => return PUSH_F.clone(),
+        _ => {},
+    }
+    let (code, value_loc) = push_argument_code();
+    let mut code: Vec<u8> = code.to_owned();
+    let displacement: i32 = (16 + (index - 7) * 8) as i32;
+    code[value_loc].copy_from_slice(&displacement.to_ne_bytes());
+    Command {
+        param_count: 0,
+        return_count: 1,
+        required_stack_depth: 0,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code: Relocatable::from(code),
+        data: Relocatable::default(),
+        span: None,
+        op: Some(StackOp::PushArgument(index)),
+    }
+}
 
 #[allow(non_snake_case)]
 pub(crate) fn PUSH_VALUE(value: isize) -> Command {
     let (code, value_loc) = push_value_code();
     let mut code: Vec<u8> = code.to_owned();
-    let value: [u8; 8] = unsafe { std::mem::transmute(value) };
-    code[value_loc].copy_from_slice(&value);
+    let bytes: [u8; 8] = unsafe { std::mem::transmute(value) };
+    code[value_loc].copy_from_slice(&bytes);
     Command {
         param_count: 0,
         return_count: 1,
         required_stack_depth: 0,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
         code: Relocatable::from(code),
         data: Relocatable::default(),
+        span: None,
+        op: Some(StackOp::PushValue(value)),
     }
 }
 
@@ -50,8 +144,13 @@ pub(crate) fn PUSH_STACK_INDEX(stack_index: i32) -> Command {
         param_count: 0,
         return_count: 1,
         required_stack_depth,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
         code: Relocatable::from(code),
         data: Relocatable::default(),
+        span: None,
+        op: Some(StackOp::PushStackIndex(stack_index)),
     }
 }
 
@@ -74,76 +173,427 @@ pub(crate) fn POP_STACK_INDEX(stack_index: i32) -> Command {
         param_count: 1,
         return_count: 0,
         required_stack_depth,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code: Relocatable::from(code),
+        data: Relocatable::default(),
+        span: None,
+        op: Some(StackOp::PopStackIndex(stack_index)),
+    }
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn DROP_N(count: i32) -> Command {
+    let (code, value_loc) = drop_n_code();
+    let mut code: Vec<u8> = code.to_owned();
+    let value: [u8; 4] = count.to_ne_bytes();
+    code[value_loc].copy_from_slice(&value);
+    Command {
+        param_count: count as usize,
+        return_count: 0,
+        required_stack_depth: count as usize,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
         code: Relocatable::from(code),
         data: Relocatable::default(),
+        span: None,
+        op: Some(StackOp::Drop(count)),
     }
 }
 
-fn new_while_loop_header_footer() -> (Relocatable, Relocatable) {
-    let header_branch_symbol = Symbol::new_local();
-    let footer_branch_symbol = Symbol::new_local();
+/// Pushes `value` and applies `op` to the top two values, as one command, if `op` can take `value` as an immediate operand
+#[allow(non_snake_case)]
+pub(crate) fn BINARY_IMMEDIATE(op: BinaryOp, value: isize) -> Option<Command> {
+    let value: i32 = value.try_into().ok()?;
+    let code = binary_immediate_code(op, value)?;
+    Some(Command {
+        param_count: 1,
+        return_count: 1,
+        required_stack_depth: 1,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code: Relocatable::from(code),
+        data: Relocatable::default(),
+        span: None,
+        op: Some(StackOp::BinaryImmediate(op, value)),
+    })
+}
 
-    let (header_code, header_offset_loc) = while_loop_header_code();
+/// Replaces each run of two or more commands with `op`s by one command which keeps the top of the stack
+/// in registers, storing the cached values back to memory at the end of the run
+pub(crate) fn cache_stack(commands: Vec<Command>) -> Vec<Command> {
+    let mut result = Vec::with_capacity(commands.len());
+    let mut run = vec![];
+    for command in commands {
+        if command.op.is_some() {
+            run.push(command);
+        } else {
+            end_run(&mut result, &mut run);
+            result.push(command);
+        }
+    }
+    end_run(&mut result, &mut run);
+    result
+}
 
-    let header_code = Relocatable {
-        data: header_code.into(),
-        symbols: vec![(header_branch_symbol.clone(), header_code.len())],
-        abs_symbols: vec![],
-        relocations: vec![Relocation::new(header_offset_loc.start, RelocationKind::Pc32, footer_branch_symbol.clone(), -4)],
+fn end_run(commands: &mut Vec<Command>, run: &mut Vec<Command>) {
+    if run.len() < 2 {
+        commands.append(run);
+        return;
+    }
+    let run = std::mem::take(run);
+    // Depths relative to the start of the run
+    let (mut depth, mut lowest, mut required) = (0isize, 0isize, 0isize);
+    for command in &run {
+        required = required.max(command.param_count.max(command.required_stack_depth) as isize - depth);
+        depth -= command.param_count as isize;
+        lowest = lowest.min(depth);
+        depth += command.return_count as isize;
+    }
+    let ops: Vec<StackOp> = run.iter().filter_map(|command| command.op).collect();
+    let span = match (&run[0].span, &run[run.len() - 1].span) {
+        (Some(first), Some(last)) => Some(first.start..last.end),
+        _ => None,
     };
+    commands.push(Command {
+        param_count: (-lowest) as usize,
+        return_count: (depth - lowest) as usize,
+        required_stack_depth: required as usize,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code: Relocatable::from(stack_cached_code(&ops)),
+        data: Relocatable::default(),
+        span,
+        op: None,
+    });
+}
+
+/// Sets up the function's frame, reserving `frame_slots` frame slots
+#[allow(non_snake_case)]
+pub(crate) fn FUNCTION_HEADER(frame_slots: usize) -> Relocatable {
+    let (code, value_loc) = function_header_code();
+    let mut code: Vec<u8> = code.to_owned();
+    let frame_size: i32 = (frame_slots * 8).try_into().expect("frame size should fit in an i32");
+    code[value_loc].copy_from_slice(&frame_size.to_ne_bytes());
+    Relocatable::from(code)
+}
 
+/// Sets the call depth to zero; the function's frame must have `max_call_depth + 1` slots past its own for the return stack
+#[allow(non_snake_case)]
+pub(crate) fn CALL_STACK_INIT() -> Relocatable {
+    Relocatable::from(call_stack_init_code())
+}
+
+/// Copies the top `count` values of the stack into the buffer whose address is on top of the stack
+#[allow(non_snake_case)]
+pub(crate) fn STORE_RESULTS(count: usize) -> Relocatable {
+    let (code, value_loc) = store_results_code();
+    let mut code: Vec<u8> = code.to_owned();
+    let count: i32 = count.try_into().expect("result count should fit in an i32");
+    code[value_loc].copy_from_slice(&count.to_ne_bytes());
+    Relocatable::from(code)
+}
 
-    let (footer_code, footer_offset_loc) = while_loop_footer_code();
+/// Loads the address of frame slot `slot` into %rcx
+fn frame_slot_address(slot: usize) -> Relocatable {
+    let (code, value_loc) = frame_slot_address_code();
+    let mut code: Vec<u8> = code.to_owned();
+    // Frame slots are below the six saved parameters and %rbx
+    let displacement: i32 = -64 - (slot * 8) as i32;
+    code[value_loc].copy_from_slice(&displacement.to_ne_bytes());
+    Relocatable::from(code)
+}
 
-    let footer_code = Relocatable {
-        data: footer_code.into(),
-        symbols: vec![(footer_branch_symbol.clone(), footer_code.len())],
+/// `code` with its rel32 branch offset pointed at `target`
+fn branch_code((code, offset_loc): (Vec<u8>, Range<usize>), target: Symbol) -> Relocatable {
+    Relocatable {
+        data: code.into(),
+        symbols: vec![],
         abs_symbols: vec![],
-        relocations: vec![Relocation::new(footer_offset_loc.start, RelocationKind::Pc32, header_branch_symbol.clone(), -4)],
-    };
+        relocations: vec![Relocation::new(offset_loc.start, RelocationKind::Pc32, target, -4)],
+    }
+}
 
-    (header_code, footer_code)
+fn abort_code((code, offset_locs): (Vec<u8>, Vec<usize>)) -> Relocatable {
+    Relocatable {
+        data: code.into(),
+        symbols: vec![],
+        abs_symbols: vec![],
+        relocations: offset_locs.into_iter().map(|offset_loc| {
+            Relocation::new(offset_loc, RelocationKind::Pc32, Symbol::abort(), -4)
+        }).collect(),
+    }
 }
+#[allow(non_snake_case)]
+pub(crate) fn WHILE_LOOP(labels: LoopLabels, commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let mut body = Block::new(commands);
+    if body.resolve_loop_exits(&labels)?.is_some_and(|depth| depth != 0) {
+        // Skipping the loop leaves the stack as it was, so breaking out of it must too
+        return Err(FunctionCreateErrorKind::MismatchedLoopExitDepths.into());
+    }
+    let body_symbol = Symbol::new_local();
+
+    let mut code = branch_code(while_loop_header_code(), labels.break_symbol.clone());
+    code += Relocatable::label(body_symbol.clone());
+    code += body.code;
+    code += Relocatable::label(labels.continue_symbol);
+    code += branch_code(while_loop_footer_code(), body_symbol);
+    code += Relocatable::label(labels.break_symbol);
 
+    Ok(Command {
+        param_count: 0,
+        return_count: 0,
+        required_stack_depth: body.required_stack_depth.max(1),
+        frame_slots: body.frame_slots,
+        branches: body.branches,
+        diverges: false,
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    })
+}
+
+/// Runs `commands` repeatedly until they `break`
 #[allow(non_snake_case)]
-pub(crate) fn WHILE_LOOP(commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
-    let (mut code, footer_code) = new_while_loop_header_footer();
-    let mut data = Relocatable::default();
-    let mut required_stack_depth: usize = 1;
-    let mut stack_difference: isize = 0;
-    for command in commands {
-        let Command {
-            param_count: command_params,
-            return_count: command_returns,
-            required_stack_depth: command_required_depth,
-            code: command_code,
-            data: command_data,
-        } = command;
-        // TODO: handle overflows
-        if ((required_stack_depth as isize + stack_difference) as usize) < command_params {
-            required_stack_depth = (command_params as isize - stack_difference) as usize;
-        }
-        if ((required_stack_depth as isize + stack_difference) as usize) < command_required_depth {
-            required_stack_depth = (command_required_depth as isize - stack_difference) as usize;
-        }
+pub(crate) fn LOOP(labels: LoopLabels, commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let mut body = Block::new(commands);
+    let break_depth = body.resolve_loop_exits(&labels)?;
 
-        stack_difference -= command_params as isize;
-        stack_difference += command_returns as isize;
+    let mut code = Relocatable::label(labels.continue_symbol.clone());
+    code += body.code;
+    code += branch_code(jump_code(), labels.continue_symbol);
+    code += Relocatable::label(labels.break_symbol);
 
-        code += command_code;
-        data += command_data;
-    }
-    code += footer_code;
+    let stack_difference = break_depth.unwrap_or(0);
+    Ok(Command {
+        param_count: (-stack_difference).max(0) as usize,
+        return_count: stack_difference.max(0) as usize,
+        required_stack_depth: body.required_stack_depth,
+        frame_slots: body.frame_slots,
+        branches: body.branches,
+        diverges: break_depth.is_none(),
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    })
+}
 
-    if stack_difference != 0 {
-        return Err(FunctionCreateError::LoopChangedStackDepth);
+/// Pops a count, then runs `commands` that many times, keeping the count in a frame slot
+#[allow(non_snake_case)]
+pub(crate) fn COUNTED_LOOP(labels: LoopLabels, commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let mut body = Block::new(commands);
+    if body.resolve_loop_exits(&labels)?.is_some_and(|depth| depth != 0) {
+        return Err(FunctionCreateErrorKind::MismatchedLoopExitDepths.into());
     }
-    
+    // Loops nested in this one use the slots below this one
+    let counter_slot = body.frame_slots;
+    let body_symbol = Symbol::new_local();
+
+    let mut code = frame_slot_address(counter_slot);
+    code += branch_code(counted_loop_header_code(), labels.continue_symbol.clone());
+    code += Relocatable::label(body_symbol.clone());
+    code += body.code;
+    code += Relocatable::label(labels.continue_symbol);
+    code += frame_slot_address(counter_slot);
+    code += branch_code(counted_loop_footer_code(), body_symbol);
+    code += Relocatable::label(labels.break_symbol);
+
     Ok(Command {
+        param_count: 1,
+        return_count: 0,
+        required_stack_depth: 1 + body.required_stack_depth,
+        frame_slots: counter_slot + 1,
+        branches: shift_branches(body.branches, -1),
+        diverges: false,
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    })
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn BREAK(labels: &LoopLabels) -> Command {
+    jump_command(labels.break_symbol.clone())
+}
+
+#[allow(non_snake_case)]
+pub(crate) fn CONTINUE(labels: &LoopLabels) -> Command {
+    jump_command(labels.continue_symbol.clone())
+}
+
+fn jump_command(target: Symbol) -> Command {
+    Command {
         param_count: 0,
         return_count: 0,
-        required_stack_depth,
+        required_stack_depth: 0,
+        frame_slots: 0,
+        branches: vec![Branch { target: target.clone(), stack_difference: 0 }],
+        diverges: true,
+        code: branch_code(jump_code(), target),
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+fn shift_branches(branches: Vec<Branch>, offset: isize) -> Vec<Branch> {
+    branches.into_iter().map(
+        |Branch { target, stack_difference }| Branch { target, stack_difference: stack_difference + offset }
+    ).collect()
+}
+
+/// Defines a word with body `commands`, giving the word and a command that skips over its code.
+///
+/// Calls nested more than `max_call_depth` deep, or made with more than `max_stack_depth` values on the stack,
+/// abort with `StackOverflow`.
+#[allow(non_snake_case)]
+pub(crate) fn DEFINE_WORD(symbol: Symbol, stack_effect: Option<(usize, usize)>, commands: Vec<Command>, max_call_depth: usize, max_stack_depth: usize) -> Result<(Word, Command), FunctionCreateError> {
+    let body = Block::new(commands);
+    let word = Word::new(symbol, stack_effect, &body)?;
+    let end_symbol = Symbol::new_local();
+
+    let (limit_code, value_loc) = word_entry_limit_code();
+    let mut limit_code: Vec<u8> = limit_code.to_owned();
+    let max_call_depth: i32 = max_call_depth.try_into().expect("maximum call depth should fit in an i32");
+    limit_code[value_loc].copy_from_slice(&max_call_depth.to_ne_bytes());
+    let (stack_limit_code, value_loc) = word_stack_limit_code();
+    let mut stack_limit_code: Vec<u8> = stack_limit_code.to_owned();
+    let max_stack_size: i32 = (max_stack_depth * 8).try_into().expect("maximum stack size should fit in an i32");
+    stack_limit_code[value_loc].copy_from_slice(&max_stack_size.to_ne_bytes());
+
+    let mut code = branch_code(jump_code(), end_symbol.clone());
+    code += Relocatable::label(word.symbol.clone());
+    code += Relocatable::from(limit_code);
+    code += abort_code(word_entry_code());
+    code += Relocatable::from(stack_limit_code);
+    code += abort_code(word_stack_check_code());
+    code += body.code;
+    code += Relocatable::from(word_exit_code());
+    code += Relocatable::label(end_symbol);
+
+    let command = Command {
+        param_count: 0,
+        return_count: 0,
+        required_stack_depth: 0,
+        frame_slots: word.frame_slots,
+        branches: vec![],
+        diverges: false,
+        code,
+        data: body.data,
+        span: None,
+        op: None,
+    };
+    Ok((word, command))
+}
+
+/// Calls `word`
+#[allow(non_snake_case)]
+pub(crate) fn CALL_WORD(word: &Word) -> Command {
+    Command {
+        param_count: word.param_count,
+        return_count: word.return_count,
+        required_stack_depth: word.param_count,
+        // The caller's own frame slots (e.g. loop counters) must not overlap the word's.
+        // A recursive call is made before the word's frame slots are known, so it has none (see `Parser::parse_definition`)
+        frame_slots: word.frame_slots,
+        branches: vec![],
+        diverges: false,
+        code: branch_code(call_word_code(), word.symbol.clone()),
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+/// Calls the extern function at the absolute address `symbol` with the top `arity` values of the stack,
+/// deepest first, and pushes its result
+#[allow(non_snake_case)]
+pub(crate) fn CALL_EXTERN(symbol: Symbol, arity: usize) -> Command {
+    let arg_codes = [extern_arg_1_code, extern_arg_2_code, extern_arg_3_code, extern_arg_4_code, extern_arg_5_code, extern_arg_6_code];
+    assert!(arity <= arg_codes.len(), "extern functions take at most {} arguments", arg_codes.len());
+    let mut code = Relocatable::default();
+    for (index, arg_code) in arg_codes[..arity].iter().enumerate() {
+        let (arg_code, value_loc) = arg_code();
+        let mut arg_code: Vec<u8> = arg_code.to_owned();
+        let displacement: i32 = (8 * (arity - 1 - index)) as i32;
+        arg_code[value_loc].copy_from_slice(&displacement.to_ne_bytes());
+        code += Relocatable::from(arg_code);
+    }
+    if arity > 0 {
+        code += DROP_N(arity as i32).code;
+    }
+    let (call_code, value_loc) = call_extern_code();
+    code += Relocatable {
+        data: call_code.into(),
+        symbols: vec![],
+        abs_symbols: vec![],
+        relocations: vec![Relocation::new(value_loc.start, RelocationKind::Direct64, symbol, 0)],
+    };
+    Command {
+        param_count: arity,
+        return_count: 1,
+        required_stack_depth: arity,
+        frame_slots: 0,
+        branches: vec![],
+        diverges: false,
+        code,
+        data: Relocatable::default(),
+        span: None,
+        op: None,
+    }
+}
+
+/// Pops the top of the stack and runs `then_commands` if it is nonzero, or `else_commands` otherwise
+#[allow(non_snake_case)]
+pub(crate) fn IF_ELSE(then_commands: Vec<Command>, else_commands: Vec<Command>) -> Result<Command, FunctionCreateError> {
+    let has_else = !else_commands.is_empty();
+    let then_block = Block::new(then_commands);
+    let else_block = Block::new(else_commands);
+    // An arm that always jumps out of a loop can leave the stack at any depth
+    let stack_difference = match (then_block.diverges, else_block.diverges) {
+        (false, false) if then_block.stack_difference != else_block.stack_difference => {
+            return Err(FunctionCreateErrorKind::MismatchedBranchStackDepths.into());
+        },
+        (true, false) => else_block.stack_difference,
+        _ => then_block.stack_difference,
+    };
+
+    let else_symbol = Symbol::new_local();
+    let end_symbol = Symbol::new_local();
+
+    let mut code = branch_code(if_header_code(), else_symbol.clone());
+    code += then_block.code;
+    if has_else {
+        code += branch_code(if_else_code(), end_symbol.clone());
+    }
+    code += Relocatable::label(else_symbol);
+    code += else_block.code;
+    code += Relocatable::label(end_symbol);
+
+    let mut data = then_block.data;
+    data += else_block.data;
+
+    // The arms start after the condition is popped
+    let mut branches = shift_branches(then_block.branches, -1);
+    branches.extend(shift_branches(else_block.branches, -1));
+
+    Ok(Command {
+        param_count: 1 + (-stack_difference).max(0) as usize,
+        return_count: stack_difference.max(0) as usize,
+        required_stack_depth: 1 + then_block.required_stack_depth.max(else_block.required_stack_depth),
+        frame_slots: then_block.frame_slots.max(else_block.frame_slots),
+        branches,
+        diverges: then_block.diverges && else_block.diverges,
         code,
-        data, 
+        data,
+        span: None,
+        op: None,
     })
 }
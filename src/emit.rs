@@ -0,0 +1,2 @@
+mod arch;
+pub(crate) use arch::*;
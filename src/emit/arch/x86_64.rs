@@ -0,0 +1,457 @@
+// The complete sets of registers and conditions, though not every snippet uses all of them
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reg {
+    Rax = 0,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Reg {
+    /// The low three bits of the register number, which go in the ModRM or SIB byte
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+    /// Whether the register needs a REX prefix bit
+    fn high(self) -> bool {
+        self as u8 >= 8
+    }
+}
+
+/// A memory operand, `disp(base,index,scale)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Mem {
+    base: Reg,
+    index: Option<(Reg, u8)>,
+    disp: i32,
+    force_disp32: bool,
+}
+
+impl Mem {
+    pub(crate) fn base(base: Reg) -> Self {
+        Self::disp(base, 0)
+    }
+    pub(crate) fn disp(base: Reg, disp: i32) -> Self {
+        Self { base, index: None, disp, force_disp32: false }
+    }
+    pub(crate) fn indexed(base: Reg, index: Reg, scale: u8, disp: i32) -> Self {
+        assert!(index != Reg::Rsp, "%rsp cannot be an index register");
+        assert!(matches!(scale, 1 | 2 | 4 | 8), "scale must be 1, 2, 4, or 8");
+        Self { base, index: Some((index, scale)), disp, force_disp32: false }
+    }
+    /// The same operand with its displacement always encoded in four bytes, so that it can be patched later
+    pub(crate) fn disp32(self) -> Self {
+        Self { force_disp32: true, ..self }
+    }
+}
+
+/// A register or memory operand, encoded in the r/m field of the ModRM byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operand {
+    Reg(Reg),
+    Mem(Mem),
+}
+
+impl From<Reg> for Operand {
+    fn from(reg: Reg) -> Self {
+        Operand::Reg(reg)
+    }
+}
+
+impl From<Mem> for Operand {
+    fn from(mem: Mem) -> Self {
+        Operand::Mem(mem)
+    }
+}
+
+/// Condition codes, in encoding order
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Cond {
+    O = 0,
+    No,
+    B,
+    Ae,
+    E,
+    Ne,
+    Be,
+    A,
+    S,
+    Ns,
+    P,
+    Np,
+    L,
+    Ge,
+    Le,
+    G,
+}
+
+/// Operations with the classic ALU encodings: `8*op+1` with a register source, or `/op` with an immediate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AluOp {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+/// Operations encoded as `0xF7 /op`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnaryOp {
+    Not = 2,
+    Mul = 4,
+    Imul = 5,
+    Div = 6,
+    Idiv = 7,
+}
+
+/// Shifts by `%cl`, encoded as `0xD3 /op`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ShiftOp {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/// A short forward jump whose target has not been emitted yet; see `Emitter::bind`
+#[must_use]
+#[derive(Debug)]
+pub(crate) struct ShortJump(usize);
+
+/// Encodes instructions into a buffer.
+///
+/// Jumps with rel32 offsets are emitted with an offset of zero, to be relocated later.
+#[derive(Debug, Default)]
+pub(crate) struct Emitter {
+    code: Vec<u8>,
+}
+
+impl Emitter {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// The offset of the next instruction
+    pub(crate) fn position(&self) -> usize {
+        self.code.len()
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.code
+    }
+
+    fn rex(&mut self, w: bool, reg: u8, rm: Operand) {
+        let (x, b) = match rm {
+            Operand::Reg(rm) => (false, rm.high()),
+            Operand::Mem(Mem { base, index, .. }) => (index.is_some_and(|(index, _)| index.high()), base.high()),
+        };
+        let rex = 0x40 | (w as u8) << 3 | ((reg >= 8) as u8) << 2 | (x as u8) << 1 | b as u8;
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    fn modrm(&mut self, reg: u8, rm: Operand) {
+        let reg = (reg & 7) << 3;
+        let Mem { base, index, disp, force_disp32 } = match rm {
+            Operand::Reg(rm) => {
+                self.code.push(0xC0 | reg | rm.low());
+                return;
+            },
+            Operand::Mem(mem) => mem,
+        };
+        // %rbp and %r13 as a base always take a displacement
+        let mode: u8 = if force_disp32 || i8::try_from(disp).is_err() {
+            0x80
+        } else if disp != 0 || base.low() == 5 {
+            0x40
+        } else {
+            0x00
+        };
+        // %rsp and %r12 as a base always take a SIB byte
+        if index.is_some() || base.low() == 4 {
+            let (index, scale) = index.map_or((4, 1), |(index, scale)| (index.low(), scale));
+            self.code.push(mode | reg | 4);
+            self.code.push((scale.trailing_zeros() as u8) << 6 | index << 3 | base.low());
+        } else {
+            self.code.push(mode | reg | base.low());
+        }
+        match mode {
+            0x40 => self.code.push(disp as u8),
+            0x80 => self.code.extend_from_slice(&disp.to_le_bytes()),
+            _ => {},
+        }
+    }
+
+    /// An instruction with a ModRM byte; `reg` is a register number or an opcode extension
+    fn op(&mut self, w: bool, opcode: &[u8], reg: u8, rm: Operand) {
+        self.rex(w, reg, rm);
+        self.code.extend_from_slice(opcode);
+        self.modrm(reg, rm);
+    }
+
+    pub(crate) fn endbr64(&mut self) {
+        self.code.extend_from_slice(&[0xF3, 0x0F, 0x1E, 0xFA]);
+    }
+
+    pub(crate) fn push(&mut self, src: impl Into<Operand>) {
+        match src.into() {
+            Operand::Reg(src) => {
+                self.rex(false, 0, src.into());
+                self.code.push(0x50 + src.low());
+            },
+            src => self.op(false, &[0xFF], 6, src),
+        }
+    }
+
+    pub(crate) fn pop(&mut self, dst: impl Into<Operand>) {
+        match dst.into() {
+            Operand::Reg(dst) => {
+                self.rex(false, 0, dst.into());
+                self.code.push(0x58 + dst.low());
+            },
+            dst => self.op(false, &[0x8F], 0, dst),
+        }
+    }
+
+    /// `mov %src,dst`
+    pub(crate) fn mov(&mut self, dst: impl Into<Operand>, src: Reg) {
+        self.op(true, &[0x89], src as u8, dst.into());
+    }
+
+    /// `mov src,%dst`
+    pub(crate) fn mov_load(&mut self, dst: Reg, src: impl Into<Operand>) {
+        self.op(true, &[0x8B], dst as u8, src.into());
+    }
+
+    /// `movq $imm,dst`, with `imm` sign-extended
+    pub(crate) fn mov_imm(&mut self, dst: impl Into<Operand>, imm: i32) {
+        self.op(true, &[0xC7], 0, dst.into());
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// `mov $imm,%dst` on the low 32 bits of `dst`, zeroing the rest
+    pub(crate) fn mov_imm32(&mut self, dst: Reg, imm: u32) {
+        self.rex(false, 0, dst.into());
+        self.code.push(0xB8 + dst.low());
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// `movabs $imm,%dst`
+    pub(crate) fn movabs(&mut self, dst: Reg, imm: i64) {
+        self.rex(true, 0, dst.into());
+        self.code.push(0xB8 + dst.low());
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    pub(crate) fn lea(&mut self, dst: Reg, src: Mem) {
+        self.op(true, &[0x8D], dst as u8, src.into());
+    }
+
+    /// `op %src,dst`
+    pub(crate) fn alu(&mut self, op: AluOp, dst: impl Into<Operand>, src: Reg) {
+        self.op(true, &[op as u8 * 8 + 1], src as u8, dst.into());
+    }
+
+    /// `op src,%dst`
+    pub(crate) fn alu_load(&mut self, op: AluOp, dst: Reg, src: impl Into<Operand>) {
+        self.op(true, &[op as u8 * 8 + 3], dst as u8, src.into());
+    }
+
+    /// `op $imm,dst`, with a one-byte immediate if `imm` fits in one
+    pub(crate) fn alu_imm(&mut self, op: AluOp, dst: impl Into<Operand>, imm: i32) {
+        match i8::try_from(imm) {
+            Ok(imm) => {
+                self.op(true, &[0x83], op as u8, dst.into());
+                self.code.push(imm as u8);
+            },
+            Err(_) => self.alu_imm32(op, dst, imm),
+        }
+    }
+
+    /// `op $imm,dst`, always with a four-byte immediate, so that it can be patched later
+    pub(crate) fn alu_imm32(&mut self, op: AluOp, dst: impl Into<Operand>, imm: i32) {
+        self.op(true, &[0x81], op as u8, dst.into());
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// `xor %src,%dst` on the low 32 bits, zeroing the rest
+    pub(crate) fn xor32(&mut self, dst: Reg, src: Reg) {
+        self.op(false, &[0x31], src as u8, dst.into());
+    }
+
+    pub(crate) fn test(&mut self, dst: impl Into<Operand>, src: Reg) {
+        self.op(true, &[0x85], src as u8, dst.into());
+    }
+
+    /// `imul src,%dst`, keeping the low half of the product
+    pub(crate) fn imul(&mut self, dst: Reg, src: impl Into<Operand>) {
+        self.op(true, &[0x0F, 0xAF], dst as u8, src.into());
+    }
+
+    pub(crate) fn unary(&mut self, op: UnaryOp, operand: impl Into<Operand>) {
+        self.op(true, &[0xF7], op as u8, operand.into());
+    }
+
+    /// `op %cl,dst`
+    pub(crate) fn shift_cl(&mut self, op: ShiftOp, dst: impl Into<Operand>) {
+        self.op(true, &[0xD3], op as u8, dst.into());
+    }
+
+    /// Sign-extends `%rax` into `%rdx`
+    pub(crate) fn cqo(&mut self) {
+        self.code.extend_from_slice(&[0x48, 0x99]);
+    }
+
+    /// Sets the low byte of `dst` to whether `cond` holds
+    pub(crate) fn setcc(&mut self, cond: Cond, dst: Reg) {
+        assert!(dst.low() < 4 || dst.high(), "byte registers past %bl need a REX prefix");
+        self.op(false, &[0x0F, 0x90 + cond as u8], 0, dst.into());
+    }
+
+    pub(crate) fn ret(&mut self) {
+        self.code.push(0xC3);
+    }
+
+    pub(crate) fn call_indirect(&mut self, target: impl Into<Operand>) {
+        self.op(false, &[0xFF], 2, target.into());
+    }
+
+    /// `jcc` with a rel32 offset ending at the new position
+    pub(crate) fn jcc(&mut self, cond: Cond) {
+        self.code.extend_from_slice(&[0x0F, 0x80 + cond as u8, 0, 0, 0, 0]);
+    }
+
+    /// `jmp` with a rel32 offset ending at the new position
+    pub(crate) fn jmp(&mut self) {
+        self.code.extend_from_slice(&[0xE9, 0, 0, 0, 0]);
+    }
+
+    /// `call` with a rel32 offset ending at the new position
+    pub(crate) fn call(&mut self) {
+        self.code.extend_from_slice(&[0xE8, 0, 0, 0, 0]);
+    }
+
+    /// `jcc` with a rel8 offset to a later position, given to `bind`
+    pub(crate) fn jcc_short(&mut self, cond: Cond) -> ShortJump {
+        self.code.extend_from_slice(&[0x70 + cond as u8, 0]);
+        ShortJump(self.code.len())
+    }
+
+    /// Points `jump` at the current position
+    pub(crate) fn bind(&mut self, ShortJump(offset_end): ShortJump) {
+        let offset = i8::try_from(self.code.len() - offset_end).expect("short jump target too far away");
+        self.code[offset_end - 1] = offset as u8;
+    }
+
+    /// Decrements `%rcx`, and jumps to `target` (at or before the current position) unless it is now zero
+    pub(crate) fn loop_to(&mut self, target: usize) {
+        let offset = i8::try_from(target as isize - (self.code.len() + 2) as isize).expect("loop target too far away");
+        self.code.extend_from_slice(&[0xE2, offset as u8]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn modrm() {
+        use super::*;
+        use Reg::*;
+        let mut e = Emitter::new();
+        // mov %rsp,%rbp ; mov -0x38(%rbp),%rbx ; mov %rcx,(%rsp,%rax,8) ; mov -0x8(%rsp,%rcx,8),%rdx
+        e.mov(Rbp, Rsp);
+        e.mov_load(Rbx, Mem::disp(Rbp, -56));
+        e.mov(Mem::indexed(Rsp, Rax, 8, 0), Rcx);
+        e.mov_load(Rdx, Mem::indexed(Rsp, Rcx, 8, -8));
+        // mov 0x400(%rsp),%r9 ; lea -0x400(%rbp),%rcx ; push 0x0(%rbp) ; pop (%r12)
+        e.mov_load(R9, Mem::disp(Rsp, 0x400));
+        e.lea(Rcx, Mem::disp(Rbp, -0x400));
+        e.push(Mem::base(Rbp));
+        e.pop(Mem::base(R12));
+        // mov (%r13,%r10,2),%r11 ; push 0x8(%rsp) with a 32-bit displacement
+        e.mov_load(R11, Mem::indexed(R13, R10, 2, 0));
+        e.push(Mem::disp(Rsp, 8).disp32());
+        // imul %r9,%r8 ; imul 0x10(%rsp),%rax
+        e.imul(R8, R9);
+        e.imul(Rax, Mem::disp(Rsp, 16));
+        assert_eq!(e.finish(), [
+            0x48, 0x89, 0xe5,
+            0x48, 0x8b, 0x5d, 0xc8,
+            0x48, 0x89, 0x0c, 0xc4,
+            0x48, 0x8b, 0x54, 0xcc, 0xf8,
+            0x4c, 0x8b, 0x8c, 0x24, 0x00, 0x04, 0x00, 0x00,
+            0x48, 0x8d, 0x8d, 0x00, 0xfc, 0xff, 0xff,
+            0xff, 0x75, 0x00,
+            0x41, 0x8f, 0x04, 0x24,
+            0x4f, 0x8b, 0x5c, 0x55, 0x00,
+            0xff, 0xb4, 0x24, 0x08, 0x00, 0x00, 0x00,
+            0x4d, 0x0f, 0xaf, 0xc1,
+            0x48, 0x0f, 0xaf, 0x44, 0x24, 0x10,
+        ]);
+    }
+
+    #[test]
+    fn immediates() {
+        use super::*;
+        use Reg::*;
+        let mut e = Emitter::new();
+        // add $0x8,%rsp ; cmp $0x400,%rcx ; and $-16,%rsp ; subq $1,(%rcx) ; cmp $-1,%rcx
+        e.alu_imm(AluOp::Add, Rsp, 8);
+        e.alu_imm(AluOp::Cmp, Rcx, 0x400);
+        e.alu_imm(AluOp::And, Rsp, -16);
+        e.alu_imm(AluOp::Sub, Mem::base(Rcx), 1);
+        e.alu_imm32(AluOp::Cmp, Rcx, -1);
+        // mov $0x3,%edx ; mov $0x7,%r8d ; movq $0x0,(%rbx) ; movabs $-0x8000000000000000,%rax
+        e.mov_imm32(Rdx, 3);
+        e.mov_imm32(R8, 7);
+        e.mov_imm(Mem::base(Rbx), 0);
+        e.movabs(Rax, i64::MIN);
+        assert_eq!(e.finish(), [
+            0x48, 0x83, 0xc4, 0x08,
+            0x48, 0x81, 0xf9, 0x00, 0x04, 0x00, 0x00,
+            0x48, 0x83, 0xe4, 0xf0,
+            0x48, 0x83, 0x29, 0x01,
+            0x48, 0x81, 0xf9, 0xff, 0xff, 0xff, 0xff,
+            0xba, 0x03, 0x00, 0x00, 0x00,
+            0x41, 0xb8, 0x07, 0x00, 0x00, 0x00,
+            0x48, 0xc7, 0x03, 0x00, 0x00, 0x00, 0x00,
+            0x48, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80,
+        ]);
+    }
+
+    #[test]
+    fn jumps() {
+        use super::*;
+        let mut e = Emitter::new();
+        // 0: jb 1f ; je . ; loop 0b ; 1: jmp . ; call .
+        let start = e.position();
+        let skip = e.jcc_short(Cond::B);
+        e.jcc(Cond::E);
+        e.loop_to(start);
+        e.bind(skip);
+        e.jmp();
+        e.call();
+        assert_eq!(e.finish(), [
+            0x72, 0x08,
+            0x0f, 0x84, 0x00, 0x00, 0x00, 0x00,
+            0xe2, 0xf6,
+            0xe9, 0x00, 0x00, 0x00, 0x00,
+            0xe8, 0x00, 0x00, 0x00, 0x00,
+        ]);
+    }
+}
@@ -2,13 +2,23 @@ mod errors {
     include!{concat!(env!("OUT_DIR"), "/function_errors.rs")}
 }
 
-use crate::code::{Relocatable, AssembleError, Symbol};
+mod error;
+mod interpreter;
+mod lower;
+mod parser;
+
+use crate::code::{Relocatable, Symbol};
 use crate::commands::*;
-use crate::raw_code::{function_header_code, function_footer_code, function_abort_code};
+use crate::ir::{Program, fold_constants};
+use crate::raw_code::{function_footer_code, function_abort_code};
 use libc::{c_void, intptr_t, mmap, munmap, mprotect};
-use std::convert::TryInto;
+use std::collections::HashMap;
+use lower::Lowerer;
+use parser::Parser;
 
 pub use errors::{FunctionError, FunctionErrorRaw, function_error_from_raw};
+pub use error::{FunctionCreateError, FunctionCreateErrorKind, SourceLocation};
+pub use interpreter::Interpreter;
 
 #[repr(C)]
 #[derive(Debug, PartialEq, Eq)]
@@ -19,6 +29,12 @@ pub struct FunctionResultRaw {
 
 pub type FunctionResult = std::result::Result<isize, FunctionError>;
 
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+extern "C" {
+    /// Makes newly written code visible to instruction fetches (from libgcc or compiler-rt)
+    fn __clear_cache(start: *mut libc::c_char, end: *mut libc::c_char);
+}
+
 pub fn function_result_from_raw(raw: FunctionResultRaw) -> FunctionResult {
     match function_error_from_raw(raw.error) {
         None => Ok(raw.value),
@@ -26,9 +42,27 @@ pub fn function_result_from_raw(raw: FunctionResultRaw) -> FunctionResult {
     }
 }
 
+/// The most parameters a `Function` can take
+pub const MAX_PARAM_COUNT: usize = 12;
+
+/// The default limit on how deeply words may call each other (see `FunctionBuilder::max_call_depth`)
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+/// The largest maximum call depth a `FunctionBuilder` accepts; its return stack takes 128 KiB on 64-bit targets
+pub const MAX_CALL_DEPTH_LIMIT: usize = 1 << 14;
+/// The default limit on how many values the stack may hold when a word is called (see `FunctionBuilder::max_stack_depth`)
+pub const DEFAULT_MAX_STACK_DEPTH: usize = 1 << 14;
+/// The largest maximum stack depth a `FunctionBuilder` accepts.
+///
+/// With both limits at their largest, words use at most 384 KiB of native stack on x86_64 (640 KiB on aarch64),
+/// plus what one word body pushes between calls, well within the 2 MiB Rust gives spawned threads.
+pub const MAX_STACK_DEPTH_LIMIT: usize = 1 << 15;
+/// The most arguments an extern function registered with `FunctionBuilder::register_extern` can take
+pub const MAX_EXTERN_ARITY: usize = 6;
+
 #[derive(Debug)]
 pub struct Function {
-    // TODO: keep track of how many params it uses?
+    param_count: usize,
+    result_count: Option<usize>,
     code: *mut c_void,
     code_length: usize,
 }
@@ -42,198 +76,302 @@ impl std::ops::Drop for Function {
     }
 }
 
+/// How `+`, `-`, and `*` behave on overflow when no explicit checked form (e.g. `+?`) is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArithmeticMode {
+    /// Two's-complement wrapping arithmetic
+    #[default]
+    Wrapping,
+    /// Return a `Signed*Overflow` error on two's-complement signed overflow
+    CheckedSigned,
+    /// Return an `Unsigned*Overflow` error on unsigned overflow
+    CheckedUnsigned,
+}
+
+/// How `<<`, `>>`, and `u>>` behave when the shift amount is negative or at least the number of bits in a word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShiftMode {
+    /// Use only the low bits of the shift amount, as x86 does (e.g. shifting by 65 shifts by 1 on 64-bit targets)
+    #[default]
+    Masked,
+    /// Return a `ShiftAmountOutOfRange` error
+    Checked,
+}
+
+/// A native function that programs can call by name (see `FunctionBuilder::register_extern`).
+///
+/// Each `extern "C" fn` taking up to `MAX_EXTERN_ARITY` `isize` arguments and returning an `isize` converts into one,
+/// e.g. `isqrt as extern "C" fn(isize) -> isize`.
+#[derive(Debug, Clone, Copy)]
+pub enum ExternFn {
+    Arity0(extern "C" fn() -> isize),
+    Arity1(extern "C" fn(isize) -> isize),
+    Arity2(extern "C" fn(isize, isize) -> isize),
+    Arity3(extern "C" fn(isize, isize, isize) -> isize),
+    Arity4(extern "C" fn(isize, isize, isize, isize) -> isize),
+    Arity5(extern "C" fn(isize, isize, isize, isize, isize) -> isize),
+    Arity6(extern "C" fn(isize, isize, isize, isize, isize, isize) -> isize),
+}
+
+impl ExternFn {
+    /// The number of arguments the function takes
+    pub fn arity(self) -> usize {
+        match self {
+            ExternFn::Arity0(_) => 0,
+            ExternFn::Arity1(_) => 1,
+            ExternFn::Arity2(_) => 2,
+            ExternFn::Arity3(_) => 3,
+            ExternFn::Arity4(_) => 4,
+            ExternFn::Arity5(_) => 5,
+            ExternFn::Arity6(_) => 6,
+        }
+    }
+    fn address(self) -> usize {
+        match self {
+            ExternFn::Arity0(function) => function as usize,
+            ExternFn::Arity1(function) => function as usize,
+            ExternFn::Arity2(function) => function as usize,
+            ExternFn::Arity3(function) => function as usize,
+            ExternFn::Arity4(function) => function as usize,
+            ExternFn::Arity5(function) => function as usize,
+            ExternFn::Arity6(function) => function as usize,
+        }
+    }
+}
+
+macro_rules! extern_fn_from {
+    ($variant:ident, $($arg:ident),*) => {
+        impl From<extern "C" fn($($arg),*) -> isize> for ExternFn {
+            fn from(function: extern "C" fn($($arg),*) -> isize) -> Self {
+                ExternFn::$variant(function)
+            }
+        }
+    }
+}
+
+extern_fn_from!(Arity0, );
+extern_fn_from!(Arity1, isize);
+extern_fn_from!(Arity2, isize, isize);
+extern_fn_from!(Arity3, isize, isize, isize);
+extern_fn_from!(Arity4, isize, isize, isize, isize);
+extern_fn_from!(Arity5, isize, isize, isize, isize, isize);
+extern_fn_from!(Arity6, isize, isize, isize, isize, isize, isize);
+
+/// A host function registered with `FunctionBuilder::register_extern` or `FunctionBuilder::register_extern_raw`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ExternFunction {
+    pub(crate) address: usize,
+    pub(crate) arity: usize,
+}
+
 #[derive(Debug, Clone)]
-pub enum FunctionCreateError {
-    UnrecognizedCommand,
-    InvalidInteger,
-    IntegerTooLarge,
-    IntegerOutOfRange,
-    StackUnderflow(&'static str),
-    UnterminatedLoop,
-    LoopChangedStackDepth,
-    AssembleError(AssembleError),
-    AllocationError(&'static str),
+pub struct FunctionBuilder {
+    arithmetic: ArithmeticMode,
+    shift: ShiftMode,
+    results: Option<usize>,
+    max_call_depth: usize,
+    max_stack_depth: usize,
+    externs: HashMap<String, ExternFunction>,
+    stack_caching: bool,
+    optimize: bool,
+    reject_constant_errors: bool,
 }
 
-impl From<AssembleError> for FunctionCreateError {
-    fn from(e: AssembleError) -> Self {
-        FunctionCreateError::AssembleError(e)
+impl Default for FunctionBuilder {
+    fn default() -> Self {
+        FunctionBuilder {
+            arithmetic: ArithmeticMode::default(),
+            shift: ShiftMode::default(),
+            results: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_stack_depth: DEFAULT_MAX_STACK_DEPTH,
+            externs: HashMap::new(),
+            stack_caching: false,
+            optimize: false,
+            reject_constant_errors: false,
+        }
     }
 }
 
-impl Function {
-    pub fn parse(mut s: &str) -> Result<Function, FunctionCreateError> {
-        let (_param_count, commands) = Function::parse_helper(&mut s)?;
-        s = s.trim_start();
-        if s.len() != 0 {
-            return Err(FunctionCreateError::UnrecognizedCommand);
-        }
-        // TODO: return param_count?
-        Function::new(commands)
-    }
-    fn parse_uint(s: &mut &str) -> Result<usize, FunctionCreateError> {
-        let mut value: usize;
-        static DIGITS: &[char] = &['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
-        *s = match s.strip_prefix(DIGITS) {
-            None => return Err(FunctionCreateError::InvalidInteger),
-            Some(rest) => {
-                value = s.chars().next().unwrap().to_digit(10).unwrap() as usize;
-                rest
-            },
-        };
-        while let Some(rest) = s.strip_prefix(DIGITS) {
-            let digit = s.chars().next().unwrap().to_digit(10).unwrap() as usize;
-            value = value
-                .checked_mul(10)
-                .ok_or(FunctionCreateError::IntegerTooLarge)?
-                .checked_add(digit)
-                .ok_or(FunctionCreateError::IntegerTooLarge)?;
-            *s = rest;
-        }
-        Ok(value)
-    }
-    fn parse_iint(s: &mut &str) -> Result<isize, FunctionCreateError> {
-        let negative: bool = match s.strip_prefix('-') {
-            None => false,
-            Some(rest) => { *s = rest; true }
-        };
-        let magnitude: usize = Function::parse_uint(s)?;
-        if !negative {
-            magnitude.try_into().map_err(|_| FunctionCreateError::IntegerOutOfRange)
-        } else if magnitude <= isize::MAX as usize {
-            Ok(-(magnitude as isize))
-        } else if magnitude == isize::MIN as usize {
-            Ok(magnitude as isize)
+impl FunctionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn arithmetic(mut self, mode: ArithmeticMode) -> Self {
+        self.arithmetic = mode;
+        self
+    }
+    pub fn shift(mut self, mode: ShiftMode) -> Self {
+        self.shift = mode;
+        self
+    }
+    /// Return the top `count` values of the stack through a results buffer (see `Function::call_into`)
+    /// instead of returning only the top value.
+    pub fn results(mut self, count: usize) -> Self {
+        self.results = Some(count);
+        self
+    }
+    /// Limit how deeply words may call each other, including recursively; deeper calls return a `StackOverflow` error.
+    ///
+    /// Functions that define words reserve room for this many return addresses in their stack frame
+    /// whenever they are called, one word each (8 KiB for the default depth on 64-bit targets).
+    /// `build` rejects depths above `MAX_CALL_DEPTH_LIMIT`.
+    pub fn max_call_depth(mut self, depth: usize) -> Self {
+        self.max_call_depth = depth;
+        self
+    }
+    /// Limit how many values the stack may hold when a word is called; calls with more return a `StackOverflow` error.
+    ///
+    /// Values are kept on the native stack, one word each (16 bytes on aarch64), so a function that defines words
+    /// holds at most this many, plus those a word body pushes before its next call, on top of its frame and return stack
+    /// (see `max_call_depth`).
+    /// `build` rejects depths above `MAX_STACK_DEPTH_LIMIT`.
+    pub fn max_stack_depth(mut self, depth: usize) -> Self {
+        self.max_stack_depth = depth;
+        self
+    }
+    /// Let programs call `function` by writing `name`, like a word. The call pops as many arguments as `function` takes
+    /// (the deepest is the first argument) and pushes the function's result.
+    ///
+    /// `parse` rejects names that could not be used for a word.
+    pub fn register_extern(self, name: &str, function: impl Into<ExternFn>) -> Self {
+        let function = function.into();
+        // A function pointer is valid for the whole program, and a panic in an `extern "C" fn` aborts instead of unwinding
+        unsafe { self.register_extern_raw(name, function.address() as *const c_void, function.arity()) }
+    }
+    /// Like `register_extern`, but for a function only known by its address; `build` rejects arities above `MAX_EXTERN_ARITY`.
+    ///
+    /// # Safety
+    ///
+    /// `function` must be an `extern "C" fn` that takes `arity` `isize` arguments, returns an `isize`, and does not unwind.
+    /// It must remain valid to call for as long as any `Function` parsed by this builder is.
+    pub unsafe fn register_extern_raw(mut self, name: &str, function: *const c_void, arity: usize) -> Self {
+        self.externs.insert(name.to_owned(), ExternFunction { address: function as usize, arity });
+        self
+    }
+    /// Keep the top few values of the stack in registers within runs of commands that only move or compute values
+    /// (e.g. `p2 p2 * s1 1 -`), storing them to memory only at the end of each run: before loops, if blocks,
+    /// calls, checked arithmetic, division, and `p`/`s` with a negative index.
+    ///
+    /// Stack underflow errors in a run point at the whole run. Only x86_64 supports this; elsewhere it has no effect.
+    pub fn stack_caching(mut self, enabled: bool) -> Self {
+        self.stack_caching = enabled;
+        self
+    }
+    /// Simplify programs before compiling them: compute operations on constants (`3 4 *` becomes `12`),
+    /// remove operations that leave their operand unchanged (`0 +`, `1 *`), and turn multiplication and division
+    /// by powers of two into shifts.
+    ///
+    /// On x86_64, constant operands of `+`, `-`, `&`, `|`, and `^` are also encoded in the instruction
+    /// (`5 +` becomes `add $5,(%rsp)`), and values pushed and immediately popped again are moved between registers instead.
+    ///
+    /// Constant operations that would fail (e.g. `1 0 /`) are kept, so the function still returns their error
+    /// when called, unless `reject_constant_errors` is set.
+    pub fn optimize(mut self, enabled: bool) -> Self {
+        self.optimize = enabled;
+        self
+    }
+    /// When optimizing, reject programs with an operation on constants that would always fail (e.g. `1 0 /`)
+    /// with a `ConstantOperationFails` error, even if it is never run.
+    pub fn reject_constant_errors(mut self, enabled: bool) -> Self {
+        self.reject_constant_errors = enabled;
+        self
+    }
+    pub fn parse(&self, source: &str) -> Result<Function, FunctionCreateError> {
+        let program = self.parse_program(source)?;
+        self.build(&program).map_err(|e| e.locate(source))
+    }
+    /// Parses `source` into a `Program` without compiling it.
+    ///
+    /// The arithmetic and shift modes decide which operations `+`, `<<`, etc. become;
+    /// the other settings only affect `build`.
+    pub fn parse_program(&self, source: &str) -> Result<Program, FunctionCreateError> {
+        Parser::new(self, source).parse().map_err(|e| e.locate(source))
+    }
+    /// Compiles `program`, which may have come from `parse_program` or been built directly.
+    ///
+    /// Errors in instructions with a span refer to it, but have no `location` since there is no program text.
+    pub fn build(&self, program: &Program) -> Result<Function, FunctionCreateError> {
+        let (commands, max_call_depth) = self.lower(program)?;
+        Function::from_commands(program.param_count, self.results, max_call_depth, self.optimize, commands)
+    }
+    /// Checks `program` like `parse`, and gives an `Interpreter` which runs it instead of compiled code
+    pub fn interpret(&self, source: &str) -> Result<Interpreter, FunctionCreateError> {
+        let program = self.parse_program(source)?;
+        self.build_interpreter(&program).map_err(|e| e.locate(source))
+    }
+    /// Checks `program` like `build`, and gives an `Interpreter` which runs it instead of compiled code
+    pub fn build_interpreter(&self, program: &Program) -> Result<Interpreter, FunctionCreateError> {
+        let (commands, _) = self.lower(program)?;
+        check_commands(program.param_count, self.results, &commands)?;
+        Ok(Interpreter::compile(self, program))
+    }
+    /// Checks `program` and lowers it to commands, also giving the call depth to reserve room for if it defines words
+    fn lower(&self, program: &Program) -> Result<(Vec<Command>, Option<usize>), FunctionCreateError> {
+        if self.max_call_depth > MAX_CALL_DEPTH_LIMIT
+            || self.max_stack_depth > MAX_STACK_DEPTH_LIMIT
+            || self.externs.values().any(|function| function.arity > MAX_EXTERN_ARITY)
+            || program.param_count > MAX_PARAM_COUNT {
+            return Err(FunctionCreateErrorKind::IntegerOutOfRange.into());
+        }
+        let optimized;
+        let body = if self.optimize {
+            optimized = fold_constants(&program.body, self.reject_constant_errors)?;
+            &optimized
         } else {
-            Err(FunctionCreateError::IntegerOutOfRange)
-        }
-    }
-    fn parse_helper(s: &mut &str) -> Result<(usize, Vec<Command>), FunctionCreateError> {
-        let mut param_count = 0;
-        let mut commands: Vec<Command> = vec![];
-        while {*s = s.trim_start(); s.len() > 0} {
-            // Commands are trimmed from s in their match
-            match s.chars().next() {
-                None => break,
-                Some(next) => match next {
-                    'a' => {
-                        param_count = param_count.max(1);
-                        commands.push(PUSH_A.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    'b' => {
-                        param_count = param_count.max(2);
-                        commands.push(PUSH_B.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    'c' => {
-                        param_count = param_count.max(3);
-                        commands.push(PUSH_C.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    'd' => {
-                        param_count = param_count.max(4);
-                        commands.push(PUSH_D.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    'e' => {
-                        param_count = param_count.max(5);
-                        commands.push(PUSH_E.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    'f' => {
-                        param_count = param_count.max(6);
-                        commands.push(PUSH_F.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    '+' => {
-                        commands.push(ADD.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    '-' => {
-                        commands.push(SUBTRACT.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    '*' => {
-                        commands.push(MULTIPLY.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    '/' => {
-                        commands.push(DIVIDE.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    '%' => {
-                        commands.push(MOD.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    '\\' => {
-                        commands.push(UDIVIDE.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    '@' => {
-                        commands.push(UMOD.clone());
-                        *s = s.split_at(1).1;
-                    },
-                    '0'..='9' => {
-                        let value: isize =
-                            Function::parse_uint(s)?
-                            .try_into()
-                            .map_err(|_| FunctionCreateError::IntegerTooLarge)?;
-                        commands.push(PUSH_VALUE(value));
-                    },
-                    'l'|'p' => {
-                        *s = s.split_at(1).1;
-                        let index: i32 = 
-                            Function::parse_iint(s)?
-                            .try_into()
-                            .map_err(|_| FunctionCreateError::StackUnderflow("Stack index out of range"))?;
-                        commands.push(PUSH_STACK_INDEX(index));
-                    },
-                    's' => {
-                        *s = s.split_at(1).1;
-                        let index: i32 = 
-                            Function::parse_iint(s)?
-                            .try_into()
-                            .map_err(|_| FunctionCreateError::StackUnderflow("Stack index out of range"))?;
-                        commands.push(POP_STACK_INDEX(index));
-                    },
-                    '{' => {
-                        *s = s.split_at(1).1;
-                        let (loop_param_count, loop_commands) = Function::parse_helper(s)?;
-                        *s = s.strip_prefix('}').ok_or(FunctionCreateError::UnterminatedLoop)?;
-                        param_count = param_count.max(loop_param_count);
-                        commands.push(WHILE_LOOP(loop_commands)?);
-                    },
-                    '}' => break, // Caller should check that the &str is empty
-                    _ => return Err(FunctionCreateError::UnrecognizedCommand),
-                },
-            };
-        }
-        Ok((param_count, commands))
-    }
-
-    pub(crate) fn new(commands: Vec<Command>) -> Result<Function, FunctionCreateError> {
-        let mut stack_size: usize = 0;
-        let mut code = Relocatable::from(function_header_code());
+            &program.body
+        };
+        let mut lowerer = Lowerer::new(self, program.param_count);
+        let commands = lowerer.lower_block(body)?;
+        let max_call_depth = lowerer.has_words().then_some(self.max_call_depth);
+        Ok((commands, max_call_depth))
+    }
+}
+
+impl Function {
+    pub fn parse(s: &str) -> Result<Function, FunctionCreateError> {
+        FunctionBuilder::new().parse(s)
+    }
+    /// The number of parameters the function takes
+    pub fn arity(&self) -> usize {
+        self.param_count
+    }
+    /// The number of results the function returns through a results buffer, if it was built with `FunctionBuilder::results`
+    pub fn result_count(&self) -> Option<usize> {
+        self.result_count
+    }
+    /// Compiles `program` with the default `FunctionBuilder` settings (see `FunctionBuilder::build`)
+    pub fn new(program: &Program) -> Result<Function, FunctionCreateError> {
+        FunctionBuilder::new().build(program)
+    }
+    /// Compiles `commands`, which take `param_count` parameters. If `result_count` is `Some`, the function takes the address of a results buffer
+    /// as an extra argument after its parameters, and stores the top values of the stack there.
+    ///
+    /// `max_call_depth` must be `Some` if the commands define words, to reserve room for their return addresses.
+    /// If `peephole` is set, the assembled code goes through the architecture's peephole pass.
+    pub(crate) fn from_commands(param_count: usize, result_count: Option<usize>, max_call_depth: Option<usize>, peephole: bool, commands: Vec<Command>) -> Result<Function, FunctionCreateError> {
+        check_commands(param_count, result_count, &commands)?;
+        let mut frame_slots: usize = 0;
+        let mut code = Relocatable::default();
         let mut data = Relocatable::default();
 
         for command in commands {
-            if stack_size < command.param_count {
-                return Err(FunctionCreateError::StackUnderflow("Function would pop value from empty stack"));
-            }
-            if stack_size < command.required_stack_depth {
-                dbg!(command);
-                return Err(FunctionCreateError::StackUnderflow("Function would use value from past end of stack"));
-            }
-            stack_size -= command.param_count;
-            stack_size += command.return_count;
+            frame_slots = frame_slots.max(command.frame_slots);
             code += command.code;
             data += command.data;
         }
-        if stack_size == 0 {
-            return Err(FunctionCreateError::StackUnderflow("Function would return from empty stack"));
+        if let Some(result_count) = result_count {
+            if result_count > 0 {
+                code += PUSH_ARGUMENT(param_count + 1).code;
+                code += STORE_RESULTS(result_count);
+            }
         }
 
+        let mut code = match max_call_depth {
+            // The return stack is addressed from the base of the data stack, so it goes past the other frame slots
+            Some(max_call_depth) => FUNCTION_HEADER(frame_slots + max_call_depth + 1) + CALL_STACK_INIT() + code,
+            None => FUNCTION_HEADER(frame_slots) + code,
+        };
         code += Relocatable::from(function_footer_code());
         code += Relocatable {
             data: function_abort_code().into(),
@@ -242,6 +380,9 @@ impl Function {
             relocations: vec![],
         };
 
+        if peephole {
+            code = crate::code::peephole(code);
+        }
         let code_and_data = code + data;
         let code = code_and_data.assemble()?;
 
@@ -256,7 +397,7 @@ impl Function {
             )
         };
         if code_binary.is_null() || code_binary == libc::MAP_FAILED {
-            return Err(FunctionCreateError::AllocationError("mmap failed"));
+            return Err(FunctionCreateErrorKind::AllocationError("mmap failed").into());
         }
 
         unsafe {
@@ -266,23 +407,70 @@ impl Function {
                 code.len(),
             )
         };
+        // ARM instruction caches are not coherent with data writes
+        #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+        unsafe {
+            let start = code_binary as *mut libc::c_char;
+            __clear_cache(start, start.add(code.len()));
+        }
 
         if 0 != unsafe {mprotect(code_binary, code.len(), libc::PROT_READ|libc::PROT_EXEC)} {
             let result = unsafe { munmap(code_binary, code.len()) };
             if result != 0 {
                 todo!("handle munmap() failure");
             }
-            return Err(FunctionCreateError::AllocationError("mprotect failed"));
+            return Err(FunctionCreateErrorKind::AllocationError("mprotect failed").into());
         }
         Ok(Function {
+            param_count,
+            result_count,
             code: code_binary,
             code_length: code.len(),
         })
     }
 }
 
+/// Checks that `commands` never use values past the bottom of the stack, and leave enough to return
+fn check_commands(param_count: usize, result_count: Option<usize>, commands: &[Command]) -> Result<(), FunctionCreateError> {
+    if result_count.is_some() && param_count >= MAX_PARAM_COUNT {
+        return Err(FunctionCreateErrorKind::TooManyParameters.into());
+    }
+    let mut stack_size: usize = 0;
+    for command in commands {
+        if stack_size < command.param_count {
+            return Err(FunctionCreateError::new(
+                FunctionCreateErrorKind::StackUnderflow("Function would pop value from empty stack"),
+                command.span.clone(),
+            ));
+        }
+        if stack_size < command.required_stack_depth {
+            return Err(FunctionCreateError::new(
+                FunctionCreateErrorKind::StackUnderflow("Function would use value from past end of stack"),
+                command.span.clone(),
+            ));
+        }
+        stack_size -= command.param_count;
+        stack_size += command.return_count;
+    }
+    if stack_size == 0 {
+        return Err(FunctionCreateErrorKind::StackUnderflow("Function would return from empty stack").into());
+    }
+    if let Some(result_count) = result_count {
+        if stack_size < result_count {
+            return Err(FunctionCreateErrorKind::StackUnderflow("Function would return more results than are on the stack").into());
+        }
+    }
+    Ok(())
+}
+
 macro_rules! impl_unsafe_as_fn_ptr {
     ($name:ident, $args:tt) => {
+        /// # Safety
+        ///
+        /// The function reads as many arguments as it has parameters (see `arity`),
+        /// so the returned pointer must only be used if it passes at least that many.
+        /// If the function has a `result_count`, the next argument must be the address of
+        /// a writable buffer of that many `isize`s.
         #[deny(unsafe_op_in_unsafe_fn)]
         pub unsafe fn $name(&self) -> extern "C" fn $args -> FunctionResultRaw {
             unsafe { std::mem::transmute(self.code) }
@@ -297,30 +485,135 @@ impl Function {
     impl_unsafe_as_fn_ptr!(as_fn_ptr_4, (intptr_t, intptr_t, intptr_t, intptr_t));
     impl_unsafe_as_fn_ptr!(as_fn_ptr_5, (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t));
     impl_unsafe_as_fn_ptr!(as_fn_ptr_6, (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t));
+    impl_unsafe_as_fn_ptr!(as_fn_ptr_7, (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t));
+    impl_unsafe_as_fn_ptr!(as_fn_ptr_8, (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t));
+    impl_unsafe_as_fn_ptr!(as_fn_ptr_9, (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t));
+    impl_unsafe_as_fn_ptr!(as_fn_ptr_10, (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t));
+    impl_unsafe_as_fn_ptr!(as_fn_ptr_11, (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t));
+    impl_unsafe_as_fn_ptr!(as_fn_ptr_12, (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t));
+}
+
+macro_rules! impl_call {
+    ($name:ident, ($($arg:ident),*)) => {
+        #[allow(clippy::too_many_arguments)]
+        pub fn $name(&self, $($arg: isize),*) -> FunctionResult {
+            self.call(&[$($arg),*])
+        }
+    }
+}
+impl Function {
+    /// Calls the function with `args`, which must have exactly as many values as the function has parameters.
+    ///
+    /// Returns `Err(FunctionError::ArgumentCountMismatch)` without calling the function otherwise,
+    /// and `Err(FunctionError::ResultCountMismatch)` if the function returns multiple results.
+    pub fn call(&self, args: &[isize]) -> FunctionResult {
+        if args.len() != self.param_count {
+            return Err(FunctionError::ArgumentCountMismatch);
+        }
+        if self.result_count.is_some() {
+            return Err(FunctionError::ResultCountMismatch);
+        }
+        // Safety: the function reads exactly `param_count` arguments, all of which are passed
+        match unsafe { self.call_raw(args) } {
+            Some(raw) => function_result_from_raw(raw),
+            None => Err(FunctionError::ArgumentCountMismatch),
+        }
+    }
+    /// Calls a function built with `FunctionBuilder::results` with `args`, storing its results in `results`,
+    /// deepest first (so the top of the stack is stored last).
+    ///
+    /// Returns `Err(FunctionError::ArgumentCountMismatch)` or `Err(FunctionError::ResultCountMismatch)`
+    /// without calling the function if `args` or `results` has the wrong length.
+    pub fn call_into(&self, args: &[isize], results: &mut [isize]) -> Result<(), FunctionError> {
+        if args.len() != self.param_count {
+            return Err(FunctionError::ArgumentCountMismatch);
+        }
+        if self.result_count != Some(results.len()) {
+            return Err(FunctionError::ResultCountMismatch);
+        }
+        let mut full_args = Vec::with_capacity(args.len() + 1);
+        full_args.extend_from_slice(args);
+        full_args.push(results.as_mut_ptr() as isize);
+        // Safety: the function reads its `param_count` arguments and then the results buffer address,
+        // and `results` has room for exactly `result_count` values
+        match unsafe { self.call_raw(&full_args) } {
+            Some(raw) => function_result_from_raw(raw).map(|_| ()),
+            None => Err(FunctionError::ArgumentCountMismatch),
+        }
+    }
+    /// Like `call_into`, but returns the results in a new `Vec`.
+    pub fn call_vec(&self, args: &[isize]) -> Result<Vec<isize>, FunctionError> {
+        let mut results = vec![0; self.result_count.ok_or(FunctionError::ResultCountMismatch)?];
+        self.call_into(args, &mut results)?;
+        Ok(results)
+    }
+    /// Like `call_into`, but returns the results in an array.
+    pub fn call_array<const N: usize>(&self, args: &[isize]) -> Result<[isize; N], FunctionError> {
+        let mut results = [0; N];
+        self.call_into(args, &mut results)?;
+        Ok(results)
+    }
+    /// Calls the function with `args` as its arguments, or returns `None` if there are too many of them.
+    ///
+    /// # Safety
+    ///
+    /// See `as_fn_ptr_0`.
+    unsafe fn call_raw(&self, args: &[isize]) -> Option<FunctionResultRaw> {
+        unsafe {
+            Some(match *args {
+                [] => self.as_fn_ptr_0()(),
+                [a] => self.as_fn_ptr_1()(a),
+                [a, b] => self.as_fn_ptr_2()(a, b),
+                [a, b, c] => self.as_fn_ptr_3()(a, b, c),
+                [a, b, c, d] => self.as_fn_ptr_4()(a, b, c, d),
+                [a, b, c, d, e] => self.as_fn_ptr_5()(a, b, c, d, e),
+                [a, b, c, d, e, f] => self.as_fn_ptr_6()(a, b, c, d, e, f),
+                [a, b, c, d, e, f, g] => self.as_fn_ptr_7()(a, b, c, d, e, f, g),
+                [a, b, c, d, e, f, g, h] => self.as_fn_ptr_8()(a, b, c, d, e, f, g, h),
+                [a, b, c, d, e, f, g, h, i] => self.as_fn_ptr_9()(a, b, c, d, e, f, g, h, i),
+                [a, b, c, d, e, f, g, h, i, j] => self.as_fn_ptr_10()(a, b, c, d, e, f, g, h, i, j),
+                [a, b, c, d, e, f, g, h, i, j, k] => self.as_fn_ptr_11()(a, b, c, d, e, f, g, h, i, j, k),
+                [a, b, c, d, e, f, g, h, i, j, k, l] => self.as_fn_ptr_12()(a, b, c, d, e, f, g, h, i, j, k, l),
+                _ => return None,
+            })
+        }
+    }
+    impl_call!(call0, ());
+    impl_call!(call1, (a));
+    impl_call!(call2, (a, b));
+    impl_call!(call3, (a, b, c));
+    impl_call!(call4, (a, b, c, d));
+    impl_call!(call5, (a, b, c, d, e));
+    impl_call!(call6, (a, b, c, d, e, f));
+    impl_call!(call7, (a, b, c, d, e, f, g));
+    impl_call!(call8, (a, b, c, d, e, f, g, h));
+    impl_call!(call9, (a, b, c, d, e, f, g, h, i));
+    impl_call!(call10, (a, b, c, d, e, f, g, h, i, j));
+    impl_call!(call11, (a, b, c, d, e, f, g, h, i, j, k));
+    impl_call!(call12, (a, b, c, d, e, f, g, h, i, j, k, l));
 }
 
 macro_rules! impl_fn_traits {
-    ($Args:ty, $as_fn_ptr:ident, $args:ident, ( $($args_expanded:tt)* ) ) => {
+    ($Args:ty, $call:ident, $args:ident, ( $($args_expanded:tt)* ) ) => {
         #[cfg(feature = "fn_traits")]
         impl std::ops::FnOnce<$Args> for Function {
             type Output = Result<isize, FunctionError>;
             extern "rust-call" fn call_once(self, args: $Args) -> Self::Output {
-                self.call(args)
+                std::ops::Fn::call(&self, args)
             }
         }
 
         #[cfg(feature = "fn_traits")]
         impl std::ops::FnMut<$Args> for Function {
             extern "rust-call" fn call_mut(&mut self, args: $Args) -> Self::Output {
-                self.call(args)
+                std::ops::Fn::call(self, args)
             }
         }
 
         #[cfg(feature = "fn_traits")]
         impl std::ops::Fn<$Args> for Function {
             extern "rust-call" fn call(&self, $args: $Args) -> Self::Output {
-                let fn_ptr = unsafe { self.$as_fn_ptr() };
-                function_result_from_raw((fn_ptr($($args_expanded)*)))
+                self.$call($($args_expanded)*)
             }
         }
     }
@@ -328,59 +621,96 @@ macro_rules! impl_fn_traits {
 
 impl_fn_traits!(
     (),
-    as_fn_ptr_0,
+    call0,
     _args,
     ()
 );
 impl_fn_traits!(
     (intptr_t, ),
-    as_fn_ptr_1,
+    call1,
     args,
     (args.0)
 );
 impl_fn_traits!(
     (intptr_t, intptr_t, ),
-    as_fn_ptr_2,
+    call2,
     args,
     (args.0, args.1)
 );
 impl_fn_traits!(
     (intptr_t, intptr_t, intptr_t, ),
-    as_fn_ptr_3,
+    call3,
     args,
     (args.0, args.1, args.2)
 );
 impl_fn_traits!(
     (intptr_t, intptr_t, intptr_t, intptr_t, ),
-    as_fn_ptr_4,
+    call4,
     args,
     (args.0, args.1, args.2, args.3)
 );
 impl_fn_traits!(
     (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, ),
-    as_fn_ptr_5,
+    call5,
     args,
     (args.0, args.1, args.2, args.3, args.4)
 );
 impl_fn_traits!(
     (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, ),
-    as_fn_ptr_6,
+    call6,
     args,
     (args.0, args.1, args.2, args.3, args.4, args.5)
 );
+impl_fn_traits!(
+    (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, ),
+    call7,
+    args,
+    (args.0, args.1, args.2, args.3, args.4, args.5, args.6)
+);
+impl_fn_traits!(
+    (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, ),
+    call8,
+    args,
+    (args.0, args.1, args.2, args.3, args.4, args.5, args.6, args.7)
+);
+impl_fn_traits!(
+    (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, ),
+    call9,
+    args,
+    (args.0, args.1, args.2, args.3, args.4, args.5, args.6, args.7, args.8)
+);
+impl_fn_traits!(
+    (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, ),
+    call10,
+    args,
+    (args.0, args.1, args.2, args.3, args.4, args.5, args.6, args.7, args.8, args.9)
+);
+impl_fn_traits!(
+    (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, ),
+    call11,
+    args,
+    (args.0, args.1, args.2, args.3, args.4, args.5, args.6, args.7, args.8, args.9, args.10)
+);
+impl_fn_traits!(
+    (intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, intptr_t, ),
+    call12,
+    args,
+    (args.0, args.1, args.2, args.3, args.4, args.5, args.6, args.7, args.8, args.9, args.10, args.11)
+);
 
 #[cfg(test)]
 mod tests {
     #[test]
     fn add_3() {
         use super::*;
-        let f = Function::new(vec![
-            PUSH_A.clone(),
-            PUSH_B.clone(),
-            PUSH_C.clone(),
-            ADD.clone(),
-            ADD.clone(),
-        ]).unwrap();
+        use crate::ir::*;
+        let f = Function::new(&Program { param_count: 3, body: vec![
+            Op::Argument(1).into(),
+            Op::Argument(2).into(),
+            Op::Argument(3).into(),
+            Op::Binary(BinaryOp::Add).into(),
+            Op::Binary(BinaryOp::Add).into(),
+        ]}).unwrap();
 
 //        dbg!(&f);
 
@@ -398,11 +728,12 @@ mod tests {
     #[test]
     fn mul() {
         use super::*;
-        let f = Function::new(vec![
-            PUSH_A.clone(),
-            PUSH_B.clone(),
-            MULTIPLY.clone(),
-        ]).unwrap();
+        use crate::ir::*;
+        let f = Function::new(&Program { param_count: 2, body: vec![
+            Op::Argument(1).into(),
+            Op::Argument(2).into(),
+            Op::Binary(BinaryOp::Multiply).into(),
+        ]}).unwrap();
 
 //        dbg!(&f);
 
@@ -414,7 +745,8 @@ mod tests {
 
         #[cfg(feature = "fn_traits")]
         {
-            assert_eq!(f(3, 4, 5), Ok(12));
+            assert_eq!(f(3, 4), Ok(12));
+            assert_eq!(f(3, 4, 5), Err(FunctionError::ArgumentCountMismatch));
         }
 
         drop(f);
@@ -422,13 +754,14 @@ mod tests {
     #[test]
     fn infinite_loop() {
         use super::*;
-        let f = Function::new(vec![
-            PUSH_A.clone(),
-            WHILE_LOOP(vec![
-                PUSH_B.clone(),
-                ADD.clone(),
-            ]).unwrap(),
-        ]).unwrap();
+        use crate::ir::*;
+        let f = Function::new(&Program { param_count: 2, body: vec![
+            Op::Argument(1).into(),
+            Op::While(vec![
+                Op::Argument(2).into(),
+                Op::Binary(BinaryOp::Add).into(),
+            ]).into(),
+        ]}).unwrap();
 
 //        dbg!(&f);
 
@@ -439,7 +772,7 @@ mod tests {
 
         #[cfg(feature = "fn_traits")]
         {
-            assert_eq!(f(3, 4, 5), Ok(12));
+            assert_eq!(f(5, -1), Ok(0));
         }
 
         drop(f);
@@ -448,22 +781,23 @@ mod tests {
     #[test]
     fn pow() {
         use super::*;
-        let f = Function::new(vec![
-            PUSH_VALUE(1),
-            PUSH_B.clone(),
-            WHILE_LOOP(vec![
-                PUSH_A.clone(),
-                PUSH_STACK_INDEX(-1),
-                // PUSH_STACK_INDEX(2),
-                MULTIPLY.clone(),
-                POP_STACK_INDEX(-1),
-                // POP_STACK_INDEX(1),
-                PUSH_VALUE(1),
-                SUBTRACT.clone(),
-            ]).unwrap(),
-            PUSH_STACK_INDEX(-1),
-            // PUSH_STACK_INDEX(1),
-        ]).unwrap();
+        use crate::ir::*;
+        let f = Function::new(&Program { param_count: 2, body: vec![
+            Op::Value(1).into(),
+            Op::Argument(2).into(),
+            Op::While(vec![
+                Op::Argument(1).into(),
+                Op::Pick(-1).into(),
+                // Op::Pick(2).into(),
+                Op::Binary(BinaryOp::Multiply).into(),
+                Op::Store(-1).into(),
+                // Op::Store(1).into(),
+                Op::Value(1).into(),
+                Op::Binary(BinaryOp::Subtract).into(),
+            ]).into(),
+            Op::Pick(-1).into(),
+            // Op::Pick(1).into(),
+        ]}).unwrap();
 
         let f_ptr = unsafe { f.as_fn_ptr_2() };
         assert_eq!(f_ptr(3, 4), FunctionResultRaw{value: 81, error: 0});
@@ -504,6 +838,189 @@ mod tests {
         drop(f);
     }
 
+    #[test]
+    fn if_else() {
+        use super::*;
+        let f = Function::parse("a [ b , c ]").unwrap();
+        assert_eq!(f.call(&[1, 2, 3]), Ok(2));
+        assert_eq!(f.call(&[-7, 2, 3]), Ok(2));
+        assert_eq!(f.call(&[0, 2, 3]), Ok(3));
+
+        let f = Function::parse("b a [ 2 * ]").unwrap();
+        assert_eq!(f.call(&[1, 5]), Ok(10));
+        assert_eq!(f.call(&[0, 5]), Ok(5));
+
+        // Arms may consume values, as long as they both leave the same depth
+        let f = Function::parse("b c a [ + , - ]").unwrap();
+        assert_eq!(f.call(&[1, 5, 3]), Ok(8));
+        assert_eq!(f.call(&[0, 5, 3]), Ok(2));
+
+        // Nested in loops and each other
+        let f = Function::parse("0 a { p1 b [ 1 [ 2 + , 100 + ] ] s1 1 - } p1").unwrap();
+        assert_eq!(f.call(&[4, 1]), Ok(8));
+        assert_eq!(f.call(&[4, 0]), Ok(0));
+
+        assert!(matches!(Function::parse("a [ b , b c ]").unwrap_err().kind(), FunctionCreateErrorKind::MismatchedBranchStackDepths));
+        assert!(matches!(Function::parse("a a [ b ]").unwrap_err().kind(), FunctionCreateErrorKind::MismatchedBranchStackDepths));
+        assert!(matches!(Function::parse("a [ + , 1 ]").unwrap_err().kind(), FunctionCreateErrorKind::MismatchedBranchStackDepths));
+        assert!(matches!(Function::parse("a [ b").unwrap_err().kind(), FunctionCreateErrorKind::UnterminatedIf));
+        assert!(matches!(Function::parse("a b [ + , - ]").unwrap_err().kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+    }
+
+    #[test]
+    fn comparisons() {
+        use super::*;
+        let compare = |op: &str, a: isize, b: isize| Function::parse(&format!("a b {}", op)).unwrap().call(&[a, b]);
+        for &(a, b) in &[(1, 2), (2, 1), (3, 3), (-1, 1), (isize::MIN, isize::MAX), (0, -1)] {
+            assert_eq!(compare("<", a, b), Ok((a < b) as isize));
+            assert_eq!(compare(">", a, b), Ok((a > b) as isize));
+            assert_eq!(compare("<=", a, b), Ok((a <= b) as isize));
+            assert_eq!(compare(">=", a, b), Ok((a >= b) as isize));
+            assert_eq!(compare("==", a, b), Ok((a == b) as isize));
+            assert_eq!(compare("!=", a, b), Ok((a != b) as isize));
+            let (ua, ub) = (a as usize, b as usize);
+            assert_eq!(compare("u<", a, b), Ok((ua < ub) as isize));
+            assert_eq!(compare("u>", a, b), Ok((ua > ub) as isize));
+            assert_eq!(compare("u<=", a, b), Ok((ua <= ub) as isize));
+            assert_eq!(compare("u>=", a, b), Ok((ua >= ub) as isize));
+        }
+
+        let not = Function::parse("a !").unwrap();
+        assert_eq!(not.call(&[0]), Ok(1));
+        assert_eq!(not.call(&[1]), Ok(0));
+        assert_eq!(not.call(&[-5]), Ok(0));
+
+        // sum of 0..n, looping while i < n
+        let f = Function::parse("0 0 p0 a < { p2 p2 + s2 p1 1 + s1 p1 a < s0 } p2").unwrap();
+        assert_eq!(f.call(&[5]), Ok(10));
+        assert_eq!(f.call(&[0]), Ok(0));
+
+        assert!(matches!(Function::parse("a b =").unwrap_err().kind(), FunctionCreateErrorKind::UnrecognizedCommand));
+    }
+
+    #[test]
+    fn bitwise() {
+        use super::*;
+        let binary = |op: &str, a: isize, b: isize| Function::parse(&format!("a b {}", op)).unwrap().call(&[a, b]);
+        for &(a, b) in &[(0b1100, 0b1010), (-1, 5), (isize::MIN, isize::MAX), (0, 0)] {
+            assert_eq!(binary("&", a, b), Ok(a & b));
+            assert_eq!(binary("|", a, b), Ok(a | b));
+            assert_eq!(binary("^", a, b), Ok(a ^ b));
+        }
+        assert_eq!(Function::parse("a ~").unwrap().call(&[0b1010]), Ok(!0b1010));
+
+        for &a in &[1, -1, 0x1234, isize::MIN, isize::MAX] {
+            for b in [0, 1, 3, isize::BITS as isize - 1] {
+                assert_eq!(binary("<<", a, b), Ok(a << b));
+                assert_eq!(binary(">>", a, b), Ok(a >> b));
+                assert_eq!(binary("u>>", a, b), Ok(((a as usize) >> b) as isize));
+            }
+        }
+
+        // By default, shift amounts are masked to the word width
+        let bits = isize::BITS as isize;
+        assert_eq!(binary("<<", 1, bits + 1), Ok(2));
+        assert_eq!(binary(">>", -8, bits + 2), Ok(-2));
+        assert_eq!(binary("u>>", 8, bits + 3), Ok(1));
+
+        let checked = |op: &str, a: isize, b: isize| FunctionBuilder::new()
+            .shift(ShiftMode::Checked)
+            .parse(&format!("a b {}", op))
+            .unwrap()
+            .call(&[a, b]);
+        for op in ["<<", ">>", "u>>"] {
+            assert_eq!(checked(op, 1, bits), Err(FunctionError::ShiftAmountOutOfRange));
+            assert_eq!(checked(op, 1, -1), Err(FunctionError::ShiftAmountOutOfRange));
+        }
+        assert_eq!(checked("<<", 1, bits - 1), Ok(isize::MIN));
+        assert_eq!(checked(">>", -8, 2), Ok(-2));
+        assert_eq!(checked("u>>", -1, bits - 1), Ok(1));
+    }
+
+    #[test]
+    fn stack_words() {
+        use super::*;
+        let f = |source: &str| Function::parse(&format!("(3) {}", source)).unwrap().call(&[1, 2, 3]);
+        assert_eq!(f("a dup +"), Ok(2));
+        assert_eq!(f("a b drop"), Ok(1));
+        assert_eq!(f("a b swap -"), Ok(1));
+        assert_eq!(f("a b swap drop"), Ok(2));
+        assert_eq!(f("a b c rot"), Ok(1));
+        assert_eq!(f("a b c rot drop"), Ok(3));
+        assert_eq!(f("a b c rot drop drop"), Ok(2));
+        assert_eq!(f("a b over"), Ok(1));
+        assert_eq!(f("a b over drop"), Ok(2));
+        assert_eq!(f("a b c 4 drop3"), Ok(1));
+        assert_eq!(f("c b a 4 drop0"), Ok(4));
+        // `d2` is still the fourth parameter followed by 2
+        assert_eq!(Function::parse("d2+").unwrap().call(&[1, 2, 3, 4]), Ok(6));
+
+        // Loops can drop their counter once they're done
+        let pow = Function::parse("1 b { a p2 * s1 1 - } drop").unwrap();
+        assert_eq!(pow.call(&[3, 4]), Ok(81));
+
+        assert!(matches!(Function::parse("a drop").unwrap_err().kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+        assert!(matches!(Function::parse("a b dup drop3").unwrap_err().kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+        assert!(matches!(Function::parse("a b rot").unwrap_err().kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+        assert!(matches!(Function::parse("a drop3x").unwrap_err().kind(), FunctionCreateErrorKind::InvalidInteger));
+        assert!(matches!(Function::parse("a drop99999999999").unwrap_err().kind(), FunctionCreateErrorKind::IntegerOutOfRange));
+    }
+
+    #[test]
+    fn counted_loops() {
+        use super::*;
+        let pow = Function::parse("1 b times{ a * }").unwrap();
+        assert_eq!(pow.call(&[3, 4]), Ok(81));
+        assert_eq!(pow.call(&[3, 0]), Ok(1));
+        assert_eq!(pow.call(&[3, -3]), Ok(1));
+
+        // Each nesting level gets its own hidden counter
+        let mul = Function::parse("0 a times{ b times{ 1 + } }").unwrap();
+        assert_eq!(mul.call(&[3, 4]), Ok(12));
+        assert_eq!(mul.call(&[0, 4]), Ok(0));
+
+        // Hidden counters do not move the bottom of the stack
+        let f = Function::parse("0 a times{ p-1 1 + s-1 } 7 drop").unwrap();
+        assert_eq!(f.call(&[5]), Ok(5));
+
+        let f = Function::parse("0 a times{ 1 + p0 3 == [ break ] }").unwrap();
+        assert_eq!(f.call(&[2]), Ok(2));
+        assert_eq!(f.call(&[10]), Ok(3));
+
+        assert!(matches!(Function::parse("times{ 1 drop }").unwrap_err().kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+        assert!(matches!(Function::parse("a times{ 1 }").unwrap_err().kind(), FunctionCreateErrorKind::LoopChangedStackDepth));
+        assert!(matches!(Function::parse("a a times{ 1 1 [ continue ] drop }").unwrap_err().kind(), FunctionCreateErrorKind::LoopChangedStackDepth));
+    }
+
+    #[test]
+    fn break_and_continue() {
+        use super::*;
+        // Double until greater than 10
+        let f = Function::parse("a { p0 10 > [ break ] 2 * }").unwrap();
+        assert_eq!(f.call(&[3]), Ok(12));
+        assert_eq!(f.call(&[0]), Ok(0));
+
+        // Sum of the odd numbers below a
+        let f = Function::parse("0 a { 1 - p0 2 % ! [ continue ] p0 p2 + s1 } drop").unwrap();
+        assert_eq!(f.call(&[6]), Ok(9));
+        assert_eq!(f.call(&[1]), Ok(0));
+
+        // `loop{ }` can only be left with `break`, so it may change the depth of the stack
+        let f = Function::parse("0 loop{ 1 + p0 a >= [ p0 break ] }").unwrap();
+        assert_eq!(f.call(&[5]), Ok(5));
+        let f = Function::parse("a 1 2 3 loop{ drop3 break }").unwrap();
+        assert_eq!(f.call(&[7]), Ok(7));
+
+        let err = |source: &str| Function::parse(source).unwrap_err().kind().clone();
+        assert!(matches!(err("a break"), FunctionCreateErrorKind::BreakOutsideLoop));
+        assert!(matches!(err("a [ continue ]"), FunctionCreateErrorKind::BreakOutsideLoop));
+        assert!(matches!(err("a { 1 break }"), FunctionCreateErrorKind::MismatchedLoopExitDepths));
+        assert!(matches!(err("a loop{ 1 [ 1 break , 2 3 break ] }"), FunctionCreateErrorKind::MismatchedLoopExitDepths));
+        assert!(matches!(err("a loop{ 1 }"), FunctionCreateErrorKind::LoopChangedStackDepth));
+        assert!(matches!(err("a loop{ 1 continue }"), FunctionCreateErrorKind::LoopChangedStackDepth));
+        assert!(matches!(err("a loop{ b"), FunctionCreateErrorKind::UnterminatedLoop));
+    }
+
     #[test]
     fn divide_and_mod_by_zero() {
         use super::*;
@@ -526,7 +1043,7 @@ mod tests {
         assert_eq!(f_ptr(301, 13), FunctionResultRaw{ value: 23, error: 0 });
         #[cfg(feature = "fn_traits")]
         {
-            assert_eq!(f(301, 13).unwrap_err(), Ok(23));
+            assert_eq!(f(301, 13), Ok(23));
         }
         let f = Function::parse("a b %").unwrap();
 
@@ -534,7 +1051,7 @@ mod tests {
         assert_eq!(f_ptr(301, 13), FunctionResultRaw{ value: 2, error: 0 });
         #[cfg(feature = "fn_traits")]
         {
-            assert_eq!(f(301, 13).unwrap_err(), Ok(2));
+            assert_eq!(f(301, 13), Ok(2));
         }
     }
 
@@ -547,7 +1064,7 @@ mod tests {
         assert_eq!(f_ptr(301, -13), FunctionResultRaw{ value: -23, error: 0 });
         #[cfg(feature = "fn_traits")]
         {
-            assert_eq!(f(301, -13).unwrap_err(), Ok(-23));
+            assert_eq!(f(301, -13), Ok(-23));
         }
         let f = Function::parse("a b %").unwrap();
 
@@ -555,7 +1072,7 @@ mod tests {
         assert_eq!(f_ptr(301, -13), FunctionResultRaw{ value: 2, error: 0 });
         #[cfg(feature = "fn_traits")]
         {
-            assert_eq!(f(301, -13).unwrap_err(), Ok(2));
+            assert_eq!(f(301, -13), Ok(2));
         }
     }
 
@@ -568,7 +1085,7 @@ mod tests {
         assert_eq!(f_ptr(-301, 13), FunctionResultRaw{ value: -23, error: 0 });
         #[cfg(feature = "fn_traits")]
         {
-            assert_eq!(f(-301, 13).unwrap_err(), Ok(-23));
+            assert_eq!(f(-301, 13), Ok(-23));
         }
         let f = Function::parse("a b %").unwrap();
 
@@ -576,7 +1093,7 @@ mod tests {
         assert_eq!(f_ptr(-301, 13), FunctionResultRaw{ value: -2, error: 0 });
         #[cfg(feature = "fn_traits")]
         {
-            assert_eq!(f(-301, 13).unwrap_err(), Ok(-2));
+            assert_eq!(f(-301, 13), Ok(-2));
         }
     }
 
@@ -589,7 +1106,7 @@ mod tests {
         assert_eq!(f_ptr(-301, -13), FunctionResultRaw{ value: 23, error: 0 });
         #[cfg(feature = "fn_traits")]
         {
-            assert_eq!(f(-301, -13).unwrap_err(), Ok(23));
+            assert_eq!(f(-301, -13), Ok(23));
         }
 
         let f = Function::parse("a b %").unwrap();
@@ -598,7 +1115,104 @@ mod tests {
         assert_eq!(f_ptr(-301, -13), FunctionResultRaw{ value: -2, error: 0 });
         #[cfg(feature = "fn_traits")]
         {
-            assert_eq!(f(-301, -13).unwrap_err(), Ok(-2));
+            assert_eq!(f(-301, -13), Ok(-2));
+        }
+    }
+
+    #[test]
+    fn divmod() {
+        use super::*;
+        let f = FunctionBuilder::new().results(2).parse("a b /%").unwrap();
+        assert_eq!(f.call_array(&[301, 13]), Ok([23, 2]));
+        assert_eq!(f.call_array(&[-301, 13]), Ok([-23, -2]));
+        assert_eq!(f.call_array(&[301, -13]), Ok([-23, 2]));
+        assert_eq!(f.call_array(&[-301, -13]), Ok([23, -2]));
+        assert_eq!(f.call_array(&[isize::MIN, 2]), Ok([isize::MIN / 2, 0]));
+        assert_eq!(f.call_array(&[isize::MIN, isize::MAX]), Ok([-1, -1]));
+        assert_eq!(f.call_array::<2>(&[3, 0]), Err(FunctionError::DivideByZero));
+        assert_eq!(f.call_array::<2>(&[isize::MIN, -1]), Err(FunctionError::DivideMinByNegativeOne));
+        assert_eq!(f.call_array(&[isize::MAX, -1]), Ok([-isize::MAX, 0]));
+
+        let f = FunctionBuilder::new().results(2).parse("a b \\@").unwrap();
+        assert_eq!(f.call_array(&[301, 13]), Ok([23, 2]));
+        assert_eq!(f.call_array(&[-1, 2]), Ok([isize::MAX, 1]));
+        assert_eq!(f.call_array(&[-1, -2]), Ok([1, 1]));
+        assert_eq!(f.call_array(&[isize::MIN, -1]), Ok([0, isize::MIN]));
+        assert_eq!(f.call_array::<2>(&[3, 0]), Err(FunctionError::DivideByZero));
+
+        assert_eq!(Function::parse("0 1 - 2 \\").unwrap().call0(), Ok(isize::MAX));
+        assert_eq!(Function::parse("0 1 - 7 @").unwrap().call0(), Ok((usize::MAX % 7) as isize));
+        // The remainder is on top
+        assert_eq!(Function::parse("a b /% -").unwrap().call2(17, 5), Ok(1));
+    }
+
+    #[test]
+    fn function_error_parity() {
+        use super::*;
+        use FunctionError::*;
+        // Every error a compiled function can report, from inputs that mean the same thing on every target
+        let unchecked = FunctionBuilder::new();
+        let signed = FunctionBuilder::new().arithmetic(ArithmeticMode::CheckedSigned);
+        let unsigned = FunctionBuilder::new().arithmetic(ArithmeticMode::CheckedUnsigned);
+        let shift = FunctionBuilder::new().shift(ShiftMode::Checked);
+        let shallow = FunctionBuilder::new().max_call_depth(4);
+        let bits = isize::BITS as isize;
+        let cases: &[(&FunctionBuilder, &str, [isize; 2], FunctionError)] = &[
+            (&unchecked, "a b /", [1, 0], DivideByZero),
+            (&unchecked, "a b %", [1, 0], DivideByZero),
+            (&unchecked, "a b /% +", [1, 0], DivideByZero),
+            (&unchecked, "a b \\", [1, 0], DivideByZero),
+            (&unchecked, "a b @", [1, 0], DivideByZero),
+            (&unchecked, "a b \\@ +", [1, 0], DivideByZero),
+            (&unchecked, "a b /", [isize::MIN, -1], DivideMinByNegativeOne),
+            (&unchecked, "a b %", [isize::MIN, -1], DivideMinByNegativeOne),
+            (&unchecked, "a b /% +", [isize::MIN, -1], DivideMinByNegativeOne),
+            (&signed, "a b +", [isize::MAX, 1], SignedAdditionOverflow),
+            (&signed, "a b -", [isize::MIN, 1], SignedSubtractionOverflow),
+            (&signed, "a b *", [isize::MAX, 2], SignedMultiplicationOverflow),
+            (&unsigned, "a b +", [-1, 1], UnsignedAdditionOverflow),
+            (&unsigned, "a b -", [0, 1], UnsignedSubtractionOverflow),
+            (&unsigned, "a b *", [-1, 2], UnsignedMultiplicationOverflow),
+            (&shift, "a b <<", [1, bits], ShiftAmountOutOfRange),
+            (&shift, "a b >>", [1, -1], ShiftAmountOutOfRange),
+            (&shift, "a b u>>", [1, bits], ShiftAmountOutOfRange),
+            (&shallow, ": down (1 -- 1) p0 [ 1 - down ] ; a down b +", [5, 0], StackOverflow),
+            // Errors inside words and loops abort the whole function too
+            (&signed, ": inc 1 + ; a inc b", [isize::MAX, 0], SignedAdditionOverflow),
+            (&unchecked, "a { b / }", [1, 0], DivideByZero),
+        ];
+        for &(builder, source, args, error) in cases {
+            let f = builder.parse(source).unwrap();
+            assert_eq!(f.call2(args[0], args[1]), Err(error), "{}", source);
+            assert_eq!(f.call(&args), Err(error), "{}", source);
+        }
+        assert_eq!(shallow.parse(": down (1 -- 1) p0 [ 1 - down ] ; a down b +").unwrap().call2(3, 1), Ok(1));
+
+        let f = FunctionBuilder::new().results(2).parse("a b").unwrap();
+        assert_eq!(f.call(&[1, 2]), Err(ResultCountMismatch));
+        assert_eq!(f.call_vec(&[1]), Err(ArgumentCountMismatch));
+    }
+
+    #[test]
+    fn self_contained_abort() {
+        use super::*;
+        let f = FunctionBuilder::new()
+            .arithmetic(ArithmeticMode::CheckedSigned)
+            .shift(ShiftMode::Checked)
+            .parse(": sq (1 -- 1) p0 * ; a b + c / sq d <<")
+            .unwrap();
+        assert_eq!(f.call4(1, 2, 3, 4), Ok(16));
+        assert_eq!(f.call4(1, 2, 0, 4), Err(FunctionError::DivideByZero));
+        assert_eq!(f.call4(isize::MAX, 1, 1, 4), Err(FunctionError::SignedAdditionOverflow));
+        assert_eq!(f.call4(1, 2, 3, 64), Err(FunctionError::ShiftAmountOutOfRange));
+
+        // Errors jump to the function's own abort code, not the copy linked into the library.
+        // x86_64 code is emitted at runtime, so there is no such copy there
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            let code = unsafe { std::slice::from_raw_parts(f.code as *const u8, f.code_length) };
+            let abort_address = (function_abort_code().as_ptr() as usize).to_ne_bytes();
+            assert!(code.windows(abort_address.len()).all(|window| window != abort_address));
         }
     }
 
@@ -637,4 +1251,476 @@ mod tests {
             let _result: Result<isize, Box<dyn std::error::Error>> = (|| Ok(f(intptr_t::MIN, -1)?))();
         }
     }
+
+    #[test]
+    fn checked_signed_arithmetic() {
+        use super::*;
+        let f = Function::parse("a b +?").unwrap();
+
+        let f_ptr = unsafe { f.as_fn_ptr_2() };
+        assert_eq!(f_ptr(2, 3), FunctionResultRaw{ value: 5, error: 0 });
+        assert_eq!(f_ptr(intptr_t::MAX, 1).error, FunctionError::SignedAdditionOverflow as isize);
+        assert_eq!(f_ptr(intptr_t::MIN, -1).error, FunctionError::SignedAdditionOverflow as isize);
+
+        let f = Function::parse("a b -?").unwrap();
+
+        let f_ptr = unsafe { f.as_fn_ptr_2() };
+        assert_eq!(f_ptr(2, 3), FunctionResultRaw{ value: -1, error: 0 });
+        assert_eq!(f_ptr(intptr_t::MIN, 1).error, FunctionError::SignedSubtractionOverflow as isize);
+        assert_eq!(f_ptr(0, intptr_t::MIN).error, FunctionError::SignedSubtractionOverflow as isize);
+
+        let f = Function::parse("a b *?").unwrap();
+
+        let f_ptr = unsafe { f.as_fn_ptr_2() };
+        assert_eq!(f_ptr(-2, 3), FunctionResultRaw{ value: -6, error: 0 });
+        assert_eq!(f_ptr(intptr_t::MAX, 2).error, FunctionError::SignedMultiplicationOverflow as isize);
+        assert_eq!(f_ptr(intptr_t::MIN, -1).error, FunctionError::SignedMultiplicationOverflow as isize);
+    }
+
+    #[test]
+    fn checked_unsigned_arithmetic() {
+        use super::*;
+        let f = Function::parse("a b u+?").unwrap();
+
+        let f_ptr = unsafe { f.as_fn_ptr_2() };
+        assert_eq!(f_ptr(intptr_t::MAX, 1), FunctionResultRaw{ value: intptr_t::MIN, error: 0 });
+        assert_eq!(f_ptr(-1, 1).error, FunctionError::UnsignedAdditionOverflow as isize);
+
+        let f = Function::parse("a b u-?").unwrap();
+
+        let f_ptr = unsafe { f.as_fn_ptr_2() };
+        assert_eq!(f_ptr(3, 2), FunctionResultRaw{ value: 1, error: 0 });
+        assert_eq!(f_ptr(2, 3).error, FunctionError::UnsignedSubtractionOverflow as isize);
+
+        let f = Function::parse("a b u*?").unwrap();
+
+        let f_ptr = unsafe { f.as_fn_ptr_2() };
+        assert_eq!(f_ptr(intptr_t::MAX, 2), FunctionResultRaw{ value: -2, error: 0 });
+        assert_eq!(f_ptr(-1, 2).error, FunctionError::UnsignedMultiplicationOverflow as isize);
+    }
+
+    #[test]
+    fn checked_arithmetic_mode() {
+        use super::*;
+        let f = FunctionBuilder::new()
+            .arithmetic(ArithmeticMode::CheckedSigned)
+            .parse("1 b { a p-1 * s-1 1 - } p-1")
+            .unwrap();
+
+        let f_ptr = unsafe { f.as_fn_ptr_2() };
+        assert_eq!(f_ptr(3, 4), FunctionResultRaw{ value: 81, error: 0 });
+        assert_eq!(f_ptr(5, 200).error, FunctionError::SignedMultiplicationOverflow as isize);
+
+        let f = FunctionBuilder::new()
+            .arithmetic(ArithmeticMode::CheckedUnsigned)
+            .parse("a b -")
+            .unwrap();
+
+        let f_ptr = unsafe { f.as_fn_ptr_2() };
+        assert_eq!(f_ptr(3, 2), FunctionResultRaw{ value: 1, error: 0 });
+        assert_eq!(f_ptr(2, 3).error, FunctionError::UnsignedSubtractionOverflow as isize);
+    }
+
+    #[test]
+    fn call() {
+        use super::*;
+        let f = Function::parse("a b c + *").unwrap();
+
+        assert_eq!(f.call(&[2, 3, 4]), Ok(14));
+        assert_eq!(f.call3(2, 3, 4), Ok(14));
+        assert_eq!(f.call(&[2, 3]), Err(FunctionError::ArgumentCountMismatch));
+        assert_eq!(f.call2(2, 3), Err(FunctionError::ArgumentCountMismatch));
+        assert_eq!(f.call4(2, 3, 4, 5), Err(FunctionError::ArgumentCountMismatch));
+
+        let f = Function::parse("1 2 +").unwrap();
+        assert_eq!(f.call0(), Ok(3));
+        assert_eq!(f.call(&[]), Ok(3));
+
+        let f = Function::parse("a b c d e f + + + + +").unwrap();
+        assert_eq!(f.call6(1, 2, 3, 4, 5, 6), Ok(21));
+        assert_eq!(f.call(&[1, 2, 3, 4, 5, 6, 7]), Err(FunctionError::ArgumentCountMismatch));
+
+        let f = Function::parse("a 0 /").unwrap();
+        assert_eq!(f.call1(3), Err(FunctionError::DivideByZero));
+    }
+
+    #[test]
+    fn arity() {
+        use super::*;
+        assert_eq!(Function::parse("1").unwrap().arity(), 0);
+        assert_eq!(Function::parse("a").unwrap().arity(), 1);
+        assert_eq!(Function::parse("c a +").unwrap().arity(), 3);
+        assert_eq!(Function::parse("1 { f + }").unwrap().arity(), 6);
+
+        let f = Function::parse("(3) a b +").unwrap();
+        assert_eq!(f.arity(), 3);
+        assert_eq!(f.call3(1, 2, 100), Ok(3));
+        assert_eq!(f.call2(1, 2), Err(FunctionError::ArgumentCountMismatch));
+
+        assert_eq!(Function::parse(" ( 2 ) a b +").unwrap().arity(), 2);
+        assert_eq!(Function::parse("(0) 7").unwrap().call0(), Ok(7));
+
+        assert!(matches!(Function::parse("(2) a b c + +"), Err(e) if matches!(e.kind(), FunctionCreateErrorKind::UndeclaredParameter)));
+        assert!(matches!(Function::parse("(1) 1 { b + }"), Err(e) if matches!(e.kind(), FunctionCreateErrorKind::UndeclaredParameter)));
+        assert!(matches!(Function::parse("(13) a"), Err(e) if matches!(e.kind(), FunctionCreateErrorKind::IntegerOutOfRange)));
+        assert!(matches!(Function::parse("(2 a"), Err(e) if matches!(e.kind(), FunctionCreateErrorKind::UnrecognizedCommand)));
+        assert!(matches!(Function::parse("a (1)"), Err(e) if matches!(e.kind(), FunctionCreateErrorKind::UnrecognizedCommand)));
+    }
+
+    #[test]
+    fn many_arguments() {
+        use super::*;
+        let args: Vec<isize> = (1..=12).map(|i| i * i).collect();
+        let sum = Function::parse("$1 $2 + $3 + $4 + $5 + $6 + $7 + $8 + $9 + $10 + $11 + $12 +").unwrap();
+        assert_eq!(sum.arity(), 12);
+        assert_eq!(sum.call(&args), Ok(args.iter().sum()));
+        assert_eq!(sum.call(&args[..11]), Err(FunctionError::ArgumentCountMismatch));
+
+        // `$1` through `$6` are `a` through `f`
+        let f = Function::parse("$12 $7 - $3 c - +").unwrap();
+        assert_eq!(f.call(&args), Ok(144 - 49));
+
+        for n in 7..=12 {
+            let f = Function::parse(&format!("${}", n)).unwrap();
+            assert_eq!(f.arity(), n);
+            assert_eq!(f.call(&args[..n]), Ok(args[n - 1]));
+        }
+        let f = Function::parse("$8 $7 -").unwrap();
+        assert_eq!(f.call8(0, 0, 0, 0, 0, 0, 3, 10), Ok(7));
+        let f_ptr = unsafe { f.as_fn_ptr_8() };
+        assert_eq!(f_ptr(0, 0, 0, 0, 0, 0, 3, 10), FunctionResultRaw{value: 7, error: 0});
+
+        assert_eq!(Function::parse("(10) $9").unwrap().arity(), 10);
+        assert!(matches!(Function::parse("(8) $9").unwrap_err().kind(), FunctionCreateErrorKind::UndeclaredParameter));
+        assert!(matches!(Function::parse("$0").unwrap_err().kind(), FunctionCreateErrorKind::IntegerOutOfRange));
+        assert!(matches!(Function::parse("$13").unwrap_err().kind(), FunctionCreateErrorKind::IntegerOutOfRange));
+        assert!(matches!(Function::parse("$ 1").unwrap_err().kind(), FunctionCreateErrorKind::InvalidInteger));
+    }
+
+    #[test]
+    fn words() {
+        use super::*;
+        let f = Function::parse(": sq p0 * ; a sq b sq +").unwrap();
+        assert_eq!(f.arity(), 2);
+        assert_eq!(f.call2(3, 4), Ok(25));
+
+        // Words can call earlier words, use loops, and be called from loops
+        let f = Function::parse(": sq p0 * ; : cube p0 sq * ; 0 a times{ a cube + }").unwrap();
+        assert_eq!(f.call1(3), Ok(3 * 27));
+        let f = Function::parse(": twice 2 * ; : quad twice twice ; 5 quad").unwrap();
+        assert_eq!(f.call0(), Ok(20));
+        let f = Function::parse(": pow 1 swap times{ over * } swap drop ; a b pow").unwrap();
+        assert_eq!(f.call2(3, 4), Ok(81));
+        assert_eq!(f.call2(2, 10), Ok(1024));
+
+        // Stack effects are inferred from the body
+        let f = Function::parse(": two 1 2 ; two + two + +").unwrap();
+        assert_eq!(f.call0(), Ok(6));
+        assert!(matches!(Function::parse(": add + ; 1 add").unwrap_err().kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+
+        // Errors inside words abort the whole function
+        let f = Function::parse(": div / ; a b div").unwrap();
+        assert_eq!(f.call2(1, 0), Err(FunctionError::DivideByZero));
+
+        assert!(matches!(Function::parse(": sq p0 *").unwrap_err().kind(), FunctionCreateErrorKind::UnterminatedDefinition));
+        assert!(matches!(Function::parse(": dup p0 ; 1").unwrap_err().kind(), FunctionCreateErrorKind::InvalidWordName));
+        assert!(matches!(Function::parse(": x p0 ; 1").unwrap_err().kind(), FunctionCreateErrorKind::InvalidWordName));
+        assert!(matches!(Function::parse(": 2x p0 ; 1").unwrap_err().kind(), FunctionCreateErrorKind::InvalidWordName));
+        assert!(matches!(Function::parse(": sq p0 * ; : sq p0 ; 1").unwrap_err().kind(), FunctionCreateErrorKind::RedefinedWord));
+        assert!(matches!(Function::parse("1 { : brk break ; }").unwrap_err().kind(), FunctionCreateErrorKind::BreakOutsideLoop));
+        assert!(matches!(Function::parse("1 ;").unwrap_err().kind(), FunctionCreateErrorKind::UnrecognizedCommand));
+    }
+
+    #[test]
+    fn recursion() {
+        use super::*;
+        let f = Function::parse(": fib (1 -- 1) p0 2 < [ , p0 1 - fib swap 2 - fib + ] ; a fib").unwrap();
+        assert_eq!(f.call1(1), Ok(1));
+        assert_eq!(f.call1(20), Ok(6765));
+
+        let f = Function::parse(": ack (2 -- 1) p1 ! [ swap drop 1 + , p0 ! [ drop 1 - 1 ack , over 1 - rot rot 1 - ack ack ] ] ; a b ack").unwrap();
+        assert_eq!(f.call2(2, 3), Ok(9));
+        assert_eq!(f.call2(3, 3), Ok(61));
+
+        // Recursive words can be called from counted loops, and can use them outside of their recursive calls
+        let f = Function::parse(": sum (1 -- 1) p0 [ 0 p1 times{ 1 + } swap 1 - sum + ] ; 0 a times{ 3 sum + }").unwrap();
+        assert_eq!(f.call1(2), Ok(12));
+
+        let f = Function::parse(": down (1 -- 1) p0 [ 1 - down ] ; a down 1 +").unwrap();
+        assert_eq!(f.call1(1000), Ok(1));
+        assert_eq!(f.call1(2000), Err(FunctionError::StackOverflow));
+        let f = FunctionBuilder::new().max_call_depth(3000).parse(": down (1 -- 1) p0 [ 1 - down ] ; a down 1 +").unwrap();
+        assert_eq!(f.call1(2000), Ok(1));
+        assert_eq!(f.call1(3001), Err(FunctionError::StackOverflow));
+        // The call depth starts over on each call
+        assert_eq!(f.call1(2999), Ok(1));
+        let f = Function::parse(": forever (0 -- 0) forever ; forever 1").unwrap();
+        assert_eq!(f.call0(), Err(FunctionError::StackOverflow));
+
+        // Values left on the stack by each call are limited too, rather than overflowing the native stack
+        let f = FunctionBuilder::new().max_stack_depth(100).parse(": down (1 -- 1) p0 [ 0 p1 1 - down + s0 ] ; a down 1 +").unwrap();
+        assert_eq!(f.call1(49), Ok(1));
+        assert_eq!(f.call1(50), Err(FunctionError::StackOverflow));
+        let wide = |values: usize| format!(": down (1 -- 1) p0 [ {}p{values} 1 - down drop{values} swap drop ] ; a down 1 +", "1 ".repeat(values));
+        let run = |builder: FunctionBuilder, source: String, arg: isize| {
+            std::thread::Builder::new().stack_size(2 << 20).spawn(move || builder.parse(&source).unwrap().call1(arg)).unwrap().join().unwrap()
+        };
+        assert_eq!(run(FunctionBuilder::new(), wide(300), 1000), Err(FunctionError::StackOverflow));
+        assert_eq!(run(FunctionBuilder::new().max_call_depth(MAX_CALL_DEPTH_LIMIT), wide(8), 60000), Err(FunctionError::StackOverflow));
+        // The largest limits fit in a spawned thread's stack
+        let largest = FunctionBuilder::new().max_call_depth(MAX_CALL_DEPTH_LIMIT).max_stack_depth(MAX_STACK_DEPTH_LIMIT);
+        assert_eq!(run(largest.clone(), wide(100), 16000), Err(FunctionError::StackOverflow));
+        assert_eq!(run(largest.clone(), wide(1), MAX_CALL_DEPTH_LIMIT as isize - 2), Ok(2));
+        assert_eq!(run(largest, ": down (1 -- 1) p0 [ 1 - down ] ; a down 1 +".to_owned(), MAX_CALL_DEPTH_LIMIT as isize), Err(FunctionError::StackOverflow));
+
+        assert!(matches!(Function::parse(": fib p0 2 < [ , p0 1 - fib swap 2 - fib + ] ; 1 fib").unwrap_err().kind(), FunctionCreateErrorKind::UndeclaredStackEffect));
+        assert!(matches!(Function::parse(": sq (1 -- 1) p0 ; 1 sq").unwrap_err().kind(), FunctionCreateErrorKind::WordStackEffectMismatch));
+        assert!(matches!(Function::parse(": sq (0 -- 1) p0 p0 * ; 1 sq").unwrap_err().kind(), FunctionCreateErrorKind::WordStackEffectMismatch));
+        assert!(matches!(Function::parse(": rep (1 -- 1) 2 times{ rep } ; 1 rep").unwrap_err().kind(), FunctionCreateErrorKind::RecursionInCountedLoop));
+        assert!(matches!(FunctionBuilder::new().max_call_depth(MAX_CALL_DEPTH_LIMIT + 1).parse("1").unwrap_err().kind(), FunctionCreateErrorKind::IntegerOutOfRange));
+        assert!(matches!(FunctionBuilder::new().max_stack_depth(MAX_STACK_DEPTH_LIMIT + 1).parse("1").unwrap_err().kind(), FunctionCreateErrorKind::IntegerOutOfRange));
+    }
+
+    #[test]
+    fn externs() {
+        use super::*;
+        use std::sync::atomic::{AtomicIsize, Ordering};
+        extern "C" fn answer() -> isize {
+            42
+        }
+        extern "C" fn isqrt(x: isize) -> isize {
+            (x as f64).sqrt() as isize
+        }
+        extern "C" fn digits(a: isize, b: isize, c: isize, d: isize, e: isize, f: isize) -> isize {
+            ((((a * 10 + b) * 10 + c) * 10 + d) * 10 + e) * 10 + f
+        }
+        static LOGGED: AtomicIsize = AtomicIsize::new(0);
+        extern "C" fn log(x: isize) -> isize {
+            LOGGED.fetch_add(x, Ordering::Relaxed);
+            x
+        }
+        let builder = FunctionBuilder::new()
+            .register_extern("answer", answer as extern "C" fn() -> isize)
+            .register_extern("isqrt", isqrt as extern "C" fn(isize) -> isize)
+            .register_extern("digits", digits as extern "C" fn(isize, isize, isize, isize, isize, isize) -> isize)
+            .register_extern("log", log as extern "C" fn(isize) -> isize);
+        assert_eq!(ExternFn::from(digits as extern "C" fn(isize, isize, isize, isize, isize, isize) -> isize).arity(), 6);
+
+        assert_eq!(builder.parse("answer").unwrap().call0(), Ok(42));
+        let f = builder.parse("a isqrt b isqrt +").unwrap();
+        assert_eq!(f.call2(16, 99), Ok(13));
+        // Arguments are passed deepest first
+        let f = builder.parse("7 1 2 3 4 5 6 digits").unwrap();
+        assert_eq!(f.call0(), Ok(123456));
+        for depth in 0..4 {
+            let source = format!("{}1 2 3 4 5 6 digits {}", "0 ".repeat(depth), "+ ".repeat(depth));
+            assert_eq!(builder.parse(&source).unwrap().call0(), Ok(123456), "{}", source);
+        }
+        // Extern functions can be called from words and loops
+        let f = builder.parse(": logsq p0 * log ; 0 a times{ a logsq + }").unwrap();
+        assert_eq!(f.call1(3), Ok(27));
+        assert_eq!(LOGGED.load(Ordering::Relaxed), 27);
+
+        assert!(matches!(builder.parse("digits").unwrap_err().kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+        assert!(matches!(builder.parse(": isqrt p0 ; 1").unwrap_err().kind(), FunctionCreateErrorKind::RedefinedWord));
+        let builder = FunctionBuilder::new().register_extern("dup", answer as extern "C" fn() -> isize);
+        assert!(matches!(builder.parse("1").unwrap_err().kind(), FunctionCreateErrorKind::InvalidWordName));
+
+        // Functions only known by their address
+        let builder = unsafe { FunctionBuilder::new().register_extern_raw("isqrt", isqrt as extern "C" fn(isize) -> isize as *const c_void, 1) };
+        assert_eq!(builder.parse("a isqrt").unwrap().call1(49), Ok(7));
+        let builder = unsafe { FunctionBuilder::new().register_extern_raw("many", answer as extern "C" fn() -> isize as *const c_void, 7) };
+        assert!(matches!(builder.parse("1").unwrap_err().kind(), FunctionCreateErrorKind::IntegerOutOfRange));
+    }
+
+    #[test]
+    fn multiple_results() {
+        use super::*;
+        let f = FunctionBuilder::new().results(3).parse("a b + a b - a b *").unwrap();
+        assert_eq!(f.result_count(), Some(3));
+        assert_eq!(f.call_vec(&[7, 3]), Ok(vec![10, 4, 21]));
+        assert_eq!(f.call_array(&[2, 5]), Ok([7, -3, 10]));
+        let mut results = [0; 3];
+        assert_eq!(f.call_into(&[1, 1], &mut results), Ok(()));
+        assert_eq!(results, [2, 0, 1]);
+        assert_eq!(f.call_into(&[1, 1], &mut [0; 2]), Err(FunctionError::ResultCountMismatch));
+        assert_eq!(f.call_vec(&[1]), Err(FunctionError::ArgumentCountMismatch));
+        assert_eq!(f.call2(1, 1), Err(FunctionError::ResultCountMismatch));
+
+        // Only the top values are results
+        let f = FunctionBuilder::new().results(2).parse("(7) 1 $6 $7").unwrap();
+        assert_eq!(f.call_array(&[0, 0, 0, 0, 0, 8, 9]), Ok([8, 9]));
+        let f = FunctionBuilder::new().results(1).parse("a b c").unwrap();
+        assert_eq!(f.call_array(&[1, 2, 3]), Ok([3]));
+        assert_eq!(Function::parse("a").unwrap().call_vec(&[1]), Err(FunctionError::ResultCountMismatch));
+
+        // Errors are still reported
+        let f = FunctionBuilder::new().results(2).parse("a b / a").unwrap();
+        assert_eq!(f.call_vec(&[1, 0]), Err(FunctionError::DivideByZero));
+        assert_eq!(f.call_vec(&[6, 3]), Ok(vec![2, 6]));
+
+        assert!(matches!(FunctionBuilder::new().results(3).parse("a b").unwrap_err().kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+        assert!(matches!(FunctionBuilder::new().results(1).parse("(12) a").unwrap_err().kind(), FunctionCreateErrorKind::TooManyParameters));
+    }
+
+    #[test]
+    fn error_locations() {
+        use super::*;
+        let location = |source: &str| {
+            let err = Function::parse(source).unwrap_err();
+            let location = err.location().expect("parse errors should have a location").clone();
+            (err.kind().clone(), location.offset, location.line, location.column, location.token)
+        };
+
+        assert!(matches!(location("a b x +"), (FunctionCreateErrorKind::UnrecognizedCommand, 4, 1, 5, t) if t == "x"));
+        assert!(matches!(location("a b\n  c uu+ +"), (FunctionCreateErrorKind::UnrecognizedCommand, 8, 2, 5, t) if t == "uu+"));
+        assert!(matches!(location("1 99999999999999999999999 +"), (FunctionCreateErrorKind::IntegerTooLarge, 2, 1, 3, t) if t == "99999999999999999999999"));
+        assert!(matches!(location("a p-x"), (FunctionCreateErrorKind::InvalidInteger, 2, 1, 3, t) if t == "p-x"));
+        assert!(matches!(location("a } b"), (FunctionCreateErrorKind::UnrecognizedCommand, 2, 1, 3, t) if t == "}"));
+        assert!(matches!(location("(1) a\nb +"), (FunctionCreateErrorKind::UndeclaredParameter, 6, 2, 1, t) if t == "b"));
+        assert!(matches!(location("a { 1 { }"), (FunctionCreateErrorKind::UnterminatedLoop, 2, 1, 3, t) if t == "{"));
+        assert!(matches!(location("a { b }"), (FunctionCreateErrorKind::LoopChangedStackDepth, 2, 1, 3, t) if t == "{ b }"));
+
+        // Stack checking happens after parsing, but still points at the offending command
+        assert!(matches!(location("a b + +"), (FunctionCreateErrorKind::StackUnderflow(_), 6, 1, 7, t) if t == "+"));
+        assert!(matches!(location("1 p1"), (FunctionCreateErrorKind::StackUnderflow(_), 2, 1, 3, t) if t == "p1"));
+        assert!(matches!(location("a\n{ 1 + p3 s0 }"), (FunctionCreateErrorKind::StackUnderflow(_), 2, 2, 1, t) if t == "{ 1 + p3 s0 }"));
+
+        // Errors that are not tied to a command have no location
+        let err = Function::parse("  ").unwrap_err();
+        assert!(matches!(err.kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+        assert!(err.location().is_none());
+    }
+
+    #[test]
+    fn error_display() {
+        use super::*;
+        let source = "a b\n  c uu+ +";
+        let err = Function::parse(source).unwrap_err();
+        assert_eq!(err.to_string(), "2:5: Unrecognized command at 'uu+'");
+        assert_eq!(
+            err.render(source),
+            "error: Unrecognized command\n --> 2:5\n  |\n2 |   c uu+ +\n  |     ^^^\n",
+        );
+
+        let _result: Result<Function, Box<dyn std::error::Error>> = (|| Ok(Function::parse(source)?))();
+    }
+
+    #[test]
+    fn stack_caching() {
+        use super::*;
+        let programs = [
+            "a 1 b { p2 p2 * s1 1 - } p1",
+            "(3) a b c rot - swap over * +",
+            "(6) a b c d e f 10 20 + + + + + + +",
+            // More values than registers, then reaching back past them
+            "(2) a b 1 2 3 4 5 6 p7 p7 * s3 drop3 + + + +",
+            "(2) a b 1 2 3 4 5 drop6",
+            "(2) a b p-1 p-2 - s-1 +",
+            "(8) a b < a b u>= + a ! + a b == + a b != + $8 +",
+            "(2) a b << a b >> ^ a b u>> | a ~ &",
+            "(2) a 12345678901 * b 0 1 - * 2147483648 + -",
+            "(2) a b [ 1 2 + , 3 4 - ] a b >= { drop 0 } p0 +",
+            "(3) a b c drop drop dup dup dup dup dup drop4 +",
+        ];
+        for source in programs {
+            let plain = Function::parse(source).unwrap();
+            let cached = FunctionBuilder::new().stack_caching(true).parse(source).unwrap();
+            for args in [[4, 3, 2, 5, 6, 7, 8, 9], [-3, 7, 0, 1, 1, 1, 1, 1], [isize::MIN, 2, 1, 0, 0, 0, 0, -1]] {
+                let args = &args[..plain.arity()];
+                assert_eq!(cached.call(args), plain.call(args), "{} with {:?}", source, args);
+            }
+        }
+
+        // Stack errors in a run of cached commands point at the whole run
+        let err = FunctionBuilder::new().stack_caching(true).parse("a b + + 1").unwrap_err();
+        assert!(matches!(err.kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+        assert_eq!(err.location().unwrap().token, "a b + + 1");
+    }
+
+    #[test]
+    fn ir_programs() {
+        use super::*;
+        use crate::ir::*;
+        let program = FunctionBuilder::new().arithmetic(ArithmeticMode::CheckedSigned).parse_program("a 2 +").unwrap();
+        assert_eq!(program, Program { param_count: 1, body: vec![
+            Instruction { op: Op::Argument(1), span: Some(0..1) },
+            Instruction { op: Op::Value(2), span: Some(2..3) },
+            Instruction { op: Op::Checked(CheckedOp::Add), span: Some(4..5) },
+        ]});
+        assert_eq!(Function::new(&program).unwrap().call1(isize::MAX), Err(FunctionError::SignedAdditionOverflow));
+
+        // : sq p0 * ; a sq
+        let f = Function::new(&Program { param_count: 1, body: vec![
+            Op::DefineWord { name: "sq".to_owned(), stack_effect: None, body: vec![
+                Op::Pick(0).into(),
+                Op::Binary(BinaryOp::Multiply).into(),
+            ]}.into(),
+            Op::Argument(1).into(),
+            Op::CallWord("sq".to_owned()).into(),
+        ]}).unwrap();
+        assert_eq!(f.call1(-7), Ok(49));
+
+        // Programs that did not come from text can still be invalid
+        for (body, kind) in [
+            (vec![Op::Argument(2)], FunctionCreateErrorKind::UndeclaredParameter),
+            (vec![Op::Argument(1), Op::CallWord("sq".to_owned())], FunctionCreateErrorKind::UndefinedWord),
+            (vec![Op::Argument(1), Op::CallExtern("sq".to_owned())], FunctionCreateErrorKind::UndefinedWord),
+            (vec![Op::Argument(1), Op::Break], FunctionCreateErrorKind::BreakOutsideLoop),
+            (vec![Op::Argument(1), Op::Binary(BinaryOp::Add)], FunctionCreateErrorKind::StackUnderflow("")),
+        ] {
+            let program = Program { param_count: 1, body: body.into_iter().map(Instruction::from).collect() };
+            let err = Function::new(&program).unwrap_err();
+            assert_eq!(std::mem::discriminant(err.kind()), std::mem::discriminant(&kind), "{:?}", program);
+            assert!(err.location().is_none());
+        }
+    }
+
+    #[test]
+    fn optimize() {
+        use super::*;
+        let programs = [
+            "3 4 * a +",
+            "a 0 + 1 * b 0 | -",
+            "a 2 / b 8 / + a 16 % +",
+            "a 4 \\ b 32 @ +",
+            "a 1 2 + 3 * * b 1024 * -",
+            "a 1 1 + 0 / [ 1 , 2 ] b +",
+            "(2) 5 2 /% + 7 3 \\@ - 1 ! ~ +",
+            "a { 3 1 + / } b 2 / +",
+            ": half 2 / ; a half b half +",
+            "a 5 + b - 3 ^ 255 & 1 | 3000000000 +",
+            "b 100000 - a 7 + [ 7 - , 9 ^ ] p0 +",
+            ": inc 1 + ; a inc inc b 0 3 - + -",
+        ];
+        let checked = FunctionBuilder::new().arithmetic(ArithmeticMode::CheckedSigned);
+        for source in programs {
+            for builder in [FunctionBuilder::new(), checked.clone(), FunctionBuilder::new().stack_caching(true)] {
+                let plain = builder.parse(source).unwrap();
+                let optimized = builder.optimize(true).parse(source).unwrap();
+                for args in [[7, 3], [-7, 3], [-1, -9], [isize::MIN, isize::MAX], [0, 1]] {
+                    assert_eq!(optimized.call(&args), plain.call(&args), "{} with {:?}", source, args);
+                }
+            }
+        }
+
+        // Immediate operands and moves between registers take less code than pushing and popping
+        #[cfg(target_arch = "x86_64")]
+        {
+            let source = "a 5 + b - 3 ^";
+            let optimized = FunctionBuilder::new().optimize(true).parse(source).unwrap();
+            assert!(optimized.code_length < FunctionBuilder::new().parse(source).unwrap().code_length);
+            assert_eq!(optimized.call(&[1, 2]), Ok(7));
+        }
+
+        // Constant operations that fail still fail when called, unless rejected when parsing
+        let f = FunctionBuilder::new().optimize(true).parse("a [ 1 0 / , 2 ]").unwrap();
+        assert_eq!(f.call1(1), Err(FunctionError::DivideByZero));
+        assert_eq!(f.call1(0), Ok(2));
+        let err = FunctionBuilder::new().optimize(true).reject_constant_errors(true).parse("a [ 1 0 / , 2 ]").unwrap_err();
+        assert!(matches!(err.kind(), FunctionCreateErrorKind::ConstantOperationFails(FunctionError::DivideByZero)));
+        assert_eq!(err.location().unwrap().token, "1 0 /");
+        let err = checked.optimize(true).reject_constant_errors(true).parse(&format!("a {} 1 + +", isize::MAX)).unwrap_err();
+        assert!(matches!(err.kind(), FunctionCreateErrorKind::ConstantOperationFails(FunctionError::SignedAdditionOverflow)));
+    }
 }
@@ -0,0 +1,195 @@
+use crate::code::AssembleError;
+use std::ops::Range;
+
+/// Where in the program text an error occurred
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// Byte offset of the start of the offending token
+    pub offset: usize,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column, counted in characters
+    pub column: usize,
+    /// The offending token
+    pub token: String,
+}
+
+impl SourceLocation {
+    pub(crate) fn new(source: &str, span: Range<usize>) -> Self {
+        let before = &source[..span.start];
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+        SourceLocation {
+            offset: span.start,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            token: source[span].to_owned(),
+        }
+    }
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum FunctionCreateErrorKind {
+    UnrecognizedCommand,
+    InvalidInteger,
+    IntegerTooLarge,
+    IntegerOutOfRange,
+    StackUnderflow(&'static str),
+    UnterminatedLoop,
+    LoopChangedStackDepth,
+    /// The `break`s out of a loop leave the stack at different depths
+    MismatchedLoopExitDepths,
+    /// `break` or `continue` outside of a loop
+    BreakOutsideLoop,
+    /// An if block is missing its closing `]`
+    UnterminatedIf,
+    /// The two arms of an if-else block leave the stack at different depths
+    MismatchedBranchStackDepths,
+    /// The program uses a parameter past its declared arity
+    UndeclaredParameter,
+    /// A word definition is missing its closing `;`
+    UnterminatedDefinition,
+    /// A word definition's name is a built-in command, a single character, or starts with a digit
+    InvalidWordName,
+    /// A word is defined more than once
+    RedefinedWord,
+    /// A word calls itself without declaring its stack effect
+    UndeclaredStackEffect,
+    /// A word's declared stack effect does not match its body
+    WordStackEffectMismatch,
+    /// A word calls itself inside a `times{ }` loop, whose counter the call would overwrite
+    RecursionInCountedLoop,
+    /// A call of a word that is neither defined earlier nor registered as an extern function
+    UndefinedWord,
+    /// An operation on constants would always fail (see `FunctionBuilder::reject_constant_errors`)
+    ConstantOperationFails(super::FunctionError),
+    /// The function takes too many parameters to also take a results buffer
+    TooManyParameters,
+    AssembleError(AssembleError),
+    AllocationError(&'static str),
+}
+
+impl std::fmt::Display for FunctionCreateErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use FunctionCreateErrorKind::*;
+        match self {
+            UnrecognizedCommand => write!(f, "Unrecognized command"),
+            InvalidInteger => write!(f, "Invalid integer"),
+            IntegerTooLarge => write!(f, "Integer too large"),
+            IntegerOutOfRange => write!(f, "Integer out of range"),
+            StackUnderflow(msg) => write!(f, "{}", msg),
+            UnterminatedLoop => write!(f, "Loop is missing its closing '}}'"),
+            LoopChangedStackDepth => write!(f, "Loop body changes the depth of the stack"),
+            MismatchedLoopExitDepths => write!(f, "Loop exits leave the stack at different depths"),
+            BreakOutsideLoop => write!(f, "'break' or 'continue' outside of a loop"),
+            UnterminatedIf => write!(f, "If block is missing its closing ']'"),
+            MismatchedBranchStackDepths => write!(f, "If block arms leave the stack at different depths"),
+            UndeclaredParameter => write!(f, "Parameter is past the declared number of parameters"),
+            UnterminatedDefinition => write!(f, "Word definition is missing its closing ';'"),
+            InvalidWordName => write!(f, "Invalid word name"),
+            RedefinedWord => write!(f, "Word is already defined"),
+            UndeclaredStackEffect => write!(f, "Recursive word must declare its stack effect"),
+            WordStackEffectMismatch => write!(f, "Word body does not match its declared stack effect"),
+            RecursionInCountedLoop => write!(f, "Word cannot call itself inside a 'times{{' loop"),
+            UndefinedWord => write!(f, "Word is not defined"),
+            ConstantOperationFails(e) => write!(f, "Constant operation always fails: {}", e),
+            TooManyParameters => write!(f, "Function with multiple results can take at most {} parameters", super::MAX_PARAM_COUNT - 1),
+            AssembleError(e) => write!(f, "Failed to assemble function: {}", e),
+            AllocationError(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionCreateError {
+    kind: FunctionCreateErrorKind,
+    span: Option<Range<usize>>,
+    location: Option<SourceLocation>,
+}
+
+impl FunctionCreateError {
+    pub(crate) fn new(kind: FunctionCreateErrorKind, span: Option<Range<usize>>) -> Self {
+        FunctionCreateError { kind, span, location: None }
+    }
+    pub fn kind(&self) -> &FunctionCreateErrorKind {
+        &self.kind
+    }
+    /// Where in the program text the error occurred, if it came from parsing a program
+    pub fn location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
+    /// Attaches `span` if the error does not already have a location
+    pub(crate) fn or_span(mut self, span: Range<usize>) -> Self {
+        self.span.get_or_insert(span);
+        self
+    }
+    /// Resolves the error's span into a `SourceLocation` in `source`
+    pub(crate) fn locate(mut self, source: &str) -> Self {
+        if let Some(span) = &self.span {
+            self.location = Some(SourceLocation::new(source, span.clone()));
+        }
+        self
+    }
+    /// Renders the error with the line of `source` it occurred on, underlining the offending token.
+    ///
+    /// ```text
+    /// error: Unrecognized command
+    ///  --> 1:5
+    ///   |
+    /// 1 | a b x +
+    ///   |     ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let location = match &self.location {
+            Some(location) => location,
+            None => return format!("error: {}\n", self.kind),
+        };
+        let line_text = source.lines().nth(location.line - 1).unwrap_or("");
+        let gutter = " ".repeat(location.line.to_string().len());
+        // Only underline the part of the token on its first line
+        let underline_len = location.token
+            .lines()
+            .next()
+            .map_or(0, |token| token.chars().count())
+            .max(1);
+        format!(
+            "error: {kind}\n{gutter}--> {location}\n{gutter} |\n{line} | {line_text}\n{gutter} | {pad}{carets}\n",
+            kind = self.kind,
+            gutter = gutter,
+            location = location,
+            line = location.line,
+            line_text = line_text,
+            pad = " ".repeat(location.column - 1),
+            carets = "^".repeat(underline_len),
+        )
+    }
+}
+
+impl std::fmt::Display for FunctionCreateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {} at '{}'", location, self.kind, location.token),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for FunctionCreateError {}
+
+impl From<FunctionCreateErrorKind> for FunctionCreateError {
+    fn from(kind: FunctionCreateErrorKind) -> Self {
+        FunctionCreateError::new(kind, None)
+    }
+}
+
+impl From<AssembleError> for FunctionCreateError {
+    fn from(e: AssembleError) -> Self {
+        FunctionCreateErrorKind::AssembleError(e).into()
+    }
+}
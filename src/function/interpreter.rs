@@ -0,0 +1,448 @@
+use super::{FunctionBuilder, FunctionCreateError, FunctionError, FunctionResult};
+use crate::ir::{evaluate, Instruction, Op, Program};
+use std::collections::HashMap;
+
+/// One step of an interpreted program. Control flow is flattened into jumps to other steps,
+/// so that deeply nested word calls do not use the native stack.
+#[derive(Debug, Clone)]
+enum Step {
+    /// Pushes the `index`th (1-based) argument
+    Argument(usize),
+    Value(isize),
+    /// Pushes a copy of a value, counting from the top if `index` is not negative, otherwise from the bottom
+    Pick(i32),
+    /// Pops a value and stores it over another, counted as in `Pick`
+    Store(i32),
+    /// Pops and discards this many values
+    Drop(usize),
+    Swap,
+    Rot,
+    Not,
+    LogicalNot,
+    /// A two-operand operation, computed by `ir::evaluate`
+    Evaluate(Op),
+    /// Pops `arity` arguments and calls an extern function with them
+    CallExtern { address: usize, arity: usize },
+    Jump(usize),
+    /// Jumps if the top of the stack is zero, without popping it
+    JumpIfZero(usize),
+    /// Jumps if the top of the stack is not zero, without popping it
+    JumpUnlessZero(usize),
+    /// Pops a value, and jumps if it is zero
+    PopJumpIfZero(usize),
+    /// Pops the count of a `times{ }` loop, and jumps past the loop if it is not positive
+    StartCount(usize),
+    /// Counts an iteration of the innermost `times{ }` loop, and jumps back to its body if there are more
+    Count(usize),
+    /// Leaves the innermost `times{ }` loop
+    EndCount,
+    /// Calls the word starting at the given step
+    Call(usize),
+    Return,
+}
+
+impl Step {
+    fn set_target(&mut self, target: usize) {
+        match self {
+            Step::Jump(to) | Step::JumpIfZero(to) | Step::JumpUnlessZero(to) | Step::PopJumpIfZero(to) | Step::StartCount(to) => *to = target,
+            _ => unreachable!("step is not a forward jump"),
+        }
+    }
+}
+
+/// The `break` and `continue` jumps of a loop, to be pointed at its end
+#[derive(Debug, Default)]
+struct LoopExits {
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+/// Turns a checked program into `Step`s
+struct Flattener<'a> {
+    builder: &'a FunctionBuilder,
+    steps: Vec<Step>,
+    /// The exits of the loops enclosing the instruction being flattened, innermost last
+    loops: Vec<LoopExits>,
+    /// The first step of each word defined so far
+    words: HashMap<&'a str, usize>,
+}
+
+impl<'a> Flattener<'a> {
+    fn push(&mut self, step: Step) -> usize {
+        self.steps.push(step);
+        self.steps.len() - 1
+    }
+
+    /// Points the jump at `index` to the next step
+    fn land(&mut self, index: usize) {
+        let target = self.steps.len();
+        self.steps[index].set_target(target);
+    }
+
+    fn block(&mut self, instructions: &'a [Instruction]) {
+        for instruction in instructions {
+            self.op(&instruction.op);
+        }
+    }
+
+    /// Flattens a loop body followed by `footer`, pointing the body's `continue`s at the footer; gives its `break`s
+    fn loop_body(&mut self, body: &'a [Instruction], footer: Step) -> Vec<usize> {
+        self.loops.push(LoopExits::default());
+        self.block(body);
+        let exits = self.loops.pop().expect("loop exits were just pushed");
+        for index in exits.continues {
+            self.land(index);
+        }
+        self.push(footer);
+        exits.breaks
+    }
+
+    fn op(&mut self, op: &'a Op) {
+        let step = match op {
+            &Op::Argument(index) => Step::Argument(index),
+            &Op::Value(value) => Step::Value(value),
+            &Op::Pick(index) => Step::Pick(index),
+            &Op::Store(index) => Step::Store(index),
+            Op::Dup => Step::Pick(0),
+            Op::Over => Step::Pick(1),
+            Op::Drop => Step::Drop(1),
+            &Op::DropN(count) => Step::Drop(count),
+            Op::Swap => Step::Swap,
+            Op::Rot => Step::Rot,
+            Op::Not => Step::Not,
+            Op::LogicalNot => Step::LogicalNot,
+            Op::Binary(_) | Op::Checked(_) | Op::Division(_) => Step::Evaluate(op.clone()),
+            Op::While(body) => {
+                let header = self.push(Step::JumpIfZero(0));
+                let start = self.steps.len();
+                let breaks = self.loop_body(body, Step::JumpUnlessZero(start));
+                for index in breaks.into_iter().chain([header]) {
+                    self.land(index);
+                }
+                return;
+            },
+            Op::Loop(body) => {
+                let start = self.steps.len();
+                for index in self.loop_body(body, Step::Jump(start)) {
+                    self.land(index);
+                }
+                return;
+            },
+            Op::Times(body) => {
+                let header = self.push(Step::StartCount(0));
+                let start = self.steps.len();
+                // `break` also leaves the loop's count behind
+                for index in self.loop_body(body, Step::Count(start)) {
+                    self.land(index);
+                }
+                self.push(Step::EndCount);
+                self.land(header);
+                return;
+            },
+            Op::Break | Op::Continue => {
+                let index = self.push(Step::Jump(0));
+                let exits = self.loops.last_mut().expect("`break` and `continue` are checked to be in loops");
+                match op {
+                    Op::Break => exits.breaks.push(index),
+                    _ => exits.continues.push(index),
+                }
+                return;
+            },
+            Op::If(then_body, else_body) => {
+                let header = self.push(Step::PopJumpIfZero(0));
+                self.block(then_body);
+                if else_body.is_empty() {
+                    self.land(header);
+                } else {
+                    let skip_else = self.push(Step::Jump(0));
+                    self.land(header);
+                    self.block(else_body);
+                    self.land(skip_else);
+                }
+                return;
+            },
+            Op::DefineWord { name, body, .. } => {
+                let skip = self.push(Step::Jump(0));
+                // The word can call itself
+                self.words.insert(name, self.steps.len());
+                let loops = std::mem::take(&mut self.loops);
+                self.block(body);
+                self.loops = loops;
+                self.push(Step::Return);
+                self.land(skip);
+                return;
+            },
+            Op::CallWord(name) => Step::Call(self.words[name.as_str()]),
+            Op::CallExtern(name) => {
+                let function = self.builder.externs[name];
+                Step::CallExtern { address: function.address, arity: function.arity }
+            },
+        };
+        self.push(step);
+    }
+}
+
+/// Calls the extern function at `address` with `args`.
+///
+/// # Safety
+///
+/// `address` must be a function registered with `FunctionBuilder::register_extern` or `register_extern_raw` with an arity of `args.len()`.
+unsafe fn call_extern(address: usize, args: &[isize]) -> isize {
+    use std::mem::transmute;
+    type I = isize;
+    unsafe {
+        match *args {
+            [] => transmute::<usize, extern "C" fn() -> I>(address)(),
+            [a] => transmute::<usize, extern "C" fn(I) -> I>(address)(a),
+            [a, b] => transmute::<usize, extern "C" fn(I, I) -> I>(address)(a, b),
+            [a, b, c] => transmute::<usize, extern "C" fn(I, I, I) -> I>(address)(a, b, c),
+            [a, b, c, d] => transmute::<usize, extern "C" fn(I, I, I, I) -> I>(address)(a, b, c, d),
+            [a, b, c, d, e] => transmute::<usize, extern "C" fn(I, I, I, I, I) -> I>(address)(a, b, c, d, e),
+            [a, b, c, d, e, f] => transmute::<usize, extern "C" fn(I, I, I, I, I, I) -> I>(address)(a, b, c, d, e, f),
+            _ => unreachable!("extern arities are checked when building"),
+        }
+    }
+}
+
+/// Runs a program in Rust instead of compiling it, with the same results and errors as a `Function` built with the same settings.
+///
+/// It accepts exactly the programs `FunctionBuilder::build` does, so it can check compiled functions
+/// against an independent implementation, or help find out why one misbehaves.
+/// The optimization and stack caching settings only change how a `Function` is compiled, so they have no effect on it.
+#[derive(Debug, Clone)]
+pub struct Interpreter {
+    param_count: usize,
+    result_count: Option<usize>,
+    max_call_depth: usize,
+    max_stack_depth: usize,
+    steps: Vec<Step>,
+}
+
+impl Interpreter {
+    /// Flattens `program`, which `builder` has checked
+    pub(super) fn compile(builder: &FunctionBuilder, program: &Program) -> Self {
+        let mut flattener = Flattener { builder, steps: vec![], loops: vec![], words: HashMap::new() };
+        flattener.block(&program.body);
+        Interpreter {
+            param_count: program.param_count,
+            result_count: builder.results,
+            max_call_depth: builder.max_call_depth,
+            max_stack_depth: builder.max_stack_depth,
+            steps: flattener.steps,
+        }
+    }
+    pub fn parse(s: &str) -> Result<Interpreter, FunctionCreateError> {
+        FunctionBuilder::new().interpret(s)
+    }
+    /// Checks `program` with the default `FunctionBuilder` settings (see `FunctionBuilder::build_interpreter`)
+    pub fn new(program: &Program) -> Result<Interpreter, FunctionCreateError> {
+        FunctionBuilder::new().build_interpreter(program)
+    }
+    /// The number of parameters the program takes
+    pub fn arity(&self) -> usize {
+        self.param_count
+    }
+    /// The number of results the program returns, if it was built with `FunctionBuilder::results`
+    pub fn result_count(&self) -> Option<usize> {
+        self.result_count
+    }
+    /// Like `Function::call`
+    pub fn call(&self, args: &[isize]) -> FunctionResult {
+        if args.len() != self.param_count {
+            return Err(FunctionError::ArgumentCountMismatch);
+        }
+        if self.result_count.is_some() {
+            return Err(FunctionError::ResultCountMismatch);
+        }
+        let stack = self.run(args)?;
+        Ok(*stack.last().expect("programs are checked to leave a value"))
+    }
+    /// Like `Function::call_into`
+    pub fn call_into(&self, args: &[isize], results: &mut [isize]) -> Result<(), FunctionError> {
+        if args.len() != self.param_count {
+            return Err(FunctionError::ArgumentCountMismatch);
+        }
+        if self.result_count != Some(results.len()) {
+            return Err(FunctionError::ResultCountMismatch);
+        }
+        let stack = self.run(args)?;
+        results.copy_from_slice(&stack[stack.len() - results.len()..]);
+        Ok(())
+    }
+    /// Like `Function::call_vec`
+    pub fn call_vec(&self, args: &[isize]) -> Result<Vec<isize>, FunctionError> {
+        let mut results = vec![0; self.result_count.ok_or(FunctionError::ResultCountMismatch)?];
+        self.call_into(args, &mut results)?;
+        Ok(results)
+    }
+    /// Like `Function::call_array`
+    pub fn call_array<const N: usize>(&self, args: &[isize]) -> Result<[isize; N], FunctionError> {
+        let mut results = [0; N];
+        self.call_into(args, &mut results)?;
+        Ok(results)
+    }
+
+    /// Runs the program, giving the stack it leaves, bottom first
+    fn run(&self, args: &[isize]) -> Result<Vec<isize>, FunctionError> {
+        let mut stack: Vec<isize> = vec![];
+        // The remaining iterations of the enclosing `times{ }` loops, innermost last
+        let mut counts: Vec<isize> = vec![];
+        let mut return_steps: Vec<usize> = vec![];
+        // Negative indices count from the bottom of the stack, so -1 is the first value pushed
+        let slot = |stack: &Vec<isize>, index: i32| match usize::try_from(index) {
+            Ok(index) => stack.len() - 1 - index,
+            Err(_) => index.unsigned_abs() as usize - 1,
+        };
+        let mut next = 0;
+        while let Some(step) = self.steps.get(next) {
+            next += 1;
+            match *step {
+                Step::Argument(index) => stack.push(args[index - 1]),
+                Step::Value(value) => stack.push(value),
+                Step::Pick(index) => stack.push(stack[slot(&stack, index)]),
+                Step::Store(index) => {
+                    let value = stack.pop().unwrap();
+                    let slot = slot(&stack, index);
+                    stack[slot] = value;
+                },
+                Step::Drop(count) => stack.truncate(stack.len() - count),
+                Step::Swap => {
+                    let len = stack.len();
+                    stack.swap(len - 1, len - 2);
+                },
+                Step::Rot => {
+                    let third = stack.remove(stack.len() - 3);
+                    stack.push(third);
+                },
+                Step::Not => *stack.last_mut().unwrap() = !*stack.last().unwrap(),
+                Step::LogicalNot => *stack.last_mut().unwrap() = (*stack.last().unwrap() == 0) as isize,
+                Step::Evaluate(ref op) => {
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.pop().unwrap();
+                    stack.extend(evaluate(op, lhs, rhs).expect("only two-operand operations are evaluated")?);
+                },
+                Step::CallExtern { address, arity } => {
+                    let args = stack.split_off(stack.len() - arity);
+                    // Safety: the function was registered with this arity, and registering it promises it can be called so
+                    stack.push(unsafe { call_extern(address, &args) });
+                },
+                Step::Jump(target) => next = target,
+                Step::JumpIfZero(target) => if *stack.last().unwrap() == 0 {
+                    next = target;
+                },
+                Step::JumpUnlessZero(target) => if *stack.last().unwrap() != 0 {
+                    next = target;
+                },
+                Step::PopJumpIfZero(target) => if stack.pop().unwrap() == 0 {
+                    next = target;
+                },
+                Step::StartCount(end) => {
+                    let count = stack.pop().unwrap();
+                    if count > 0 {
+                        counts.push(count);
+                    } else {
+                        next = end;
+                    }
+                },
+                Step::Count(start) => {
+                    let count = counts.last_mut().unwrap();
+                    *count -= 1;
+                    if *count > 0 {
+                        next = start;
+                    }
+                },
+                Step::EndCount => {
+                    counts.pop();
+                },
+                Step::Call(start) => {
+                    if return_steps.len() >= self.max_call_depth || stack.len() > self.max_stack_depth {
+                        return Err(FunctionError::StackOverflow);
+                    }
+                    return_steps.push(next);
+                    next = start;
+                },
+                Step::Return => next = return_steps.pop().unwrap(),
+            }
+        }
+        Ok(stack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn matches_compiled_functions() {
+        use super::*;
+        use crate::function::{ArithmeticMode, ShiftMode};
+        extern "C" fn weigh(x: isize, y: isize) -> isize {
+            x.wrapping_mul(3).wrapping_sub(y)
+        }
+        let programs = [
+            "a b + a b - * a b * ^",
+            "a b 1 rot over drop2 dup swap - +",
+            "a b p-1 p-2 * s-1 +",
+            "a b /% - a b \\@ + ^",
+            "a b << a b >> a b u>> + +",
+            "a b < a b u>= + a b == + a b != + a ! + a ~ +",
+            "a b 7 & { swap 2 * swap 1 - } drop",
+            "a b 7 & times{ 3 * 1 + }",
+            "0 loop{ 1 + p0 b 7 & >= [ p0 break ] }",
+            "0 a 7 & times{ p0 2 & [ 1 + continue ] 3 + } a 3 & { 1 - p0 1 == [ break ] } b drop",
+            "a [ b , a b + ] a ! [ 5 + ]",
+            ": down (1 -- 1) p0 [ 1 - down ] ; a 7 & down b +",
+            ": wide (1 -- 1) p0 [ 0 p1 1 - wide + s0 ] ; a 7 & wide b +",
+            ": sq p0 * ; : inc 1 + ; a sq inc b sq -",
+            "a b weigh b weigh",
+            "a 1 0 / b",
+        ];
+        let base = FunctionBuilder::new().register_extern("weigh", weigh as extern "C" fn(isize, isize) -> isize);
+        let builders = [
+            base.clone(),
+            base.clone().arithmetic(ArithmeticMode::CheckedSigned),
+            base.clone().arithmetic(ArithmeticMode::CheckedUnsigned),
+            base.clone().shift(ShiftMode::Checked),
+            base.clone().max_call_depth(4),
+            base.clone().max_stack_depth(6),
+        ];
+        let bits = isize::BITS as isize;
+        for source in programs {
+            for builder in &builders {
+                let function = builder.parse(source).unwrap();
+                let interpreter = builder.interpret(source).unwrap();
+                assert_eq!(interpreter.arity(), function.arity());
+                for args in [[7, 3], [-7, 3], [0, 5], [5, 0], [isize::MIN, -1], [isize::MAX, 2], [3, bits], [-1, -1]] {
+                    assert_eq!(interpreter.call(&args), function.call(&args), "{} with {:?}", source, args);
+                }
+                assert_eq!(interpreter.call(&[1]), function.call(&[1]));
+            }
+        }
+    }
+
+    #[test]
+    fn results_and_errors() {
+        use super::*;
+        use crate::function::{FunctionCreateErrorKind, MAX_CALL_DEPTH_LIMIT};
+        let builder = FunctionBuilder::new().results(3);
+        let source = "a b p1 p1 /% a";
+        let interpreter = builder.interpret(source).unwrap();
+        assert_eq!(interpreter.call_vec(&[17, 5]), builder.parse(source).unwrap().call_vec(&[17, 5]));
+        assert_eq!(interpreter.call_array(&[17, 5]), Ok([3, 2, 17]));
+        assert_eq!(interpreter.call(&[17, 5]), Err(FunctionError::ResultCountMismatch));
+        assert_eq!(interpreter.call_vec(&[17, 0]), Err(FunctionError::DivideByZero));
+
+        // Programs which cannot be compiled cannot be interpreted either
+        let err = Interpreter::parse("a +").unwrap_err();
+        assert!(matches!(err.kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+        assert_eq!(err.location().unwrap().token, "+");
+        assert!(matches!(builder.interpret("a b").unwrap_err().kind(), FunctionCreateErrorKind::StackUnderflow(_)));
+
+        // Word calls do not use the native stack
+        let deep = FunctionBuilder::new().max_call_depth(MAX_CALL_DEPTH_LIMIT).interpret(": down (1 -- 1) p0 [ 1 - down ] ; a down 1 +").unwrap();
+        assert_eq!(deep.call(&[16000]), Ok(1));
+        assert_eq!(deep.call(&[17000]), Err(FunctionError::StackOverflow));
+        // Nor do the values words leave on the stack, but they are limited like a `Function`'s
+        let wide = FunctionBuilder::new().max_stack_depth(100).interpret(": down (1 -- 1) p0 [ 0 p1 1 - down + s0 ] ; a down 1 +").unwrap();
+        assert_eq!(wide.call(&[49]), Ok(1));
+        assert_eq!(wide.call(&[50]), Err(FunctionError::StackOverflow));
+    }
+}
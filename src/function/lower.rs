@@ -0,0 +1,239 @@
+use super::{FunctionBuilder, FunctionCreateError, FunctionCreateErrorKind, MAX_PARAM_COUNT};
+use crate::code::Symbol;
+use crate::commands::*;
+use crate::ir::{BinaryOp, CheckedOp, DivisionOp, Instruction, Op};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+/// Lowers a `Program`'s instructions into the target architecture's `Command`s
+pub(crate) struct Lowerer<'a> {
+    builder: &'a FunctionBuilder,
+    param_count: usize,
+    /// Labels of the loops enclosing the instruction being lowered, innermost last
+    loops: Vec<LoopLabels>,
+    /// Words defined so far, by name
+    words: HashMap<&'a str, Word>,
+    /// Name of the word whose body is being lowered, if any
+    defining: Option<&'a str>,
+    /// Number of `times{ }` loops enclosing the instruction being lowered, within the word being defined
+    counted_loops: usize,
+    /// Extern functions called so far, whose addresses have been defined
+    used_externs: HashSet<&'a str>,
+}
+
+impl<'a> Lowerer<'a> {
+    pub(crate) fn new(builder: &'a FunctionBuilder, param_count: usize) -> Self {
+        Lowerer {
+            builder,
+            param_count,
+            loops: vec![],
+            words: HashMap::new(),
+            defining: None,
+            counted_loops: 0,
+            used_externs: HashSet::new(),
+        }
+    }
+
+    /// Whether the program defined any words
+    pub(crate) fn has_words(&self) -> bool {
+        !self.words.is_empty()
+    }
+
+    /// Lowers a sequence of instructions, giving each resulting command the instruction's span
+    pub(crate) fn lower_block(&mut self, instructions: &'a [Instruction]) -> Result<Vec<Command>, FunctionCreateError> {
+        let mut commands = Vec::with_capacity(instructions.len());
+        let mut rest = instructions;
+        while let [instruction, after @ ..] = rest {
+            if let Some((command, after)) = self.merge_immediate(rest) {
+                commands.push(command);
+                rest = after;
+                continue;
+            }
+            rest = after;
+            let mut command = self.lower(&instruction.op).map_err(|e| match &instruction.span {
+                Some(span) => e.or_span(span.clone()),
+                None => e,
+            })?;
+            command.span = instruction.span.clone();
+            commands.push(command);
+        }
+        if self.builder.stack_caching {
+            commands = cache_stack(commands);
+        }
+        Ok(commands)
+    }
+
+    /// When optimizing, lowers `value op` at the start of `instructions` as one command
+    /// with `value` as an immediate operand, if the architecture has one; also gives the instructions after it
+    fn merge_immediate(&self, instructions: &'a [Instruction]) -> Option<(Command, &'a [Instruction])> {
+        let [Instruction { op: Op::Value(value), span: first }, Instruction { op: Op::Binary(op), span: last }, rest @ ..] = instructions else {
+            return None;
+        };
+        if !self.builder.optimize {
+            return None;
+        }
+        let mut command = BINARY_IMMEDIATE(*op, *value)?;
+        command.span = match (first, last) {
+            (Some(first), Some(last)) => Some(first.start..last.end),
+            _ => None,
+        };
+        Some((command, rest))
+    }
+
+    fn lower(&mut self, op: &'a Op) -> Result<Command, FunctionCreateError> {
+        let command = match op {
+            &Op::Argument(index) => {
+                if index == 0 || index > MAX_PARAM_COUNT {
+                    return Err(FunctionCreateErrorKind::IntegerOutOfRange.into());
+                }
+                if index > self.param_count {
+                    return Err(FunctionCreateErrorKind::UndeclaredParameter.into());
+                }
+                PUSH_ARGUMENT(index)
+            },
+            &Op::Value(value) => PUSH_VALUE(value),
+            &Op::Pick(index) => PUSH_STACK_INDEX(index),
+            &Op::Store(index) => POP_STACK_INDEX(index),
+            Op::Dup => DUP.clone(),
+            Op::Drop => DROP.clone(),
+            &Op::DropN(count) => {
+                let count: i32 = count.try_into().map_err(|_| FunctionCreateErrorKind::IntegerOutOfRange)?;
+                DROP_N(count)
+            },
+            Op::Swap => SWAP.clone(),
+            Op::Rot => ROT.clone(),
+            Op::Over => OVER.clone(),
+            &Op::Binary(op) => Lowerer::binary(op),
+            &Op::Checked(op) => Lowerer::checked(op),
+            &Op::Division(op) => Lowerer::division(op),
+            Op::Not => NOT.clone(),
+            Op::LogicalNot => LOGICAL_NOT.clone(),
+            Op::While(body) => self.lower_loop(body, WHILE_LOOP)?,
+            Op::Loop(body) => self.lower_loop(body, LOOP)?,
+            Op::Times(body) => {
+                self.counted_loops += 1;
+                let command = self.lower_loop(body, COUNTED_LOOP);
+                self.counted_loops -= 1;
+                command?
+            },
+            Op::Break => BREAK(self.loops.last().ok_or(FunctionCreateErrorKind::BreakOutsideLoop)?),
+            Op::Continue => CONTINUE(self.loops.last().ok_or(FunctionCreateErrorKind::BreakOutsideLoop)?),
+            Op::If(then_body, else_body) => {
+                let then_commands = self.lower_block(then_body)?;
+                let else_commands = self.lower_block(else_body)?;
+                IF_ELSE(then_commands, else_commands)?
+            },
+            Op::DefineWord { name, stack_effect, body } => self.define_word(name, *stack_effect, body)?,
+            Op::CallWord(name) => match self.words.get(name.as_str()) {
+                Some(_) if self.defining == Some(name) && self.counted_loops > 0 => {
+                    return Err(FunctionCreateErrorKind::RecursionInCountedLoop.into());
+                },
+                Some(word) => CALL_WORD(word),
+                None if self.defining == Some(name) => {
+                    return Err(FunctionCreateErrorKind::UndeclaredStackEffect.into());
+                },
+                None => return Err(FunctionCreateErrorKind::UndefinedWord.into()),
+            },
+            Op::CallExtern(name) => {
+                let function = self.builder.externs.get(name).ok_or(FunctionCreateErrorKind::UndefinedWord)?;
+                let symbol = Symbol::new_global(name.clone());
+                let mut command = CALL_EXTERN(symbol.clone(), function.arity);
+                // The address only needs to be defined once per function
+                if self.used_externs.insert(name) {
+                    command.code.abs_symbols.push((symbol, function.address as isize));
+                }
+                command
+            },
+        };
+        Ok(command)
+    }
+
+    /// Lowers the body of a loop, then builds the loop with `make_loop`
+    fn lower_loop(
+        &mut self,
+        body: &'a [Instruction],
+        make_loop: fn(LoopLabels, Vec<Command>) -> Result<Command, FunctionCreateError>,
+    ) -> Result<Command, FunctionCreateError> {
+        self.loops.push(LoopLabels::new());
+        let commands = self.lower_block(body);
+        let labels = self.loops.pop().expect("loop labels were just pushed");
+        make_loop(labels, commands?)
+    }
+
+    fn define_word(&mut self, name: &'a str, stack_effect: Option<(usize, usize)>, body: &'a [Instruction]) -> Result<Command, FunctionCreateError> {
+        if self.words.contains_key(name) || self.builder.externs.contains_key(name) {
+            return Err(FunctionCreateErrorKind::RedefinedWord.into());
+        }
+        let symbol = Symbol::new_global(name.to_owned());
+        if let Some((word_params, word_returns)) = stack_effect {
+            // Recursive calls use the declared stack effect. They are only allowed outside of `times{ }` loops,
+            // so no frame slots are live across them and they do not need to know the word's frame slots.
+            self.words.insert(name, Word {
+                symbol: symbol.clone(),
+                param_count: word_params,
+                return_count: word_returns,
+                frame_slots: 0,
+            });
+        }
+        // `break` and `continue` in the body cannot leave the word
+        let loops = std::mem::take(&mut self.loops);
+        let defining = self.defining.replace(name);
+        let counted_loops = std::mem::take(&mut self.counted_loops);
+        let commands = self.lower_block(body);
+        self.loops = loops;
+        self.defining = defining;
+        self.counted_loops = counted_loops;
+        let (word, command) = DEFINE_WORD(symbol, stack_effect, commands?, self.builder.max_call_depth, self.builder.max_stack_depth)?;
+        self.words.insert(name, word);
+        Ok(command)
+    }
+
+    fn binary(op: BinaryOp) -> Command {
+        match op {
+            BinaryOp::Add => ADD.clone(),
+            BinaryOp::Subtract => SUBTRACT.clone(),
+            BinaryOp::Multiply => MULTIPLY.clone(),
+            BinaryOp::And => AND.clone(),
+            BinaryOp::Or => OR.clone(),
+            BinaryOp::Xor => XOR.clone(),
+            BinaryOp::ShiftLeft => SHIFT_LEFT.clone(),
+            BinaryOp::ShiftRight => SHIFT_RIGHT.clone(),
+            BinaryOp::UShiftRight => USHIFT_RIGHT.clone(),
+            BinaryOp::Less => LESS.clone(),
+            BinaryOp::Greater => GREATER.clone(),
+            BinaryOp::LessEqual => LESS_EQUAL.clone(),
+            BinaryOp::GreaterEqual => GREATER_EQUAL.clone(),
+            BinaryOp::ULess => ULESS.clone(),
+            BinaryOp::UGreater => UGREATER.clone(),
+            BinaryOp::ULessEqual => ULESS_EQUAL.clone(),
+            BinaryOp::UGreaterEqual => UGREATER_EQUAL.clone(),
+            BinaryOp::Equal => EQUAL.clone(),
+            BinaryOp::NotEqual => NOT_EQUAL.clone(),
+        }
+    }
+
+    fn checked(op: CheckedOp) -> Command {
+        match op {
+            CheckedOp::Add => CHECKED_ADD.clone(),
+            CheckedOp::UAdd => CHECKED_UADD.clone(),
+            CheckedOp::Subtract => CHECKED_SUBTRACT.clone(),
+            CheckedOp::USubtract => CHECKED_USUBTRACT.clone(),
+            CheckedOp::Multiply => CHECKED_MULTIPLY.clone(),
+            CheckedOp::UMultiply => CHECKED_UMULTIPLY.clone(),
+            CheckedOp::ShiftLeft => CHECKED_SHIFT_LEFT.clone(),
+            CheckedOp::ShiftRight => CHECKED_SHIFT_RIGHT.clone(),
+            CheckedOp::UShiftRight => CHECKED_USHIFT_RIGHT.clone(),
+        }
+    }
+
+    fn division(op: DivisionOp) -> Command {
+        match op {
+            DivisionOp::Divide => DIVIDE.clone(),
+            DivisionOp::Mod => MOD.clone(),
+            DivisionOp::DivMod => DIVMOD.clone(),
+            DivisionOp::UDivide => UDIVIDE.clone(),
+            DivisionOp::UMod => UMOD.clone(),
+            DivisionOp::UDivMod => UDIVMOD.clone(),
+        }
+    }
+}
@@ -0,0 +1,506 @@
+use super::{ArithmeticMode, FunctionBuilder, ShiftMode, FunctionCreateError, FunctionCreateErrorKind, MAX_PARAM_COUNT};
+use crate::ir::{BinaryOp, CheckedOp, DivisionOp, Instruction, Op, Program};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::ops::Range;
+
+/// Parses program text into a `Program`, remembering where in the text each instruction came from
+pub(crate) struct Parser<'a> {
+    builder: &'a FunctionBuilder,
+    source: &'a str,
+    declared_param_count: Option<usize>,
+    /// Names of the words defined so far
+    words: HashSet<&'a str>,
+    /// Name of the word whose body is being parsed, if any
+    defining: Option<&'a str>,
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn new(builder: &'a FunctionBuilder, source: &'a str) -> Self {
+        Parser {
+            builder,
+            source,
+            declared_param_count: None,
+            words: HashSet::new(),
+            defining: None,
+        }
+    }
+
+    /// Parses the whole program
+    pub(crate) fn parse(&mut self) -> Result<Program, FunctionCreateError> {
+        if !self.builder.externs.keys().all(|name| Parser::is_valid_word_name(name)) {
+            return Err(FunctionCreateErrorKind::InvalidWordName.into());
+        }
+        let mut s = self.source;
+        self.declared_param_count = self.parse_declared_arity(&mut s)?;
+        let (param_count, body) = self.parse_helper(&mut s)?;
+        s = s.trim_start();
+        if s.len() != 0 {
+            return Err(self.error_at(FunctionCreateErrorKind::UnrecognizedCommand, s));
+        }
+        Ok(Program {
+            param_count: self.declared_param_count.unwrap_or(param_count),
+            body,
+        })
+    }
+
+    /// Byte offset of the remaining text `s` in the source
+    fn offset(&self, s: &str) -> usize {
+        self.source.len() - s.len()
+    }
+
+    /// Span of the whitespace-delimited token at the start of the remaining text `s`
+    fn token_span(&self, s: &str) -> Range<usize> {
+        let start = self.offset(s);
+        let len = s.find(char::is_whitespace).unwrap_or(s.len());
+        start..start + len
+    }
+
+    fn error_at(&self, kind: FunctionCreateErrorKind, s: &str) -> FunctionCreateError {
+        FunctionCreateError::from(kind).or_span(self.token_span(s))
+    }
+
+    /// Parses an optional `(N)` prefix declaring that the function takes N parameters
+    fn parse_declared_arity(&self, s: &mut &'a str) -> Result<Option<usize>, FunctionCreateError> {
+        *s = s.trim_start();
+        let start = *s;
+        let rest = match s.strip_prefix('(') {
+            None => return Ok(None),
+            Some(rest) => rest.trim_start(),
+        };
+        *s = rest;
+        let param_count = Parser::parse_uint(s).map_err(|e| e.or_span(self.token_span(rest)))?;
+        if param_count > MAX_PARAM_COUNT {
+            return Err(self.error_at(FunctionCreateErrorKind::IntegerOutOfRange, rest));
+        }
+        *s = s.trim_start();
+        *s = s.strip_prefix(')').ok_or_else(|| self.error_at(FunctionCreateErrorKind::UnrecognizedCommand, start))?;
+        Ok(Some(param_count))
+    }
+
+    fn parse_helper(&mut self, s: &mut &'a str) -> Result<(usize, Vec<Instruction>), FunctionCreateError> {
+        let mut param_count = 0;
+        let mut instructions: Vec<Instruction> = vec![];
+        while {*s = s.trim_start(); s.len() > 0} {
+            if s.starts_with(['}', ']', ',', ';']) {
+                break; // Caller should check that the &str is empty
+            }
+            let start = *s;
+            let op = self.parse_command(s, &mut param_count)
+                .map_err(|e| e.or_span(self.token_span(start)))?;
+            instructions.push(Instruction { op, span: Some(self.offset(start)..self.offset(s)) });
+        }
+        Ok((param_count, instructions))
+    }
+
+    /// Parses one command, trimming it from `s`
+    fn parse_command(&mut self, s: &mut &'a str, param_count: &mut usize) -> Result<Op, FunctionCreateError> {
+        if let Some(op) = self.parse_word(s, param_count)? {
+            return Ok(op);
+        }
+        let next = s.chars().next().ok_or(FunctionCreateErrorKind::UnrecognizedCommand)?;
+        let op = match next {
+            'a' => {
+                *s = s.split_at(1).1;
+                self.push_param(1, param_count)?
+            },
+            'b' => {
+                *s = s.split_at(1).1;
+                self.push_param(2, param_count)?
+            },
+            'c' => {
+                *s = s.split_at(1).1;
+                self.push_param(3, param_count)?
+            },
+            'd' => {
+                *s = s.split_at(1).1;
+                self.push_param(4, param_count)?
+            },
+            'e' => {
+                *s = s.split_at(1).1;
+                self.push_param(5, param_count)?
+            },
+            'f' => {
+                *s = s.split_at(1).1;
+                self.push_param(6, param_count)?
+            },
+            '+' => {
+                *s = s.split_at(1).1;
+                match s.strip_prefix('?') {
+                    Some(rest) => {
+                        *s = rest;
+                        Op::Checked(CheckedOp::Add)
+                    },
+                    None => self.add_command(),
+                }
+            },
+            '-' => {
+                *s = s.split_at(1).1;
+                match s.strip_prefix('?') {
+                    Some(rest) => {
+                        *s = rest;
+                        Op::Checked(CheckedOp::Subtract)
+                    },
+                    None => self.subtract_command(),
+                }
+            },
+            '*' => {
+                *s = s.split_at(1).1;
+                match s.strip_prefix('?') {
+                    Some(rest) => {
+                        *s = rest;
+                        Op::Checked(CheckedOp::Multiply)
+                    },
+                    None => self.multiply_command(),
+                }
+            },
+            '/' => {
+                *s = s.split_at(1).1;
+                match s.strip_prefix('%') {
+                    Some(rest) => {
+                        *s = rest;
+                        Op::Division(DivisionOp::DivMod)
+                    },
+                    None => Op::Division(DivisionOp::Divide),
+                }
+            },
+            '%' => {
+                *s = s.split_at(1).1;
+                Op::Division(DivisionOp::Mod)
+            },
+            '\\' => {
+                *s = s.split_at(1).1;
+                match s.strip_prefix('@') {
+                    Some(rest) => {
+                        *s = rest;
+                        Op::Division(DivisionOp::UDivMod)
+                    },
+                    None => Op::Division(DivisionOp::UDivide),
+                }
+            },
+            '@' => {
+                *s = s.split_at(1).1;
+                Op::Division(DivisionOp::UMod)
+            },
+            'u' => {
+                let rest = s.split_at(1).1;
+                let (op, rest) = if let Some(rest) = rest.strip_prefix("+?") {
+                    (Op::Checked(CheckedOp::UAdd), rest)
+                } else if let Some(rest) = rest.strip_prefix("-?") {
+                    (Op::Checked(CheckedOp::USubtract), rest)
+                } else if let Some(rest) = rest.strip_prefix("*?") {
+                    (Op::Checked(CheckedOp::UMultiply), rest)
+                } else if let Some(rest) = rest.strip_prefix(">>") {
+                    (self.shift_command(BinaryOp::UShiftRight, CheckedOp::UShiftRight), rest)
+                } else if let Some(rest) = rest.strip_prefix("<=") {
+                    (Op::Binary(BinaryOp::ULessEqual), rest)
+                } else if let Some(rest) = rest.strip_prefix(">=") {
+                    (Op::Binary(BinaryOp::UGreaterEqual), rest)
+                } else if let Some(rest) = rest.strip_prefix('<') {
+                    (Op::Binary(BinaryOp::ULess), rest)
+                } else if let Some(rest) = rest.strip_prefix('>') {
+                    (Op::Binary(BinaryOp::UGreater), rest)
+                } else {
+                    return Err(FunctionCreateErrorKind::UnrecognizedCommand.into());
+                };
+                *s = rest;
+                op
+            },
+            '<' => {
+                *s = s.split_at(1).1;
+                if let Some(rest) = s.strip_prefix('<') {
+                    *s = rest;
+                    self.shift_command(BinaryOp::ShiftLeft, CheckedOp::ShiftLeft)
+                } else if let Some(rest) = s.strip_prefix('=') {
+                    *s = rest;
+                    Op::Binary(BinaryOp::LessEqual)
+                } else {
+                    Op::Binary(BinaryOp::Less)
+                }
+            },
+            '>' => {
+                *s = s.split_at(1).1;
+                if let Some(rest) = s.strip_prefix('>') {
+                    *s = rest;
+                    self.shift_command(BinaryOp::ShiftRight, CheckedOp::ShiftRight)
+                } else if let Some(rest) = s.strip_prefix('=') {
+                    *s = rest;
+                    Op::Binary(BinaryOp::GreaterEqual)
+                } else {
+                    Op::Binary(BinaryOp::Greater)
+                }
+            },
+            '&' => {
+                *s = s.split_at(1).1;
+                Op::Binary(BinaryOp::And)
+            },
+            '|' => {
+                *s = s.split_at(1).1;
+                Op::Binary(BinaryOp::Or)
+            },
+            '^' => {
+                *s = s.split_at(1).1;
+                Op::Binary(BinaryOp::Xor)
+            },
+            '~' => {
+                *s = s.split_at(1).1;
+                Op::Not
+            },
+            '=' => {
+                *s = s.strip_prefix("==").ok_or(FunctionCreateErrorKind::UnrecognizedCommand)?;
+                Op::Binary(BinaryOp::Equal)
+            },
+            '!' => {
+                *s = s.split_at(1).1;
+                match s.strip_prefix('=') {
+                    Some(rest) => {
+                        *s = rest;
+                        Op::Binary(BinaryOp::NotEqual)
+                    },
+                    None => Op::LogicalNot,
+                }
+            },
+            '$' => {
+                *s = s.split_at(1).1;
+                let index = Parser::parse_uint(s)?;
+                if !(1..=MAX_PARAM_COUNT).contains(&index) {
+                    return Err(FunctionCreateErrorKind::IntegerOutOfRange.into());
+                }
+                self.push_param(index, param_count)?
+            },
+            '0'..='9' => {
+                let value: isize =
+                    Parser::parse_uint(s)?
+                    .try_into()
+                    .map_err(|_| FunctionCreateErrorKind::IntegerTooLarge)?;
+                Op::Value(value)
+            },
+            'l'|'p' => {
+                *s = s.split_at(1).1;
+                let index: i32 =
+                    Parser::parse_iint(s)?
+                    .try_into()
+                    .map_err(|_| FunctionCreateErrorKind::StackUnderflow("Stack index out of range"))?;
+                Op::Pick(index)
+            },
+            's' => {
+                *s = s.split_at(1).1;
+                let index: i32 =
+                    Parser::parse_iint(s)?
+                    .try_into()
+                    .map_err(|_| FunctionCreateErrorKind::StackUnderflow("Stack index out of range"))?;
+                Op::Store(index)
+            },
+            '{' => {
+                let start = *s;
+                *s = s.split_at(1).1;
+                self.parse_loop(s, start, param_count, Op::While)?
+            },
+            ':' => {
+                let start = *s;
+                *s = s.split_at(1).1.trim_start();
+                return self.parse_definition(s, start, param_count);
+            },
+            '[' => {
+                let start = *s;
+                *s = s.split_at(1).1;
+                let (then_param_count, then_body) = self.parse_helper(s)?;
+                let (else_param_count, else_body) = match s.strip_prefix(',') {
+                    Some(rest) => {
+                        *s = rest;
+                        self.parse_helper(s)?
+                    },
+                    None => (0, vec![]),
+                };
+                *s = s.strip_prefix(']').ok_or_else(|| self.error_at(FunctionCreateErrorKind::UnterminatedIf, start))?;
+                *param_count = (*param_count).max(then_param_count).max(else_param_count);
+                Op::If(then_body, else_body)
+            },
+            _ => return Err(FunctionCreateErrorKind::UnrecognizedCommand.into()),
+        };
+        Ok(op)
+    }
+
+    /// Parses the body of a loop starting at `start` up to its closing `}`, then builds the loop with `make_loop`
+    fn parse_loop(
+        &mut self,
+        s: &mut &'a str,
+        start: &'a str,
+        param_count: &mut usize,
+        make_loop: fn(Vec<Instruction>) -> Op,
+    ) -> Result<Op, FunctionCreateError> {
+        let (loop_param_count, body) = self.parse_helper(s)?;
+        *s = s.strip_prefix('}').ok_or_else(|| self.error_at(FunctionCreateErrorKind::UnterminatedLoop, start))?;
+        *param_count = (*param_count).max(loop_param_count);
+        Ok(make_loop(body))
+    }
+
+    /// Parses a word definition starting at `start` from its name up to its closing `;`
+    fn parse_definition(&mut self, s: &mut &'a str, start: &'a str, param_count: &mut usize) -> Result<Op, FunctionCreateError> {
+        let (name, rest) = Parser::split_word(s);
+        if !Parser::is_valid_word_name(name) {
+            return Err(self.error_at(FunctionCreateErrorKind::InvalidWordName, s));
+        }
+        if self.words.contains(name) || self.builder.externs.contains_key(name) {
+            return Err(self.error_at(FunctionCreateErrorKind::RedefinedWord, s));
+        }
+        *s = rest;
+        let stack_effect = self.parse_stack_effect(s)?;
+        let defining = self.defining.replace(name);
+        let body = self.parse_helper(s);
+        self.defining = defining;
+        let (word_param_count, body) = body?;
+        *s = s.strip_prefix(';').ok_or_else(|| self.error_at(FunctionCreateErrorKind::UnterminatedDefinition, start))?;
+        *param_count = (*param_count).max(word_param_count);
+        self.words.insert(name);
+        Ok(Op::DefineWord { name: name.to_owned(), stack_effect, body })
+    }
+
+    /// Parses an optional `(P -- R)` declaring that a word takes P values from the stack and leaves R
+    fn parse_stack_effect(&self, s: &mut &'a str) -> Result<Option<(usize, usize)>, FunctionCreateError> {
+        *s = s.trim_start();
+        let start = *s;
+        let mut rest = match s.strip_prefix('(') {
+            None => return Ok(None),
+            Some(rest) => rest.trim_start(),
+        };
+        let token = rest;
+        let param_count = Parser::parse_uint(&mut rest).map_err(|e| e.or_span(self.token_span(token)))?;
+        rest = rest.trim_start().strip_prefix("--").ok_or_else(|| self.error_at(FunctionCreateErrorKind::UnrecognizedCommand, start))?.trim_start();
+        let token = rest;
+        let return_count = Parser::parse_uint(&mut rest).map_err(|e| e.or_span(self.token_span(token)))?;
+        *s = rest.trim_start().strip_prefix(')').ok_or_else(|| self.error_at(FunctionCreateErrorKind::UnrecognizedCommand, start))?;
+        Ok(Some((param_count, return_count)))
+    }
+
+    /// Splits the maximal run of ASCII alphanumerics and underscores from the start of `s`
+    fn split_word(s: &'a str) -> (&'a str, &'a str) {
+        let len = s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(s.len());
+        s.split_at(len)
+    }
+
+    /// Whether `name` can be used for a word: it must be one token of at least two characters,
+    /// must not start with a digit, and must not be a built-in command
+    fn is_valid_word_name(name: &str) -> bool {
+        name.len() >= 2
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && !Parser::is_builtin_word(name)
+    }
+
+    /// Whether `word` is parsed as a built-in command by `parse_word`
+    fn is_builtin_word(word: &str) -> bool {
+        matches!(word, "loop" | "times" | "break" | "continue" | "dup" | "drop" | "swap" | "rot" | "over")
+            || word.strip_prefix("drop").is_some_and(|count| count.starts_with(|c: char| c.is_ascii_digit()))
+    }
+
+    /// Parses a named command such as `dup`, trimming it from `s`.
+    ///
+    /// Returns `None` if `s` does not start with one or with a user-defined word,
+    /// so that e.g. `d2` is still parsed as `d` followed by `2`.
+    fn parse_word(&mut self, s: &mut &'a str, param_count: &mut usize) -> Result<Option<Op>, FunctionCreateError> {
+        let (word, rest) = Parser::split_word(s);
+        let op = match word {
+            "loop" | "times" => {
+                let start = *s;
+                *s = rest.trim_start().strip_prefix('{').ok_or(FunctionCreateErrorKind::UnrecognizedCommand)?;
+                let make_loop = if word == "loop" { Op::Loop } else { Op::Times };
+                return self.parse_loop(s, start, param_count, make_loop).map(Some);
+            },
+            "break" => Op::Break,
+            "continue" => Op::Continue,
+            "dup" => Op::Dup,
+            "drop" => Op::Drop,
+            "swap" => Op::Swap,
+            "rot" => Op::Rot,
+            "over" => Op::Over,
+            _ => match word.strip_prefix("drop").filter(|count| count.starts_with(|c: char| c.is_ascii_digit())) {
+                Some(mut digits) => {
+                    let count = Parser::parse_uint(&mut digits)?;
+                    if !digits.is_empty() {
+                        return Err(FunctionCreateErrorKind::InvalidInteger.into());
+                    }
+                    Op::DropN(count)
+                },
+                // Whether the word can be called here is checked when it is compiled
+                None if self.words.contains(word) || self.defining == Some(word) => Op::CallWord(word.to_owned()),
+                None if self.builder.externs.contains_key(word) => Op::CallExtern(word.to_owned()),
+                None => return Ok(None),
+            },
+        };
+        *s = rest;
+        Ok(Some(op))
+    }
+
+    /// A push of the `index`th (1-based) parameter
+    fn push_param(&self, index: usize, param_count: &mut usize) -> Result<Op, FunctionCreateError> {
+        if self.declared_param_count.is_some_and(|declared| index > declared) {
+            return Err(FunctionCreateErrorKind::UndeclaredParameter.into());
+        }
+        *param_count = (*param_count).max(index);
+        Ok(Op::Argument(index))
+    }
+
+    fn add_command(&self) -> Op {
+        self.arithmetic_command(BinaryOp::Add, CheckedOp::Add, CheckedOp::UAdd)
+    }
+    fn subtract_command(&self) -> Op {
+        self.arithmetic_command(BinaryOp::Subtract, CheckedOp::Subtract, CheckedOp::USubtract)
+    }
+    fn multiply_command(&self) -> Op {
+        self.arithmetic_command(BinaryOp::Multiply, CheckedOp::Multiply, CheckedOp::UMultiply)
+    }
+
+    fn arithmetic_command(&self, wrapping: BinaryOp, signed: CheckedOp, unsigned: CheckedOp) -> Op {
+        match self.builder.arithmetic {
+            ArithmeticMode::Wrapping => Op::Binary(wrapping),
+            ArithmeticMode::CheckedSigned => Op::Checked(signed),
+            ArithmeticMode::CheckedUnsigned => Op::Checked(unsigned),
+        }
+    }
+
+    fn shift_command(&self, masked: BinaryOp, checked: CheckedOp) -> Op {
+        match self.builder.shift {
+            ShiftMode::Masked => Op::Binary(masked),
+            ShiftMode::Checked => Op::Checked(checked),
+        }
+    }
+
+    fn parse_uint(s: &mut &str) -> Result<usize, FunctionCreateError> {
+        let mut value: usize;
+        static DIGITS: &[char] = &['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
+        *s = match s.strip_prefix(DIGITS) {
+            None => return Err(FunctionCreateErrorKind::InvalidInteger.into()),
+            Some(rest) => {
+                value = s.chars().next().unwrap().to_digit(10).unwrap() as usize;
+                rest
+            },
+        };
+        while let Some(rest) = s.strip_prefix(DIGITS) {
+            let digit = s.chars().next().unwrap().to_digit(10).unwrap() as usize;
+            value = value
+                .checked_mul(10)
+                .ok_or(FunctionCreateErrorKind::IntegerTooLarge)?
+                .checked_add(digit)
+                .ok_or(FunctionCreateErrorKind::IntegerTooLarge)?;
+            *s = rest;
+        }
+        Ok(value)
+    }
+    fn parse_iint(s: &mut &str) -> Result<isize, FunctionCreateError> {
+        let negative: bool = match s.strip_prefix('-') {
+            None => false,
+            Some(rest) => { *s = rest; true }
+        };
+        let magnitude: usize = Parser::parse_uint(s)?;
+        if !negative {
+            magnitude.try_into().map_err(|_| FunctionCreateErrorKind::IntegerOutOfRange.into())
+        } else if magnitude <= isize::MAX as usize {
+            Ok(-(magnitude as isize))
+        } else if magnitude == isize::MIN as usize {
+            Ok(magnitude as isize)
+        } else {
+            Err(FunctionCreateErrorKind::IntegerOutOfRange.into())
+        }
+    }
+}
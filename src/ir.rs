@@ -0,0 +1,134 @@
+//! An architecture-neutral representation of programs, between parsing and code generation.
+//!
+//! `FunctionBuilder::parse_program` turns program text into a `Program`, and `FunctionBuilder::build`
+//! (or `Function::new`) compiles a `Program`, which need not have come from text.
+
+mod fold;
+
+pub(crate) use fold::{evaluate, fold_constants};
+use std::ops::Range;
+
+/// A whole program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// The number of parameters the function takes; `Op::Argument` can only push these
+    pub param_count: usize,
+    pub body: Vec<Instruction>,
+}
+
+/// An operation, and where in the program text it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub op: Op,
+    /// Byte range of the program text, for error reporting
+    pub span: Option<Range<usize>>,
+}
+
+impl From<Op> for Instruction {
+    fn from(op: Op) -> Self {
+        Instruction { op, span: None }
+    }
+}
+
+/// One command. Unlike the program text, the behaviour of every operation is explicit:
+/// e.g. `+` is `Binary(BinaryOp::Add)` or `Checked(CheckedOp::Add)` depending on the builder's `ArithmeticMode`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Pushes the `index`th (1-based) argument
+    Argument(usize),
+    /// Pushes a value
+    Value(isize),
+    /// `p<index>`: pushes a copy of a value, counting from the top if `index` is not negative
+    Pick(i32),
+    /// `s<index>`: pops a value and stores it over another, counting from the top if `index` is not negative
+    Store(i32),
+    Dup,
+    Drop,
+    /// Pops and discards this many values
+    DropN(usize),
+    Swap,
+    Rot,
+    Over,
+    /// Pops two values and pushes the result of an operation which cannot fail
+    Binary(BinaryOp),
+    /// Pops two values and pushes the result of an operation which fails on overflow
+    Checked(CheckedOp),
+    /// Pops two values and pushes their quotient and/or remainder, failing on division by zero or overflow
+    Division(DivisionOp),
+    /// Bitwise NOT
+    Not,
+    /// Pushes 1 if the popped value is zero, otherwise 0
+    LogicalNot,
+    /// `{ body }`
+    While(Vec<Instruction>),
+    /// `loop{ body }`
+    Loop(Vec<Instruction>),
+    /// `times{ body }`
+    Times(Vec<Instruction>),
+    Break,
+    Continue,
+    /// `[ then , else ]`
+    If(Vec<Instruction>, Vec<Instruction>),
+    /// `: name (P -- R) body ;`
+    DefineWord {
+        name: String,
+        /// How many values the word takes and leaves, if declared
+        stack_effect: Option<(usize, usize)>,
+        body: Vec<Instruction>,
+    },
+    /// Calls a word defined earlier, or the word being defined
+    CallWord(String),
+    /// Calls an extern function registered with `FunctionBuilder::register_extern`
+    CallExtern(String),
+}
+
+/// Two-operand operations with wrapping arithmetic and masked shift amounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    And,
+    Or,
+    Xor,
+    ShiftLeft,
+    /// Arithmetic (sign-extending) shift
+    ShiftRight,
+    /// Logical (zero-filling) shift
+    UShiftRight,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    ULess,
+    UGreater,
+    ULessEqual,
+    UGreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+/// Two-operand operations which fail on overflow, or on a shift amount outside of the word width
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckedOp {
+    Add,
+    UAdd,
+    Subtract,
+    USubtract,
+    Multiply,
+    UMultiply,
+    ShiftLeft,
+    ShiftRight,
+    UShiftRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DivisionOp {
+    Divide,
+    Mod,
+    /// Pushes the quotient and then the remainder
+    DivMod,
+    UDivide,
+    UMod,
+    UDivMod,
+}
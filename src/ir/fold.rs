@@ -0,0 +1,241 @@
+use super::{BinaryOp, CheckedOp, DivisionOp, Instruction, Op};
+use crate::function::{FunctionCreateError, FunctionCreateErrorKind, FunctionError};
+use std::ops::Range;
+
+/// Folds operations on constants, removes operations that leave their operand unchanged,
+/// and turns multiplication and division by powers of two into shifts, in `body` and every block nested in it.
+///
+/// Constant operations that would fail are left as they are, so that they fail when the function is called,
+/// or rejected with a `ConstantOperationFails` error if `reject_errors` is set.
+pub(crate) fn fold_constants(body: &[Instruction], reject_errors: bool) -> Result<Vec<Instruction>, FunctionCreateError> {
+    let mut folded: Vec<Instruction> = Vec::with_capacity(body.len());
+    for instruction in body {
+        let op = match &instruction.op {
+            Op::While(body) => Op::While(fold_constants(body, reject_errors)?),
+            Op::Loop(body) => Op::Loop(fold_constants(body, reject_errors)?),
+            Op::Times(body) => Op::Times(fold_constants(body, reject_errors)?),
+            Op::If(then_body, else_body) => Op::If(
+                fold_constants(then_body, reject_errors)?,
+                fold_constants(else_body, reject_errors)?,
+            ),
+            Op::DefineWord { name, stack_effect, body } => Op::DefineWord {
+                name: name.clone(),
+                stack_effect: *stack_effect,
+                body: fold_constants(body, reject_errors)?,
+            },
+            op => op.clone(),
+        };
+        folded.push(Instruction { op, span: instruction.span.clone() });
+        while simplify_end(&mut folded, reject_errors)? {}
+    }
+    Ok(folded)
+}
+
+/// Rewrites the last few instructions of `instructions` if they can be simplified, returning whether it did
+fn simplify_end(instructions: &mut Vec<Instruction>, reject_errors: bool) -> Result<bool, FunctionCreateError> {
+    let len = instructions.len();
+    let replacement = match &instructions[len.saturating_sub(3)..] {
+        [Instruction { op: Op::Value(lhs), span: first }, Instruction { op: Op::Value(rhs), .. }, Instruction { op, span: last }] => {
+            let span = join(first, last);
+            match evaluate(op, *lhs, *rhs) {
+                None => None,
+                Some(Ok(values)) => Some((3, values.into_iter().map(Op::Value).collect(), span)),
+                Some(Err(error)) if reject_errors => {
+                    return Err(FunctionCreateError::new(FunctionCreateErrorKind::ConstantOperationFails(error), span));
+                },
+                Some(Err(_)) => None,
+            }
+        },
+        _ => None,
+    };
+    let replacement = replacement.or_else(|| match &instructions[len.saturating_sub(2)..] {
+        [Instruction { op: Op::Value(value), span: first }, Instruction { op, span: last }] => {
+            let ops = simplify_constant_operand(op, *value, len >= 3 && leaves_value(&instructions[len - 3].op))?;
+            Some((2, ops, join(first, last)))
+        },
+        _ => None,
+    });
+    let Some((count, ops, span)) = replacement else {
+        return Ok(false);
+    };
+    instructions.truncate(len - count);
+    instructions.extend(ops.into_iter().map(|op| Instruction { op, span: span.clone() }));
+    Ok(true)
+}
+
+/// The span from the start of `first` to the end of `last`
+fn join(first: &Option<Range<usize>>, last: &Option<Range<usize>>) -> Option<Range<usize>> {
+    match (first, last) {
+        (Some(first), Some(last)) => Some(first.start..last.end),
+        (first, last) => first.clone().or_else(|| last.clone()),
+    }
+}
+
+/// Whether `op` always leaves at least one value on the stack that it pushed or checked was there
+fn leaves_value(op: &Op) -> bool {
+    matches!(
+        op,
+        Op::Argument(_) | Op::Value(_) | Op::Pick(_) | Op::Dup | Op::Swap | Op::Rot | Op::Over
+            | Op::Binary(_) | Op::Checked(_) | Op::Division(_) | Op::Not | Op::LogicalNot | Op::While(_) | Op::CallExtern(_)
+    )
+}
+
+/// What to replace `value op` with, if it can be simplified without knowing the value below it.
+///
+/// `has_operand` is whether the value below is known to be on the stack, so that removing `value op`
+/// cannot hide a stack underflow.
+fn simplify_constant_operand(op: &Op, value: isize, has_operand: bool) -> Option<Vec<Op>> {
+    let power = (value as usize).is_power_of_two().then(|| (value as usize).trailing_zeros() as isize);
+    let identity = match op {
+        Op::Binary(BinaryOp::Add | BinaryOp::Subtract | BinaryOp::Or | BinaryOp::Xor)
+            | Op::Binary(BinaryOp::ShiftLeft | BinaryOp::ShiftRight | BinaryOp::UShiftRight)
+            | Op::Checked(CheckedOp::Add | CheckedOp::UAdd | CheckedOp::Subtract | CheckedOp::USubtract)
+            | Op::Checked(CheckedOp::ShiftLeft | CheckedOp::ShiftRight | CheckedOp::UShiftRight) => value == 0,
+        Op::Binary(BinaryOp::Multiply)
+            | Op::Checked(CheckedOp::Multiply | CheckedOp::UMultiply)
+            | Op::Division(DivisionOp::Divide | DivisionOp::UDivide) => value == 1,
+        Op::Binary(BinaryOp::And) => value == -1,
+        _ => false,
+    };
+    if identity {
+        return has_operand.then(Vec::new);
+    }
+    match (op, power) {
+        (Op::Not, _) => Some(vec![Op::Value(!value)]),
+        (Op::LogicalNot, _) => Some(vec![Op::Value((value == 0) as isize)]),
+        (Op::Dup, _) => Some(vec![Op::Value(value), Op::Value(value)]),
+        (Op::Drop, _) => Some(vec![]),
+        (Op::Binary(BinaryOp::Multiply), Some(shift)) => Some(vec![Op::Value(shift), Op::Binary(BinaryOp::ShiftLeft)]),
+        (Op::Division(DivisionOp::UDivide), Some(shift)) => Some(vec![Op::Value(shift), Op::Binary(BinaryOp::UShiftRight)]),
+        (Op::Division(DivisionOp::UMod), Some(_)) => Some(vec![Op::Value(value.wrapping_sub(1)), Op::Binary(BinaryOp::And)]),
+        (Op::Division(DivisionOp::Divide), Some(shift)) if value > 1 => {
+            // An arithmetic shift rounds towards negative infinity, so negative dividends are first
+            // biased by `value - 1` to round towards zero like division does
+            let bits = isize::BITS as isize;
+            Some(vec![
+                Op::Dup,
+                Op::Value(bits - 1),
+                Op::Binary(BinaryOp::ShiftRight),
+                Op::Value(bits - shift),
+                Op::Binary(BinaryOp::UShiftRight),
+                Op::Binary(BinaryOp::Add),
+                Op::Value(shift),
+                Op::Binary(BinaryOp::ShiftRight),
+            ])
+        },
+        _ => None,
+    }
+}
+
+/// The values `lhs rhs op` leaves on the stack or the error it fails with, if `op` takes two operands
+pub(crate) fn evaluate(op: &Op, lhs: isize, rhs: isize) -> Option<Result<Vec<isize>, FunctionError>> {
+    use FunctionError::*;
+    let (ulhs, urhs) = (lhs as usize, rhs as usize);
+    let checked_shift_amount = || u32::try_from(rhs).ok().filter(|&amount| amount < isize::BITS).ok_or(ShiftAmountOutOfRange);
+    let value = match op {
+        Op::Binary(op) => Ok(match op {
+            BinaryOp::Add => lhs.wrapping_add(rhs),
+            BinaryOp::Subtract => lhs.wrapping_sub(rhs),
+            BinaryOp::Multiply => lhs.wrapping_mul(rhs),
+            BinaryOp::And => lhs & rhs,
+            BinaryOp::Or => lhs | rhs,
+            BinaryOp::Xor => lhs ^ rhs,
+            // Only the low bits of the shift amount are used
+            BinaryOp::ShiftLeft => lhs.wrapping_shl(rhs as u32),
+            BinaryOp::ShiftRight => lhs.wrapping_shr(rhs as u32),
+            BinaryOp::UShiftRight => ulhs.wrapping_shr(rhs as u32) as isize,
+            BinaryOp::Less => (lhs < rhs) as isize,
+            BinaryOp::Greater => (lhs > rhs) as isize,
+            BinaryOp::LessEqual => (lhs <= rhs) as isize,
+            BinaryOp::GreaterEqual => (lhs >= rhs) as isize,
+            BinaryOp::ULess => (ulhs < urhs) as isize,
+            BinaryOp::UGreater => (ulhs > urhs) as isize,
+            BinaryOp::ULessEqual => (ulhs <= urhs) as isize,
+            BinaryOp::UGreaterEqual => (ulhs >= urhs) as isize,
+            BinaryOp::Equal => (lhs == rhs) as isize,
+            BinaryOp::NotEqual => (lhs != rhs) as isize,
+        }),
+        Op::Checked(op) => match op {
+            CheckedOp::Add => lhs.checked_add(rhs).ok_or(SignedAdditionOverflow),
+            CheckedOp::UAdd => ulhs.checked_add(urhs).map(|value| value as isize).ok_or(UnsignedAdditionOverflow),
+            CheckedOp::Subtract => lhs.checked_sub(rhs).ok_or(SignedSubtractionOverflow),
+            CheckedOp::USubtract => ulhs.checked_sub(urhs).map(|value| value as isize).ok_or(UnsignedSubtractionOverflow),
+            CheckedOp::Multiply => lhs.checked_mul(rhs).ok_or(SignedMultiplicationOverflow),
+            CheckedOp::UMultiply => ulhs.checked_mul(urhs).map(|value| value as isize).ok_or(UnsignedMultiplicationOverflow),
+            CheckedOp::ShiftLeft => checked_shift_amount().map(|amount| lhs << amount),
+            CheckedOp::ShiftRight => checked_shift_amount().map(|amount| lhs >> amount),
+            CheckedOp::UShiftRight => checked_shift_amount().map(|amount| (ulhs >> amount) as isize),
+        },
+        Op::Division(op) => {
+            let signed = matches!(op, DivisionOp::Divide | DivisionOp::Mod | DivisionOp::DivMod);
+            if rhs == 0 {
+                return Some(Err(DivideByZero));
+            }
+            if signed && lhs == isize::MIN && rhs == -1 {
+                return Some(Err(DivideMinByNegativeOne));
+            }
+            return Some(Ok(match op {
+                DivisionOp::Divide => vec![lhs / rhs],
+                DivisionOp::Mod => vec![lhs % rhs],
+                DivisionOp::DivMod => vec![lhs / rhs, lhs % rhs],
+                DivisionOp::UDivide => vec![(ulhs / urhs) as isize],
+                DivisionOp::UMod => vec![(ulhs % urhs) as isize],
+                DivisionOp::UDivMod => vec![(ulhs / urhs) as isize, (ulhs % urhs) as isize],
+            }));
+        },
+        Op::Swap => return Some(Ok(vec![rhs, lhs])),
+        _ => return None,
+    };
+    Some(value.map(|value| vec![value]))
+}
+
+#[cfg(test)]
+mod tests {
+    /// The ops of `source` after folding, without their spans
+    fn folded(source: &str) -> Vec<super::Op> {
+        use super::*;
+        let program = crate::function::FunctionBuilder::new().parse_program(source).unwrap();
+        fold_constants(&program.body, false).unwrap().into_iter().map(|instruction| instruction.op).collect()
+    }
+
+    #[test]
+    fn constants() {
+        use super::*;
+        assert_eq!(folded("3 4 * a +"), folded("12 a +"));
+        assert_eq!(folded("a 1 2 3 + * ! 7 swap -"), folded("a 7"));
+        assert_eq!(folded("7 2 /% 0 1 - 1 u>> ~"), [Op::Value(3), Op::Value(1), Op::Value(!isize::MAX)]);
+        assert!(matches!(&folded("{ 2 3 << }")[..], [Op::While(body)] if body.len() == 1 && body[0].op == Op::Value(16)));
+        // Failing operations are kept
+        assert_eq!(folded("1 0 /"), [Op::Value(1), Op::Value(0), Op::Division(DivisionOp::Divide)]);
+        assert_eq!(folded(&format!("{} 1 +?", isize::MAX)), [Op::Value(isize::MAX), Op::Value(1), Op::Checked(CheckedOp::Add)]);
+    }
+
+    #[test]
+    fn identities() {
+        use super::*;
+        assert_eq!(folded("a 0 + 1 * 0 -? 1 /"), folded("a"));
+        assert_eq!(folded("a b * 0 | 0 ^"), folded("a b *"));
+        // Without a value below, removing them would hide a stack underflow
+        assert_eq!(folded("0 +"), [Op::Value(0), Op::Binary(BinaryOp::Add)]);
+        assert_eq!(folded("a s0 1 *"), [Op::Argument(1), Op::Store(0), Op::Value(1), Op::Binary(BinaryOp::Multiply)]);
+    }
+
+    #[test]
+    fn powers_of_two() {
+        use super::*;
+        assert_eq!(folded("a 8 *"), folded("a 3 <<"));
+        assert_eq!(folded("a 16 \\ b 4 @"), folded("a 4 u>> b 3 &"));
+        let bits = isize::BITS;
+        assert_eq!(folded("a 4 /"), folded(&format!("a dup {} >> {} u>> + 2 >>", bits - 1, bits - 2)));
+        // Checked multiplication fails on overflow, which a shift would not
+        assert_eq!(folded("a 8 *?"), [Op::Argument(1), Op::Value(8), Op::Checked(CheckedOp::Multiply)]);
+    }
+
+    #[test]
+    fn rejected_errors() {
+        use super::*;
+        let program = crate::function::FunctionBuilder::new().parse_program("a [ 1 2 + 0 % ]").unwrap();
+        let err = fold_constants(&program.body, true).unwrap_err();
+        assert!(matches!(err.kind(), FunctionCreateErrorKind::ConstantOperationFails(FunctionError::DivideByZero)));
+    }
+}
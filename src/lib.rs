@@ -4,9 +4,13 @@
 //std::compile_error!("This library only works on x86_64 Linux.");
 
 pub(crate) mod raw_code;
+// Only x86_64 code is emitted so far; other targets copy assembled code segments
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod emit;
 pub(crate) mod commands;
 pub(crate) mod code;
 pub mod function;
+pub mod ir;
 
 
 #[cfg(test)]
@@ -0,0 +1,202 @@
+use std::convert::TryInto;
+
+macro_rules! make_no_value_code {
+    ($name:ident, $start:ident, $end:ident) => {
+        pub(crate) fn $name() -> &'static [u8] {
+            extern "C" {
+                static $start: [u8; 0];
+                static $end: [u8; 0];
+            }
+            let start: *const u8 = unsafe {&$start[..]}.as_ptr();
+            let end:   *const u8 = unsafe {&$end[..]  }.as_ptr();
+            assert!(start as usize <= end as usize);
+            let length: usize = // TODO: Should this use end as isize - start as isize?
+                unsafe{end.offset_from(start)}
+                    .try_into()
+                    .expect("end should follow start");
+            unsafe {std::slice::from_raw_parts(start, length)}
+        }
+    }
+}
+
+make_no_value_code!(function_footer_code, function_footer_code_start, function_footer_code_end);
+make_no_value_code!(function_abort_code, function_abort_code_start, function_abort_code_end);
+make_no_value_code!(call_stack_init_code, call_stack_init_code_start, call_stack_init_code_end);
+make_no_value_code!(word_exit_code, word_exit_code_start, word_exit_code_end);
+
+make_no_value_code!(push_a_code, push_a_code_start, push_a_code_end);
+make_no_value_code!(push_b_code, push_b_code_start, push_b_code_end);
+make_no_value_code!(push_c_code, push_c_code_start, push_c_code_end);
+make_no_value_code!(push_d_code, push_d_code_start, push_d_code_end);
+make_no_value_code!(push_e_code, push_e_code_start, push_e_code_end);
+make_no_value_code!(push_f_code, push_f_code_start, push_f_code_end);
+
+make_no_value_code!(add_code, add_code_start, add_code_end);
+make_no_value_code!(subtract_code, subtract_code_start, subtract_code_end);
+make_no_value_code!(multiply_code, multiply_code_start, multiply_code_end);
+
+make_no_value_code!(signed_less_code, signed_less_code_start, signed_less_code_end);
+make_no_value_code!(signed_greater_code, signed_greater_code_start, signed_greater_code_end);
+make_no_value_code!(signed_less_equal_code, signed_less_equal_code_start, signed_less_equal_code_end);
+make_no_value_code!(signed_greater_equal_code, signed_greater_equal_code_start, signed_greater_equal_code_end);
+make_no_value_code!(unsigned_less_code, unsigned_less_code_start, unsigned_less_code_end);
+make_no_value_code!(unsigned_greater_code, unsigned_greater_code_start, unsigned_greater_code_end);
+make_no_value_code!(unsigned_less_equal_code, unsigned_less_equal_code_start, unsigned_less_equal_code_end);
+make_no_value_code!(unsigned_greater_equal_code, unsigned_greater_equal_code_start, unsigned_greater_equal_code_end);
+make_no_value_code!(equal_code, equal_code_start, equal_code_end);
+make_no_value_code!(not_equal_code, not_equal_code_start, not_equal_code_end);
+make_no_value_code!(logical_not_code, logical_not_code_start, logical_not_code_end);
+
+make_no_value_code!(bitwise_and_code, bitwise_and_code_start, bitwise_and_code_end);
+make_no_value_code!(bitwise_or_code, bitwise_or_code_start, bitwise_or_code_end);
+make_no_value_code!(bitwise_xor_code, bitwise_xor_code_start, bitwise_xor_code_end);
+make_no_value_code!(bitwise_not_code, bitwise_not_code_start, bitwise_not_code_end);
+make_no_value_code!(shift_left_code, shift_left_code_start, shift_left_code_end);
+make_no_value_code!(arithmetic_shift_right_code, arithmetic_shift_right_code_start, arithmetic_shift_right_code_end);
+make_no_value_code!(logical_shift_right_code, logical_shift_right_code_start, logical_shift_right_code_end);
+
+make_no_value_code!(dup_code, dup_code_start, dup_code_end);
+make_no_value_code!(drop_code, drop_code_start, drop_code_end);
+make_no_value_code!(swap_code, swap_code_start, swap_code_end);
+make_no_value_code!(rot_code, rot_code_start, rot_code_end);
+make_no_value_code!(over_code, over_code_start, over_code_end);
+
+macro_rules! make_value_code {
+    ($name:ident, $start:ident, $movz:ident, $movk:ident, $end:ident) => {
+        pub(crate) fn $name() -> (&'static [u8], usize, usize) {
+            extern "C" {
+                static $start: [u8; 0];
+                static $movz: [u8; 0];
+                static $movk: [u8; 0];
+                static $end: [u8; 0];
+            }
+            let start: *const u8 = unsafe {&$start[..]}.as_ptr();
+            let movz:  *const u8 = unsafe {&$movz[..] }.as_ptr();
+            let movk:  *const u8 = unsafe {&$movk[..] }.as_ptr();
+            let end:   *const u8 = unsafe {&$end[..]  }.as_ptr();
+            assert!(start as usize <= movz as usize);
+            assert!(movz as usize + 4 <= movk as usize);
+            assert!(movk as usize + 4 <= end as usize);
+            let movz_index: usize =
+                unsafe{movz.offset_from(start)}
+                    .try_into()
+                    .expect("movz should follow start");
+            let movk_index: usize =
+                unsafe{movk.offset_from(start)}
+                    .try_into()
+                    .expect("movk should follow start");
+            let length: usize = // TODO: Should this use end as isize - start as isize?
+                unsafe{end.offset_from(start)}
+                    .try_into()
+                    .expect("end should follow start");
+            (unsafe {std::slice::from_raw_parts(start, length)}, movz_index, movk_index)
+        }
+    }
+}
+
+make_value_code!(push_argument_code, push_argument_code_start, push_argument_movz, push_argument_movk, push_argument_code_end);
+
+make_value_code!(function_header_code, function_header_code_start, function_header_frame_size_movz, function_header_frame_size_movk, function_header_code_end);
+make_value_code!(store_results_code, store_results_code_start, store_results_movz, store_results_movk, store_results_code_end);
+make_value_code!(frame_slot_address_code, frame_slot_address_code_start, frame_slot_address_movz, frame_slot_address_movk, frame_slot_address_code_end);
+
+make_value_code!(push_stack_index_code, push_stack_index_code_start, push_stack_index_movz, push_stack_index_movk, push_stack_index_code_end);
+make_value_code!(push_negative_stack_index_code, push_negative_stack_index_code_start, push_negative_stack_index_movz, push_negative_stack_index_movk, push_negative_stack_index_code_end);
+make_value_code!(pop_stack_index_code, pop_stack_index_code_start, pop_stack_index_movz, pop_stack_index_movk, pop_stack_index_code_end);
+make_value_code!(pop_negative_stack_index_code, pop_negative_stack_index_code_start, pop_negative_stack_index_movz, pop_negative_stack_index_movk, pop_negative_stack_index_code_end);
+make_value_code!(drop_n_code, drop_n_code_start, drop_n_movz, drop_n_movk, drop_n_code_end);
+make_value_code!(word_entry_limit_code, word_entry_limit_code_start, word_entry_limit_movz, word_entry_limit_movk, word_entry_limit_code_end);
+make_value_code!(word_stack_limit_code, word_stack_limit_code_start, word_stack_limit_movz, word_stack_limit_movk, word_stack_limit_code_end);
+
+macro_rules! make_branch_code {
+    ($name:ident, $start:ident, $branch:ident, $end:ident) => {
+        pub(crate) fn $name() -> (&'static [u8], usize) {
+            extern "C" {
+                static $start: [u8; 0];
+                static $branch: [u8; 0];
+                static $end: [u8; 0];
+            }
+            let start: *const u8 = unsafe {&$start[..]}.as_ptr();
+            let branch:  *const u8 = unsafe {&$branch[..] }.as_ptr();
+            let end:   *const u8 = unsafe {&$end[..]  }.as_ptr();
+            assert!(start as usize <= branch as usize);
+            assert!(branch as usize + 4 <= end as usize);
+            let branch_index: usize =
+                unsafe{branch.offset_from(start)}
+                    .try_into()
+                    .expect("branch should follow start");
+            let length: usize = // TODO: Should this use end as isize - start as isize?
+                unsafe{end.offset_from(start)}
+                    .try_into()
+                    .expect("end should follow start");
+            (unsafe {std::slice::from_raw_parts(start, length)}, branch_index)
+        }
+    };
+    ($name:ident, $start:ident, ( $($branches:ident),*), $end:ident) => {
+        pub(crate) fn $name() -> (&'static [u8], Vec<usize>) {
+            extern "C" {
+                static $start: [u8; 0];
+                $( static $branches: [u8; 0]; )*
+                static $end: [u8; 0];
+            }
+            let start: *const u8 = unsafe {&$start[..]}.as_ptr();
+            let branches = [ $( unsafe {&$branches[..] }.as_ptr() ),* ];
+            let end:   *const u8 = unsafe {&$end[..]  }.as_ptr();
+            assert!(start as usize <= branches[0] as usize);
+            for (b1, b2) in branches.iter().take(branches.len()-1).zip(branches.iter().skip(1)) {
+                assert!(*b1 as usize + 4 <= *b2 as usize);
+            }
+            assert!(branches[branches.len()-1] as usize + 4 <= end as usize);
+            let branch_indices = branches.map(|branch|
+                unsafe{branch.offset_from(start)}
+                    .try_into()
+                    .expect("branch should follow start")
+            );
+            let length: usize = // TODO: Should this use end as isize - start as isize?
+                unsafe{end.offset_from(start)}
+                    .try_into()
+                    .expect("end should follow start");
+            (unsafe {std::slice::from_raw_parts(start, length)}, branch_indices.into())
+        }
+    };
+}
+
+// The adrp/add pair that loads the address of a literal is located like a branch
+make_branch_code!(push_value_code, push_value_code_start, push_value_adrp, push_value_code_end);
+
+make_branch_code!(while_loop_header_code, while_loop_header_code_start, while_loop_header_code_branch, while_loop_header_code_end);
+make_branch_code!(while_loop_footer_code, while_loop_footer_code_start, while_loop_footer_code_branch, while_loop_footer_code_end);
+make_branch_code!(if_header_code, if_header_code_start, if_header_code_branch, if_header_code_end);
+make_branch_code!(if_else_code, if_else_code_start, if_else_code_branch, if_else_code_end);
+make_branch_code!(jump_code, jump_code_start, jump_code_branch, jump_code_end);
+make_branch_code!(counted_loop_header_code, counted_loop_header_code_start, counted_loop_header_code_branch, counted_loop_header_code_end);
+make_branch_code!(counted_loop_footer_code, counted_loop_footer_code_start, counted_loop_footer_code_branch, counted_loop_footer_code_end);
+make_branch_code!(call_word_code, call_word_code_start, call_word_code_branch, call_word_code_end);
+make_branch_code!(word_entry_code, word_entry_code_start, word_entry_code_branch, word_entry_code_end);
+make_branch_code!(word_stack_check_code, word_stack_check_code_start, word_stack_check_code_branch, word_stack_check_code_end);
+
+make_branch_code!(signed_add_checked_code, signed_add_checked_code_start, signed_add_checked_code_branch, signed_add_checked_code_end);
+make_branch_code!(unsigned_add_checked_code, unsigned_add_checked_code_start, unsigned_add_checked_code_branch, unsigned_add_checked_code_end);
+make_branch_code!(signed_subtract_checked_code, signed_subtract_checked_code_start, signed_subtract_checked_code_branch, signed_subtract_checked_code_end);
+make_branch_code!(unsigned_subtract_checked_code, unsigned_subtract_checked_code_start, unsigned_subtract_checked_code_branch, unsigned_subtract_checked_code_end);
+make_branch_code!(signed_multiply_checked_code, signed_multiply_checked_code_start, signed_multiply_checked_code_branch, signed_multiply_checked_code_end);
+make_branch_code!(unsigned_multiply_checked_code, unsigned_multiply_checked_code_start, unsigned_multiply_checked_code_branch, unsigned_multiply_checked_code_end);
+make_branch_code!(shift_left_checked_code, shift_left_checked_code_start, shift_left_checked_code_branch, shift_left_checked_code_end);
+make_branch_code!(arithmetic_shift_right_checked_code, arithmetic_shift_right_checked_code_start, arithmetic_shift_right_checked_code_branch, arithmetic_shift_right_checked_code_end);
+make_branch_code!(logical_shift_right_checked_code, logical_shift_right_checked_code_start, logical_shift_right_checked_code_branch, logical_shift_right_checked_code_end);
+
+make_branch_code!(signed_divide_code, signed_divide_code_start, (signed_divide_branch_1, signed_divide_branch_2), signed_divide_code_end);
+make_branch_code!(signed_mod_code, signed_mod_code_start, (signed_mod_branch_1, signed_mod_branch_2), signed_mod_code_end);
+make_branch_code!(signed_divmod_code, signed_divmod_code_start, (signed_divmod_branch_1, signed_divmod_branch_2), signed_divmod_code_end);
+make_branch_code!(unsigned_divide_code, unsigned_divide_code_start, (unsigned_divide_branch), unsigned_divide_code_end);
+make_branch_code!(unsigned_mod_code, unsigned_mod_code_start, (unsigned_mod_branch), unsigned_mod_code_end);
+make_branch_code!(unsigned_divmod_code, unsigned_divmod_code_start, (unsigned_divmod_branch), unsigned_divmod_code_end);
+
+make_value_code!(extern_arg_1_code, extern_arg_1_code_start, extern_arg_1_movz, extern_arg_1_movk, extern_arg_1_code_end);
+make_value_code!(extern_arg_2_code, extern_arg_2_code_start, extern_arg_2_movz, extern_arg_2_movk, extern_arg_2_code_end);
+make_value_code!(extern_arg_3_code, extern_arg_3_code_start, extern_arg_3_movz, extern_arg_3_movk, extern_arg_3_code_end);
+make_value_code!(extern_arg_4_code, extern_arg_4_code_start, extern_arg_4_movz, extern_arg_4_movk, extern_arg_4_code_end);
+make_value_code!(extern_arg_5_code, extern_arg_5_code_start, extern_arg_5_movz, extern_arg_5_movk, extern_arg_5_code_end);
+make_value_code!(extern_arg_6_code, extern_arg_6_code_start, extern_arg_6_movz, extern_arg_6_movk, extern_arg_6_code_end);
+make_branch_code!(call_extern_code, call_extern_code_start, call_extern_adrp, call_extern_code_end);
+make_no_value_code!(push_extern_result_code, push_extern_result_code_start, push_extern_result_code_end);
@@ -3,7 +3,7 @@ use std::convert::TryInto;
 macro_rules! make_no_value_code {
     ($name:ident, $start:ident, $end:ident) => {
         pub(crate) fn $name() -> &'static [u8] {
-            extern {
+            extern "C" {
                 static $start: [u8; 0];
                 static $end: [u8; 0];
             }
@@ -19,9 +19,10 @@ macro_rules! make_no_value_code {
     }
 }
 
-make_no_value_code!(function_header_code, function_header_code_start, function_header_code_end);
 make_no_value_code!(function_footer_code, function_footer_code_start, function_footer_code_end);
 make_no_value_code!(function_abort_code, function_abort_code_start, function_abort_code_end);
+make_no_value_code!(call_stack_init_code, call_stack_init_code_start, call_stack_init_code_end);
+make_no_value_code!(word_exit_code, word_exit_code_start, word_exit_code_end);
 
 make_no_value_code!(push_a_code, push_a_code_start, push_a_code_end);
 make_no_value_code!(push_b_code, push_b_code_start, push_b_code_end);
@@ -33,15 +34,37 @@ make_no_value_code!(push_f_code, push_f_code_start, push_f_code_end);
 make_no_value_code!(add_code, add_code_start, add_code_end);
 make_no_value_code!(subtract_code, subtract_code_start, subtract_code_end);
 make_no_value_code!(multiply_code, multiply_code_start, multiply_code_end);
-// make_no_value_code!(signed_divide_code, signed_divide_code_start, signed_divide_code_end);
-// make_no_value_code!(signed_mod_code, signed_mod_code_start, signed_mod_code_end);
-make_no_value_code!(unsigned_divide_code, unsigned_divide_code_start, unsigned_divide_code_end);
-make_no_value_code!(unsigned_mod_code, unsigned_mod_code_start, unsigned_mod_code_end);
+
+make_no_value_code!(signed_less_code, signed_less_code_start, signed_less_code_end);
+make_no_value_code!(signed_greater_code, signed_greater_code_start, signed_greater_code_end);
+make_no_value_code!(signed_less_equal_code, signed_less_equal_code_start, signed_less_equal_code_end);
+make_no_value_code!(signed_greater_equal_code, signed_greater_equal_code_start, signed_greater_equal_code_end);
+make_no_value_code!(unsigned_less_code, unsigned_less_code_start, unsigned_less_code_end);
+make_no_value_code!(unsigned_greater_code, unsigned_greater_code_start, unsigned_greater_code_end);
+make_no_value_code!(unsigned_less_equal_code, unsigned_less_equal_code_start, unsigned_less_equal_code_end);
+make_no_value_code!(unsigned_greater_equal_code, unsigned_greater_equal_code_start, unsigned_greater_equal_code_end);
+make_no_value_code!(equal_code, equal_code_start, equal_code_end);
+make_no_value_code!(not_equal_code, not_equal_code_start, not_equal_code_end);
+make_no_value_code!(logical_not_code, logical_not_code_start, logical_not_code_end);
+
+make_no_value_code!(bitwise_and_code, bitwise_and_code_start, bitwise_and_code_end);
+make_no_value_code!(bitwise_or_code, bitwise_or_code_start, bitwise_or_code_end);
+make_no_value_code!(bitwise_xor_code, bitwise_xor_code_start, bitwise_xor_code_end);
+make_no_value_code!(bitwise_not_code, bitwise_not_code_start, bitwise_not_code_end);
+make_no_value_code!(shift_left_code, shift_left_code_start, shift_left_code_end);
+make_no_value_code!(arithmetic_shift_right_code, arithmetic_shift_right_code_start, arithmetic_shift_right_code_end);
+make_no_value_code!(logical_shift_right_code, logical_shift_right_code_start, logical_shift_right_code_end);
+
+make_no_value_code!(dup_code, dup_code_start, dup_code_end);
+make_no_value_code!(drop_code, drop_code_start, drop_code_end);
+make_no_value_code!(swap_code, swap_code_start, swap_code_end);
+make_no_value_code!(rot_code, rot_code_start, rot_code_end);
+make_no_value_code!(over_code, over_code_start, over_code_end);
 
 macro_rules! make_value_code {
     ($name:ident, $start:ident, $movw:ident, $movt:ident, $end:ident) => {
         pub(crate) fn $name() -> (&'static [u8], usize, usize) {
-            extern {
+            extern "C" {
                 static $start: [u8; 0];
                 static $movw: [u8; 0];
                 static $movt: [u8; 0];
@@ -71,17 +94,25 @@ macro_rules! make_value_code {
     }
 }
 
+make_value_code!(push_argument_code, push_argument_code_start, push_argument_movw, push_argument_movt, push_argument_code_end);
 make_value_code!(push_value_code, push_value_code_start, push_value_movw, push_value_movt, push_value_code_end);
 
+make_value_code!(function_header_code, function_header_code_start, function_header_frame_size_movw, function_header_frame_size_movt, function_header_code_end);
+make_value_code!(store_results_code, store_results_code_start, store_results_movw, store_results_movt, store_results_code_end);
+make_value_code!(frame_slot_address_code, frame_slot_address_code_start, frame_slot_address_movw, frame_slot_address_movt, frame_slot_address_code_end);
+
 make_value_code!(push_stack_index_code, push_stack_index_code_start, push_stack_index_movw, push_stack_index_movt, push_stack_index_code_end);
 make_value_code!(push_negative_stack_index_code, push_negative_stack_index_code_start, push_negative_stack_index_movw, push_negative_stack_index_movt, push_negative_stack_index_code_end);
 make_value_code!(pop_stack_index_code, pop_stack_index_code_start, pop_stack_index_movw, pop_stack_index_movt, pop_stack_index_code_end);
 make_value_code!(pop_negative_stack_index_code, pop_negative_stack_index_code_start, pop_negative_stack_index_movw, pop_negative_stack_index_movt, pop_negative_stack_index_code_end);
+make_value_code!(drop_n_code, drop_n_code_start, drop_n_movw, drop_n_movt, drop_n_code_end);
+make_value_code!(word_entry_limit_code, word_entry_limit_code_start, word_entry_limit_movw, word_entry_limit_movt, word_entry_limit_code_end);
+make_value_code!(word_stack_limit_code, word_stack_limit_code_start, word_stack_limit_movw, word_stack_limit_movt, word_stack_limit_code_end);
 
 macro_rules! make_branch_code {
     ($name:ident, $start:ident, $branch:ident, $end:ident) => {
         pub(crate) fn $name() -> (&'static [u8], usize) {
-            extern {
+            extern "C" {
                 static $start: [u8; 0];
                 static $branch: [u8; 0];
                 static $end: [u8; 0];
@@ -104,7 +135,7 @@ macro_rules! make_branch_code {
     };
     ($name:ident, $start:ident, ( $($branches:ident),*), $end:ident) => {
         pub(crate) fn $name() -> (&'static [u8], Vec<usize>) {
-            extern {
+            extern "C" {
                 static $start: [u8; 0];
                 $( static $branches: [u8; 0]; )*
                 static $end: [u8; 0];
@@ -133,6 +164,38 @@ macro_rules! make_branch_code {
 
 make_branch_code!(while_loop_header_code, while_loop_header_code_start, while_loop_header_code_branch, while_loop_header_code_end);
 make_branch_code!(while_loop_footer_code, while_loop_footer_code_start, while_loop_footer_code_branch, while_loop_footer_code_end);
+make_branch_code!(if_header_code, if_header_code_start, if_header_code_branch, if_header_code_end);
+make_branch_code!(if_else_code, if_else_code_start, if_else_code_branch, if_else_code_end);
+make_branch_code!(jump_code, jump_code_start, jump_code_branch, jump_code_end);
+make_branch_code!(counted_loop_header_code, counted_loop_header_code_start, counted_loop_header_code_branch, counted_loop_header_code_end);
+make_branch_code!(counted_loop_footer_code, counted_loop_footer_code_start, counted_loop_footer_code_branch, counted_loop_footer_code_end);
+make_branch_code!(call_word_code, call_word_code_start, call_word_code_branch, call_word_code_end);
+make_branch_code!(word_entry_code, word_entry_code_start, word_entry_code_branch, word_entry_code_end);
+make_branch_code!(word_stack_check_code, word_stack_check_code_start, word_stack_check_code_branch, word_stack_check_code_end);
+
+make_branch_code!(signed_add_checked_code, signed_add_checked_code_start, signed_add_checked_code_branch, signed_add_checked_code_end);
+make_branch_code!(unsigned_add_checked_code, unsigned_add_checked_code_start, unsigned_add_checked_code_branch, unsigned_add_checked_code_end);
+make_branch_code!(signed_subtract_checked_code, signed_subtract_checked_code_start, signed_subtract_checked_code_branch, signed_subtract_checked_code_end);
+make_branch_code!(unsigned_subtract_checked_code, unsigned_subtract_checked_code_start, unsigned_subtract_checked_code_branch, unsigned_subtract_checked_code_end);
+make_branch_code!(signed_multiply_checked_code, signed_multiply_checked_code_start, signed_multiply_checked_code_branch, signed_multiply_checked_code_end);
+make_branch_code!(unsigned_multiply_checked_code, unsigned_multiply_checked_code_start, unsigned_multiply_checked_code_branch, unsigned_multiply_checked_code_end);
+make_branch_code!(shift_left_checked_code, shift_left_checked_code_start, shift_left_checked_code_branch, shift_left_checked_code_end);
+make_branch_code!(arithmetic_shift_right_checked_code, arithmetic_shift_right_checked_code_start, arithmetic_shift_right_checked_code_branch, arithmetic_shift_right_checked_code_end);
+make_branch_code!(logical_shift_right_checked_code, logical_shift_right_checked_code_start, logical_shift_right_checked_code_branch, logical_shift_right_checked_code_end);
 
 make_branch_code!(signed_divide_code, signed_divide_code_start, (signed_divide_branch_1, signed_divide_branch_2), signed_divide_code_end);
 make_branch_code!(signed_mod_code, signed_mod_code_start, (signed_mod_branch_1, signed_mod_branch_2), signed_mod_code_end);
+make_branch_code!(signed_divmod_code, signed_divmod_code_start, (signed_divmod_branch_1, signed_divmod_branch_2), signed_divmod_code_end);
+make_branch_code!(unsigned_divide_code, unsigned_divide_code_start, (unsigned_divide_branch), unsigned_divide_code_end);
+make_branch_code!(unsigned_mod_code, unsigned_mod_code_start, (unsigned_mod_branch), unsigned_mod_code_end);
+make_branch_code!(unsigned_divmod_code, unsigned_divmod_code_start, (unsigned_divmod_branch), unsigned_divmod_code_end);
+
+make_value_code!(extern_arg_1_code, extern_arg_1_code_start, extern_arg_1_movw, extern_arg_1_movt, extern_arg_1_code_end);
+make_value_code!(extern_arg_2_code, extern_arg_2_code_start, extern_arg_2_movw, extern_arg_2_movt, extern_arg_2_code_end);
+make_value_code!(extern_arg_3_code, extern_arg_3_code_start, extern_arg_3_movw, extern_arg_3_movt, extern_arg_3_code_end);
+make_value_code!(extern_arg_4_code, extern_arg_4_code_start, extern_arg_4_movw, extern_arg_4_movt, extern_arg_4_code_end);
+make_value_code!(extern_arg_5_code, extern_arg_5_code_start, extern_arg_5_movw, extern_arg_5_movt, extern_arg_5_code_end);
+make_value_code!(extern_arg_6_code, extern_arg_6_code_start, extern_arg_6_movw, extern_arg_6_movt, extern_arg_6_code_end);
+make_value_code!(call_extern_code, call_extern_code_start, call_extern_movw, call_extern_movt, call_extern_code_end);
+make_no_value_code!(call_extern_setup_code, call_extern_setup_code_start, call_extern_setup_code_end);
+make_no_value_code!(push_extern_result_code, push_extern_result_code_start, push_extern_result_code_end);
@@ -3,7 +3,7 @@ use std::convert::TryInto;
 macro_rules! make_no_value_code {
     ($name:ident, $start:ident, $end:ident) => {
         pub(crate) fn $name() -> &'static [u8] {
-            extern {
+            extern "C" {
                 static $start: [u8; 0];
                 static $end: [u8; 0];
             }
@@ -19,9 +19,39 @@ macro_rules! make_no_value_code {
     }
 }
 
-make_no_value_code!(function_header_code, function_header_code_start, function_header_code_end);
+/// Code containing jumps to the abort code, returning where each jump's rel32 offset starts
+macro_rules! make_abort_code {
+    ($name:ident, $start:ident, ( $($offset_ends:ident),* ), $end:ident) => {
+        pub(crate) fn $name() -> (&'static [u8], Vec<usize>) {
+            extern "C" {
+                static $start: [u8; 0];
+                $( static $offset_ends: [u8; 0]; )*
+                static $end: [u8; 0];
+            }
+            let start: *const u8 = unsafe {&$start[..]}.as_ptr();
+            let offset_ends = [ $( unsafe {&$offset_ends[..] }.as_ptr() ),* ];
+            let end:   *const u8 = unsafe {&$end[..]  }.as_ptr();
+            let offset_locs = offset_ends.iter().map(|&offset_end| {
+                assert!(start as usize + 4 <= offset_end as usize && offset_end as usize <= end as usize);
+                let offset_end_idx: usize =
+                    unsafe{offset_end.offset_from(start)}
+                        .try_into()
+                        .expect("offset_end should follow start");
+                offset_end_idx - 4
+            }).collect();
+            let length: usize = // TODO: Should this use end as isize - start as isize?
+                unsafe{end.offset_from(start)}
+                    .try_into()
+                    .expect("end should follow start");
+            (unsafe {std::slice::from_raw_parts(start, length)}, offset_locs)
+        }
+    }
+}
+
 make_no_value_code!(function_footer_code, function_footer_code_start, function_footer_code_end);
 make_no_value_code!(function_abort_code, function_abort_code_start, function_abort_code_end);
+make_no_value_code!(call_stack_init_code, call_stack_init_code_start, call_stack_init_code_end);
+make_no_value_code!(word_exit_code, word_exit_code_start, word_exit_code_end);
 
 make_no_value_code!(push_a_code, push_a_code_start, push_a_code_end);
 make_no_value_code!(push_b_code, push_b_code_start, push_b_code_end);
@@ -33,15 +63,52 @@ make_no_value_code!(push_f_code, push_f_code_start, push_f_code_end);
 make_no_value_code!(add_code, add_code_start, add_code_end);
 make_no_value_code!(subtract_code, subtract_code_start, subtract_code_end);
 make_no_value_code!(multiply_code, multiply_code_start, multiply_code_end);
-make_no_value_code!(signed_divide_code, signed_divide_code_start, signed_divide_code_end);
-make_no_value_code!(signed_mod_code, signed_mod_code_start, signed_mod_code_end);
-make_no_value_code!(unsigned_divide_code, unsigned_divide_code_start, unsigned_divide_code_end);
-make_no_value_code!(unsigned_mod_code, unsigned_mod_code_start, unsigned_mod_code_end);
+make_abort_code!(signed_add_checked_code, signed_add_checked_code_start, (signed_add_checked_abort_offset_end), signed_add_checked_code_end);
+make_abort_code!(unsigned_add_checked_code, unsigned_add_checked_code_start, (unsigned_add_checked_abort_offset_end), unsigned_add_checked_code_end);
+make_abort_code!(signed_subtract_checked_code, signed_subtract_checked_code_start, (signed_subtract_checked_abort_offset_end), signed_subtract_checked_code_end);
+make_abort_code!(unsigned_subtract_checked_code, unsigned_subtract_checked_code_start, (unsigned_subtract_checked_abort_offset_end), unsigned_subtract_checked_code_end);
+make_abort_code!(signed_multiply_checked_code, signed_multiply_checked_code_start, (signed_multiply_checked_abort_offset_end), signed_multiply_checked_code_end);
+make_abort_code!(unsigned_multiply_checked_code, unsigned_multiply_checked_code_start, (unsigned_multiply_checked_abort_offset_end), unsigned_multiply_checked_code_end);
+make_abort_code!(signed_divide_code, signed_divide_code_start, (signed_divide_abort_offset_end_1, signed_divide_abort_offset_end_2), signed_divide_code_end);
+make_abort_code!(signed_mod_code, signed_mod_code_start, (signed_mod_abort_offset_end_1, signed_mod_abort_offset_end_2), signed_mod_code_end);
+make_abort_code!(signed_divmod_code, signed_divmod_code_start, (signed_divmod_abort_offset_end_1, signed_divmod_abort_offset_end_2), signed_divmod_code_end);
+make_abort_code!(unsigned_divide_code, unsigned_divide_code_start, (unsigned_divide_abort_offset_end), unsigned_divide_code_end);
+make_abort_code!(unsigned_mod_code, unsigned_mod_code_start, (unsigned_mod_abort_offset_end), unsigned_mod_code_end);
+make_abort_code!(unsigned_divmod_code, unsigned_divmod_code_start, (unsigned_divmod_abort_offset_end), unsigned_divmod_code_end);
+
+make_no_value_code!(signed_less_code, signed_less_code_start, signed_less_code_end);
+make_no_value_code!(signed_greater_code, signed_greater_code_start, signed_greater_code_end);
+make_no_value_code!(signed_less_equal_code, signed_less_equal_code_start, signed_less_equal_code_end);
+make_no_value_code!(signed_greater_equal_code, signed_greater_equal_code_start, signed_greater_equal_code_end);
+make_no_value_code!(unsigned_less_code, unsigned_less_code_start, unsigned_less_code_end);
+make_no_value_code!(unsigned_greater_code, unsigned_greater_code_start, unsigned_greater_code_end);
+make_no_value_code!(unsigned_less_equal_code, unsigned_less_equal_code_start, unsigned_less_equal_code_end);
+make_no_value_code!(unsigned_greater_equal_code, unsigned_greater_equal_code_start, unsigned_greater_equal_code_end);
+make_no_value_code!(equal_code, equal_code_start, equal_code_end);
+make_no_value_code!(not_equal_code, not_equal_code_start, not_equal_code_end);
+make_no_value_code!(logical_not_code, logical_not_code_start, logical_not_code_end);
+
+make_no_value_code!(bitwise_and_code, bitwise_and_code_start, bitwise_and_code_end);
+make_no_value_code!(bitwise_or_code, bitwise_or_code_start, bitwise_or_code_end);
+make_no_value_code!(bitwise_xor_code, bitwise_xor_code_start, bitwise_xor_code_end);
+make_no_value_code!(bitwise_not_code, bitwise_not_code_start, bitwise_not_code_end);
+make_no_value_code!(shift_left_code, shift_left_code_start, shift_left_code_end);
+make_no_value_code!(arithmetic_shift_right_code, arithmetic_shift_right_code_start, arithmetic_shift_right_code_end);
+make_no_value_code!(logical_shift_right_code, logical_shift_right_code_start, logical_shift_right_code_end);
+make_abort_code!(shift_left_checked_code, shift_left_checked_code_start, (shift_left_checked_abort_offset_end), shift_left_checked_code_end);
+make_abort_code!(arithmetic_shift_right_checked_code, arithmetic_shift_right_checked_code_start, (arithmetic_shift_right_checked_abort_offset_end), arithmetic_shift_right_checked_code_end);
+make_abort_code!(logical_shift_right_checked_code, logical_shift_right_checked_code_start, (logical_shift_right_checked_abort_offset_end), logical_shift_right_checked_code_end);
+
+make_no_value_code!(dup_code, dup_code_start, dup_code_end);
+make_no_value_code!(drop_code, drop_code_start, drop_code_end);
+make_no_value_code!(swap_code, swap_code_start, swap_code_end);
+make_no_value_code!(rot_code, rot_code_start, rot_code_end);
+make_no_value_code!(over_code, over_code_start, over_code_end);
 
 macro_rules! make_value_code {
     ($name:ident, $start:ident, $value_end:ident, $end:ident, $value_size:expr) => {
         pub(crate) fn $name() -> (&'static [u8], std::ops::Range<usize>) {
-            extern {
+            extern "C" {
                 static $start: [u8; 0];
                 static $value_end: [u8; 0];
                 static $end: [u8; 0];
@@ -67,12 +134,34 @@ macro_rules! make_value_code {
     }
 }
 
+make_value_code!(push_argument_code, push_argument_code_start, push_argument_value_end, push_argument_code_end, 4);
 make_value_code!(push_value_code, push_value_code_start, push_value_value_end, push_value_code_end, 4);
 
 make_value_code!(push_stack_index_code, push_stack_index_code_start, push_stack_index_value_end, push_stack_index_code_end, 4);
 make_value_code!(push_negative_stack_index_code, push_negative_stack_index_code_start, push_negative_stack_index_value_end, push_negative_stack_index_code_end, 4);
 make_value_code!(pop_stack_index_code, pop_stack_index_code_start, pop_stack_index_value_end, pop_stack_index_code_end, 4);
 make_value_code!(pop_negative_stack_index_code, pop_negative_stack_index_code_start, pop_negative_stack_index_value_end, pop_negative_stack_index_code_end, 4);
+make_value_code!(drop_n_code, drop_n_code_start, drop_n_value_end, drop_n_code_end, 4);
+
+make_value_code!(function_header_code, function_header_code_start, function_header_frame_size_end, function_header_code_end, 4);
+make_value_code!(store_results_code, store_results_code_start, store_results_value_end, store_results_code_end, 4);
 
 make_value_code!(while_loop_header_code, while_loop_header_code_start, while_loop_header_branch_offset_end, while_loop_header_code_end, 4);
 make_value_code!(while_loop_footer_code, while_loop_footer_code_start, while_loop_footer_branch_offset_end, while_loop_footer_code_end, 4);
+make_value_code!(if_header_code, if_header_code_start, if_header_branch_offset_end, if_header_code_end, 4);
+make_value_code!(if_else_code, if_else_code_start, if_else_branch_offset_end, if_else_code_end, 4);
+make_value_code!(jump_code, jump_code_start, jump_branch_offset_end, jump_code_end, 4);
+make_value_code!(frame_slot_address_code, frame_slot_address_code_start, frame_slot_address_value_end, frame_slot_address_code_end, 4);
+make_value_code!(counted_loop_header_code, counted_loop_header_code_start, counted_loop_header_branch_offset_end, counted_loop_header_code_end, 4);
+make_value_code!(counted_loop_footer_code, counted_loop_footer_code_start, counted_loop_footer_branch_offset_end, counted_loop_footer_code_end, 4);
+make_value_code!(call_word_code, call_word_code_start, call_word_branch_offset_end, call_word_code_end, 4);
+make_value_code!(word_entry_limit_code, word_entry_limit_code_start, word_entry_limit_value_end, word_entry_limit_code_end, 4);
+make_abort_code!(word_entry_code, word_entry_code_start, (word_entry_abort_offset_end), word_entry_code_end);
+make_value_code!(word_stack_limit_code, word_stack_limit_code_start, word_stack_limit_value_end, word_stack_limit_code_end, 4);
+make_abort_code!(word_stack_check_code, word_stack_check_code_start, (word_stack_check_abort_offset_end), word_stack_check_code_end);
+
+make_value_code!(call_extern_setup_code, call_extern_setup_code_start, call_extern_setup_value_end, call_extern_setup_code_end, 4);
+make_value_code!(call_extern_align_code, call_extern_align_code_start, call_extern_align_value_end, call_extern_align_code_end, 4);
+make_value_code!(extern_arg_code, extern_arg_code_start, extern_arg_value_end, extern_arg_code_end, 4);
+make_value_code!(call_extern_code, call_extern_code_start, call_extern_value_end, call_extern_code_end, 4);
+make_value_code!(call_extern_return_code, call_extern_return_code_start, call_extern_return_value_end, call_extern_return_code_end, 4);
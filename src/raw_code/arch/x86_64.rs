@@ -1,78 +1,594 @@
-use std::convert::TryInto;
-
-macro_rules! make_no_value_code {
-    ($name:ident, $start:ident, $end:ident) => {
-        pub(crate) fn $name() -> &'static [u8] {
-            extern {
-                static $start: [u8; 0];
-                static $end: [u8; 0];
-            }
-            let start: *const u8 = unsafe {&$start[..]}.as_ptr();
-            let end:   *const u8 = unsafe {&$end[..]  }.as_ptr();
-            assert!(start as usize <= end as usize);
-            let length: usize = // TODO: Should this use end as isize - start as isize?
-                unsafe{end.offset_from(start)}
-                    .try_into()
-                    .expect("end should follow start");
-            unsafe {std::slice::from_raw_parts(start, length)}
+use std::ops::Range;
+use crate::emit::{AluOp::*, Cond, Emitter, Mem, Reg::{self, *}, ShiftOp, UnaryOp};
+use crate::function::FunctionError;
+use crate::ir::BinaryOp;
+
+/// The code segments assembled from `x86_64-unknown-linux-gnu.S`, which the emitted code must match
+#[cfg(all(test, feature = "asm_reference"))]
+mod reference;
+mod stack_cache;
+pub(crate) use stack_cache::stack_cached_code;
+
+fn code(f: impl FnOnce(&mut Emitter)) -> Vec<u8> {
+    let mut e = Emitter::new();
+    f(&mut e);
+    e.finish()
+}
+
+/// Code with a `size`-byte value to patch, ending at the position `f` returns
+fn value_code(size: usize, f: impl FnOnce(&mut Emitter) -> usize) -> (Vec<u8>, Range<usize>) {
+    let mut e = Emitter::new();
+    let value_end = f(&mut e);
+    (e.finish(), value_end - size .. value_end)
+}
+
+/// Code containing jumps to the abort code, returning where each jump's rel32 offset starts
+fn abort_code(f: impl FnOnce(&mut Emitter, &mut Vec<usize>)) -> (Vec<u8>, Vec<usize>) {
+    let mut e = Emitter::new();
+    let mut offset_locs = vec![];
+    f(&mut e, &mut offset_locs);
+    (e.finish(), offset_locs)
+}
+
+/// Jumps to the abort code with `error` unless `cond` holds
+fn abort_unless(e: &mut Emitter, offset_locs: &mut Vec<usize>, cond: Cond, error: FunctionError) {
+    let skip = e.jcc_short(cond);
+    e.mov_imm32(Rdx, error as u32);
+    e.jmp();
+    offset_locs.push(e.position() - 4);
+    e.bind(skip);
+}
+
+pub(crate) fn function_header_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.endbr64();
+        e.push(Rbp);
+        e.mov(Rbp, Rsp);
+        // The arguments are at -8(%rbp) to -48(%rbp), and %rbx at -56(%rbp)
+        for reg in [Rdi, Rsi, Rdx, Rcx, R8, R9, Rbx] {
+            e.push(reg);
         }
+        // Frame slots are at -64(%rbp) and below
+        e.alu_imm32(Sub, Rsp, 0);
+        let value_end = e.position();
+        // Bottom of the data stack
+        e.mov(Rbx, Rsp);
+        value_end
+    })
+}
+
+pub(crate) fn function_footer_code() -> Vec<u8> {
+    code(|e| {
+        e.pop(Rax);
+        e.xor32(Rdx, Rdx);
+        e.mov_load(Rbx, Mem::disp(Rbp, -56));
+        e.mov(Rsp, Rbp);
+        e.pop(Rbp);
+        e.ret();
+    })
+}
+
+pub(crate) fn function_abort_code() -> Vec<u8> {
+    // The error code is already in %rdx
+    code(|e| {
+        e.mov_load(Rbx, Mem::disp(Rbp, -56));
+        e.mov(Rsp, Rbp);
+        e.pop(Rbp);
+        e.ret();
+    })
+}
+
+/// Copies the top values of the stack into the buffer at the address on top of the stack, in stack order
+pub(crate) fn store_results_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.pop(Rax);
+        // Number of values
+        e.mov_imm(Rcx, 0);
+        let value_end = e.position();
+        e.mov_load(Rdx, Mem::indexed(Rsp, Rcx, 8, -8));
+        e.mov(Mem::base(Rax), Rdx);
+        e.alu_imm(Add, Rax, 8);
+        e.loop_to(value_end);
+        value_end
+    })
+}
+
+pub(crate) fn while_loop_header_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.mov_load(Rax, Mem::base(Rsp));
+        e.test(Rax, Rax);
+        e.jcc(Cond::E);
+        e.position()
+    })
+}
+
+pub(crate) fn while_loop_footer_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.mov_load(Rax, Mem::base(Rsp));
+        e.test(Rax, Rax);
+        e.jcc(Cond::Ne);
+        e.position()
+    })
+}
+
+pub(crate) fn if_header_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.pop(Rax);
+        e.test(Rax, Rax);
+        e.jcc(Cond::E);
+        e.position()
+    })
+}
+
+pub(crate) fn if_else_code() -> (Vec<u8>, Range<usize>) {
+    jump_code()
+}
+
+pub(crate) fn jump_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.jmp();
+        e.position()
+    })
+}
+
+pub(crate) fn frame_slot_address_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.lea(Rcx, Mem::disp(Rbp, 0).disp32());
+        e.position()
+    })
+}
+
+/// `%rcx` holds the address of the counter's frame slot
+pub(crate) fn counted_loop_header_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.pop(Rax);
+        e.mov(Mem::base(Rcx), Rax);
+        e.jmp();
+        e.position()
+    })
+}
+
+/// `%rcx` holds the address of the counter's frame slot
+pub(crate) fn counted_loop_footer_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.alu_imm(Sub, Mem::base(Rcx), 1);
+        e.jcc(Cond::Ge);
+        e.position()
+    })
+}
+
+/// Calls a user-defined word
+pub(crate) fn call_word_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.call();
+        e.position()
+    })
+}
+
+/// The return stack sits just above the data stack: `(%rbx)` holds the call depth,
+/// and `8(%rbx)`, `16(%rbx)`, ... hold the return addresses of the active calls
+pub(crate) fn call_stack_init_code() -> Vec<u8> {
+    code(|e| e.mov_imm(Mem::base(Rbx), 0))
+}
+
+/// Compares the call depth with the maximum call depth
+pub(crate) fn word_entry_limit_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.mov_load(Rcx, Mem::base(Rbx));
+        e.alu_imm32(Cmp, Rcx, 0);
+        e.position()
+    })
+}
+
+/// Moves the return address pushed by `call` onto the return stack, or aborts if the
+/// maximum call depth has been reached
+pub(crate) fn word_entry_code() -> (Vec<u8>, Vec<usize>) {
+    abort_code(|e, offset_locs| {
+        abort_unless(e, offset_locs, Cond::B, FunctionError::StackOverflow);
+        e.alu_imm(Add, Rcx, 1);
+        e.mov(Mem::base(Rbx), Rcx);
+        e.pop(Mem::indexed(Rbx, Rcx, 8, 0));
+    })
+}
+
+/// Compares the size of the data stack in bytes with its limit
+pub(crate) fn word_stack_limit_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.mov(Rcx, Rbx);
+        e.alu(Sub, Rcx, Rsp);
+        e.alu_imm32(Cmp, Rcx, 0);
+        e.position()
+    })
+}
+
+/// Aborts if the data stack is larger than its limit
+pub(crate) fn word_stack_check_code() -> (Vec<u8>, Vec<usize>) {
+    abort_code(|e, offset_locs| abort_unless(e, offset_locs, Cond::Be, FunctionError::StackOverflow))
+}
+
+pub(crate) fn word_exit_code() -> Vec<u8> {
+    code(|e| {
+        e.mov_load(Rcx, Mem::base(Rbx));
+        e.push(Mem::indexed(Rbx, Rcx, 8, 0));
+        e.alu_imm(Sub, Rcx, 1);
+        e.mov(Mem::base(Rbx), Rcx);
+        e.ret();
+    })
+}
+
+fn push_saved_argument_code(index: i32) -> Vec<u8> {
+    code(|e| e.push(Mem::disp(Rbp, -8 * index)))
+}
+
+pub(crate) fn push_a_code() -> Vec<u8> { push_saved_argument_code(1) }
+pub(crate) fn push_b_code() -> Vec<u8> { push_saved_argument_code(2) }
+pub(crate) fn push_c_code() -> Vec<u8> { push_saved_argument_code(3) }
+pub(crate) fn push_d_code() -> Vec<u8> { push_saved_argument_code(4) }
+pub(crate) fn push_e_code() -> Vec<u8> { push_saved_argument_code(5) }
+pub(crate) fn push_f_code() -> Vec<u8> { push_saved_argument_code(6) }
+
+/// Arguments past the sixth are passed on the stack, starting at `16(%rbp)`
+pub(crate) fn push_argument_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.push(Mem::disp(Rbp, 0).disp32());
+        e.position()
+    })
+}
+
+/// Pops the top of the stack into `%rax` and applies `op` to the new top
+fn binary_code(op: crate::emit::AluOp) -> Vec<u8> {
+    code(|e| {
+        e.pop(Rax);
+        e.alu(op, Mem::base(Rsp), Rax);
+    })
+}
+
+/// `binary_code(op)`, aborting with `error` unless `cond` holds afterwards
+fn binary_checked_code(op: crate::emit::AluOp, cond: Cond, error: FunctionError) -> (Vec<u8>, Vec<usize>) {
+    abort_code(|e, offset_locs| {
+        e.pop(Rax);
+        e.alu(op, Mem::base(Rsp), Rax);
+        abort_unless(e, offset_locs, cond, error);
+    })
+}
+
+/// The ALU operation which computes `op`, if there is one
+fn alu_op(op: BinaryOp) -> Option<crate::emit::AluOp> {
+    match op {
+        BinaryOp::Add => Some(Add),
+        BinaryOp::Subtract => Some(Sub),
+        BinaryOp::And => Some(And),
+        BinaryOp::Or => Some(Or),
+        BinaryOp::Xor => Some(Xor),
+        _ => None,
     }
 }
 
-make_no_value_code!(function_header_code, function_header_code_start, function_header_code_end);
-make_no_value_code!(function_footer_code, function_footer_code_start, function_footer_code_end);
-make_no_value_code!(function_abort_code, function_abort_code_start, function_abort_code_end);
-
-make_no_value_code!(push_a_code, push_a_code_start, push_a_code_end);
-make_no_value_code!(push_b_code, push_b_code_start, push_b_code_end);
-make_no_value_code!(push_c_code, push_c_code_start, push_c_code_end);
-make_no_value_code!(push_d_code, push_d_code_start, push_d_code_end);
-make_no_value_code!(push_e_code, push_e_code_start, push_e_code_end);
-make_no_value_code!(push_f_code, push_f_code_start, push_f_code_end);
-
-make_no_value_code!(add_code, add_code_start, add_code_end);
-make_no_value_code!(subtract_code, subtract_code_start, subtract_code_end);
-make_no_value_code!(multiply_code, multiply_code_start, multiply_code_end);
-make_no_value_code!(signed_divide_code, signed_divide_code_start, signed_divide_code_end);
-make_no_value_code!(signed_mod_code, signed_mod_code_start, signed_mod_code_end);
-make_no_value_code!(unsigned_divide_code, unsigned_divide_code_start, unsigned_divide_code_end);
-make_no_value_code!(unsigned_mod_code, unsigned_mod_code_start, unsigned_mod_code_end);
-
-macro_rules! make_value_code {
-    ($name:ident, $start:ident, $value_end:ident, $end:ident, $value_size:expr) => {
-        pub(crate) fn $name() -> (&'static [u8], std::ops::Range<usize>) {
-            extern {
-                static $start: [u8; 0];
-                static $value_end: [u8; 0];
-                static $end: [u8; 0];
+/// Applies `op` to the top of the stack and `value`, if `op` can take an immediate operand
+pub(crate) fn binary_immediate_code(op: BinaryOp, value: i32) -> Option<Vec<u8>> {
+    let op = alu_op(op)?;
+    Some(code(|e| e.alu_imm(op, Mem::base(Rsp), value)))
+}
+
+pub(crate) fn add_code() -> Vec<u8> { binary_code(Add) }
+pub(crate) fn subtract_code() -> Vec<u8> { binary_code(Sub) }
+pub(crate) fn bitwise_and_code() -> Vec<u8> { binary_code(And) }
+pub(crate) fn bitwise_or_code() -> Vec<u8> { binary_code(Or) }
+pub(crate) fn bitwise_xor_code() -> Vec<u8> { binary_code(Xor) }
+
+pub(crate) fn signed_add_checked_code() -> (Vec<u8>, Vec<usize>) {
+    binary_checked_code(Add, Cond::No, FunctionError::SignedAdditionOverflow)
+}
+pub(crate) fn unsigned_add_checked_code() -> (Vec<u8>, Vec<usize>) {
+    binary_checked_code(Add, Cond::Ae, FunctionError::UnsignedAdditionOverflow)
+}
+pub(crate) fn signed_subtract_checked_code() -> (Vec<u8>, Vec<usize>) {
+    binary_checked_code(Sub, Cond::No, FunctionError::SignedSubtractionOverflow)
+}
+pub(crate) fn unsigned_subtract_checked_code() -> (Vec<u8>, Vec<usize>) {
+    binary_checked_code(Sub, Cond::Ae, FunctionError::UnsignedSubtractionOverflow)
+}
+
+pub(crate) fn multiply_code() -> Vec<u8> {
+    code(|e| {
+        e.pop(Rax);
+        e.unary(UnaryOp::Mul, Mem::base(Rsp));
+        e.mov(Mem::base(Rsp), Rax);
+    })
+}
+
+/// `mul` and `imul` set the overflow flag when the high half of the product is significant
+fn multiply_checked_code(op: UnaryOp, error: FunctionError) -> (Vec<u8>, Vec<usize>) {
+    abort_code(|e, offset_locs| {
+        e.pop(Rax);
+        e.unary(op, Mem::base(Rsp));
+        abort_unless(e, offset_locs, Cond::No, error);
+        e.mov(Mem::base(Rsp), Rax);
+    })
+}
+
+pub(crate) fn signed_multiply_checked_code() -> (Vec<u8>, Vec<usize>) {
+    multiply_checked_code(UnaryOp::Imul, FunctionError::SignedMultiplicationOverflow)
+}
+pub(crate) fn unsigned_multiply_checked_code() -> (Vec<u8>, Vec<usize>) {
+    multiply_checked_code(UnaryOp::Mul, FunctionError::UnsignedMultiplicationOverflow)
+}
+
+/// Pops the divisor into `%rcx`, checks it, and divides the new top of the stack by it,
+/// storing `results` (`%rax` is the quotient and `%rdx` the remainder) in its place
+fn division_code(signed: bool, results: &[Reg]) -> (Vec<u8>, Vec<usize>) {
+    abort_code(|e, offset_locs| {
+        e.pop(Rcx);
+        e.test(Rcx, Rcx);
+        abort_unless(e, offset_locs, Cond::Ne, FunctionError::DivideByZero);
+        if signed {
+            // MIN / -1 overflows
+            e.alu_imm(Cmp, Rcx, -1);
+            let not_negative_one = e.jcc_short(Cond::Ne);
+            e.movabs(Rax, i64::MIN);
+            e.alu_load(Cmp, Rax, Mem::base(Rsp));
+            abort_unless(e, offset_locs, Cond::Ne, FunctionError::DivideMinByNegativeOne);
+            e.bind(not_negative_one);
+        }
+        e.mov_load(Rax, Mem::base(Rsp));
+        if signed {
+            e.cqo();
+            e.unary(UnaryOp::Idiv, Rcx);
+        } else {
+            e.xor32(Rdx, Rdx);
+            e.unary(UnaryOp::Div, Rcx);
+        }
+        e.mov(Mem::base(Rsp), results[0]);
+        for &result in &results[1..] {
+            e.push(result);
+        }
+    })
+}
+
+pub(crate) fn signed_divide_code() -> (Vec<u8>, Vec<usize>) { division_code(true, &[Rax]) }
+pub(crate) fn signed_mod_code() -> (Vec<u8>, Vec<usize>) { division_code(true, &[Rdx]) }
+pub(crate) fn signed_divmod_code() -> (Vec<u8>, Vec<usize>) { division_code(true, &[Rax, Rdx]) }
+pub(crate) fn unsigned_divide_code() -> (Vec<u8>, Vec<usize>) { division_code(false, &[Rax]) }
+pub(crate) fn unsigned_mod_code() -> (Vec<u8>, Vec<usize>) { division_code(false, &[Rdx]) }
+pub(crate) fn unsigned_divmod_code() -> (Vec<u8>, Vec<usize>) { division_code(false, &[Rax, Rdx]) }
+
+/// Replaces the top two values of the stack with whether `cond` holds between them
+fn comparison_code(cond: Cond) -> Vec<u8> {
+    code(|e| {
+        e.pop(Rcx);
+        e.xor32(Rax, Rax);
+        e.alu(Cmp, Mem::base(Rsp), Rcx);
+        e.setcc(cond, Rax);
+        e.mov(Mem::base(Rsp), Rax);
+    })
+}
+
+pub(crate) fn signed_less_code() -> Vec<u8> { comparison_code(Cond::L) }
+pub(crate) fn signed_greater_code() -> Vec<u8> { comparison_code(Cond::G) }
+pub(crate) fn signed_less_equal_code() -> Vec<u8> { comparison_code(Cond::Le) }
+pub(crate) fn signed_greater_equal_code() -> Vec<u8> { comparison_code(Cond::Ge) }
+pub(crate) fn unsigned_less_code() -> Vec<u8> { comparison_code(Cond::B) }
+pub(crate) fn unsigned_greater_code() -> Vec<u8> { comparison_code(Cond::A) }
+pub(crate) fn unsigned_less_equal_code() -> Vec<u8> { comparison_code(Cond::Be) }
+pub(crate) fn unsigned_greater_equal_code() -> Vec<u8> { comparison_code(Cond::Ae) }
+pub(crate) fn equal_code() -> Vec<u8> { comparison_code(Cond::E) }
+pub(crate) fn not_equal_code() -> Vec<u8> { comparison_code(Cond::Ne) }
+
+pub(crate) fn logical_not_code() -> Vec<u8> {
+    code(|e| {
+        e.xor32(Rax, Rax);
+        e.alu_imm(Cmp, Mem::base(Rsp), 0);
+        e.setcc(Cond::E, Rax);
+        e.mov(Mem::base(Rsp), Rax);
+    })
+}
+
+pub(crate) fn bitwise_not_code() -> Vec<u8> {
+    code(|e| e.unary(UnaryOp::Not, Mem::base(Rsp)))
+}
+
+/// The shift amount is masked to the word width
+fn shift_code(op: ShiftOp) -> Vec<u8> {
+    code(|e| {
+        e.pop(Rcx);
+        e.shift_cl(op, Mem::base(Rsp));
+    })
+}
+
+fn shift_checked_code(op: ShiftOp) -> (Vec<u8>, Vec<usize>) {
+    abort_code(|e, offset_locs| {
+        e.pop(Rcx);
+        e.alu_imm(Cmp, Rcx, 64);
+        abort_unless(e, offset_locs, Cond::B, FunctionError::ShiftAmountOutOfRange);
+        e.shift_cl(op, Mem::base(Rsp));
+    })
+}
+
+pub(crate) fn shift_left_code() -> Vec<u8> { shift_code(ShiftOp::Shl) }
+pub(crate) fn arithmetic_shift_right_code() -> Vec<u8> { shift_code(ShiftOp::Sar) }
+pub(crate) fn logical_shift_right_code() -> Vec<u8> { shift_code(ShiftOp::Shr) }
+pub(crate) fn shift_left_checked_code() -> (Vec<u8>, Vec<usize>) { shift_checked_code(ShiftOp::Shl) }
+pub(crate) fn arithmetic_shift_right_checked_code() -> (Vec<u8>, Vec<usize>) { shift_checked_code(ShiftOp::Sar) }
+pub(crate) fn logical_shift_right_checked_code() -> (Vec<u8>, Vec<usize>) { shift_checked_code(ShiftOp::Shr) }
+
+pub(crate) fn push_value_code() -> (Vec<u8>, Range<usize>) {
+    value_code(8, |e| {
+        e.movabs(Rax, 0);
+        let value_end = e.position();
+        e.push(Rax);
+        value_end
+    })
+}
+
+/// Pushes the value `%rax` slots above `base`
+fn push_stack_index_code_from(base: Reg) -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.mov_imm(Rax, 0);
+        let value_end = e.position();
+        e.mov_load(Rcx, Mem::indexed(base, Rax, 8, 0));
+        e.push(Rcx);
+        value_end
+    })
+}
+
+/// Pops a value, and stores it `%rax` slots above `base`
+fn pop_stack_index_code_from(base: Reg) -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.pop(Rcx);
+        e.mov_imm(Rax, 0);
+        let value_end = e.position();
+        e.mov(Mem::indexed(base, Rax, 8, 0), Rcx);
+        value_end
+    })
+}
+
+pub(crate) fn push_stack_index_code() -> (Vec<u8>, Range<usize>) { push_stack_index_code_from(Rsp) }
+pub(crate) fn push_negative_stack_index_code() -> (Vec<u8>, Range<usize>) { push_stack_index_code_from(Rbx) }
+pub(crate) fn pop_stack_index_code() -> (Vec<u8>, Range<usize>) { pop_stack_index_code_from(Rsp) }
+pub(crate) fn pop_negative_stack_index_code() -> (Vec<u8>, Range<usize>) { pop_stack_index_code_from(Rbx) }
+
+pub(crate) fn dup_code() -> Vec<u8> {
+    code(|e| e.push(Mem::base(Rsp)))
+}
+
+pub(crate) fn drop_code() -> Vec<u8> {
+    code(|e| e.alu_imm(Add, Rsp, 8))
+}
+
+pub(crate) fn swap_code() -> Vec<u8> {
+    code(|e| {
+        e.mov_load(Rax, Mem::base(Rsp));
+        e.mov_load(Rcx, Mem::disp(Rsp, 8));
+        e.mov(Mem::base(Rsp), Rcx);
+        e.mov(Mem::disp(Rsp, 8), Rax);
+    })
+}
+
+pub(crate) fn rot_code() -> Vec<u8> {
+    code(|e| {
+        e.mov_load(Rax, Mem::base(Rsp));
+        e.mov_load(Rcx, Mem::disp(Rsp, 8));
+        e.mov_load(Rdx, Mem::disp(Rsp, 16));
+        e.mov(Mem::base(Rsp), Rdx);
+        e.mov(Mem::disp(Rsp, 8), Rax);
+        e.mov(Mem::disp(Rsp, 16), Rcx);
+    })
+}
+
+pub(crate) fn over_code() -> Vec<u8> {
+    code(|e| e.push(Mem::disp(Rsp, 8)))
+}
+
+pub(crate) fn drop_n_code() -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.mov_imm(Rax, 0);
+        let value_end = e.position();
+        e.lea(Rsp, Mem::indexed(Rsp, Rax, 8, 0));
+        value_end
+    })
+}
+
+/// Loads an argument of an extern call from the data stack into `reg`
+fn extern_arg_code(reg: Reg) -> (Vec<u8>, Range<usize>) {
+    value_code(4, |e| {
+        e.mov_load(reg, Mem::disp(Rsp, 0).disp32());
+        e.position()
+    })
+}
+
+pub(crate) fn extern_arg_1_code() -> (Vec<u8>, Range<usize>) { extern_arg_code(Rdi) }
+pub(crate) fn extern_arg_2_code() -> (Vec<u8>, Range<usize>) { extern_arg_code(Rsi) }
+pub(crate) fn extern_arg_3_code() -> (Vec<u8>, Range<usize>) { extern_arg_code(Rdx) }
+pub(crate) fn extern_arg_4_code() -> (Vec<u8>, Range<usize>) { extern_arg_code(Rcx) }
+pub(crate) fn extern_arg_5_code() -> (Vec<u8>, Range<usize>) { extern_arg_code(R8) }
+pub(crate) fn extern_arg_6_code() -> (Vec<u8>, Range<usize>) { extern_arg_code(R9) }
+
+/// Calls an extern function with the stack 16-byte aligned, and pushes its result
+pub(crate) fn call_extern_code() -> (Vec<u8>, Range<usize>) {
+    value_code(8, |e| {
+        e.mov(Rax, Rsp);
+        e.alu_imm(And, Rsp, -16);
+        e.push(Rax);
+        e.push(Rax);
+        // Function address
+        e.movabs(Rax, 0);
+        let value_end = e.position();
+        e.call_indirect(Rax);
+        e.mov_load(Rsp, Mem::base(Rsp));
+        e.push(Rax);
+        value_end
+    })
+}
+
+#[cfg(all(test, feature = "asm_reference"))]
+mod tests {
+    /// Checks that the emitted and assembled code match, except in the `patched` bytes,
+    /// which the assembled code fills with placeholders
+    fn assert_same(name: &str, emitted: &[u8], assembled: &[u8], patched: &[std::ops::Range<usize>]) {
+        let mask = |code: &[u8]| {
+            let mut code = code.to_vec();
+            for range in patched {
+                code[range.clone()].fill(0);
             }
-            let start:     *const u8 = unsafe {&$start[..]}.as_ptr();
-            let value_end: *const u8 = unsafe {&$value_end[..]}.as_ptr();
-            let end:       *const u8 = unsafe {&$end[..]  }.as_ptr();
-            assert!(start as usize + $value_size <= value_end as usize && value_end as usize <= end as usize);
-            let value_end_idx: usize = // TODO: Should this use end as isize - start as isize?
-                unsafe{value_end.offset_from(start)}
-                    .try_into()
-                    .expect("value_end should follow start");
-            let value_idx: usize =
-                value_end_idx
-                    .checked_sub($value_size)
-                    .expect("value_end should be at least value_size bytes after start");
-            let length: usize = // TODO: Should this use end as isize - start as isize?
-                unsafe{end.offset_from(start)}
-                    .try_into()
-                    .expect("end should follow start");
-            (unsafe {std::slice::from_raw_parts(start, length)}, value_idx .. value_idx+$value_size)
+            code
+        };
+        assert_eq!(mask(emitted), mask(assembled), "{} differs from the assembled code", name);
+    }
+
+    macro_rules! assert_same_code {
+        ($($name:ident),* $(,)?) => {
+            $( assert_same(stringify!($name), &super::$name(), super::reference::$name(), &[]); )*
         }
     }
-}
 
-make_value_code!(push_value_code, push_value_code_start, push_value_value_end, push_value_code_end, 8);
+    macro_rules! assert_same_value_code {
+        ($($name:ident),* $(,)?) => { $( {
+            let (emitted, value) = super::$name();
+            let (assembled, assembled_value) = super::reference::$name();
+            assert_eq!(value, assembled_value, "{} has its value in the wrong place", stringify!($name));
+            assert_same(stringify!($name), &emitted, assembled, &[value]);
+        } )* }
+    }
 
-make_value_code!(push_stack_index_code, push_stack_index_code_start, push_stack_index_value_end, push_stack_index_code_end, 4);
-make_value_code!(push_negative_stack_index_code, push_negative_stack_index_code_start, push_negative_stack_index_value_end, push_negative_stack_index_code_end, 4);
-make_value_code!(pop_stack_index_code, pop_stack_index_code_start, pop_stack_index_value_end, pop_stack_index_code_end, 4);
-make_value_code!(pop_negative_stack_index_code, pop_negative_stack_index_code_start, pop_negative_stack_index_value_end, pop_negative_stack_index_code_end, 4);
+    macro_rules! assert_same_abort_code {
+        ($($name:ident),* $(,)?) => { $( {
+            let (emitted, offset_locs) = super::$name();
+            let (assembled, assembled_offset_locs) = super::reference::$name();
+            assert_eq!(offset_locs, assembled_offset_locs, "{} has its abort jumps in the wrong place", stringify!($name));
+            let patched: Vec<_> = offset_locs.iter().map(|&loc| loc..loc+4).collect();
+            assert_same(stringify!($name), &emitted, assembled, &patched);
+        } )* }
+    }
 
-make_value_code!(while_loop_header_code, while_loop_header_code_start, while_loop_header_branch_offset_end, while_loop_header_code_end, 4);
-make_value_code!(while_loop_footer_code, while_loop_footer_code_start, while_loop_footer_branch_offset_end, while_loop_footer_code_end, 4);
+    #[test]
+    fn matches_assembled_code() {
+        assert_same_code!(
+            function_footer_code, function_abort_code, call_stack_init_code, word_exit_code,
+            push_a_code, push_b_code, push_c_code, push_d_code, push_e_code, push_f_code,
+            add_code, subtract_code, multiply_code,
+            signed_less_code, signed_greater_code, signed_less_equal_code, signed_greater_equal_code,
+            unsigned_less_code, unsigned_greater_code, unsigned_less_equal_code, unsigned_greater_equal_code,
+            equal_code, not_equal_code, logical_not_code,
+            bitwise_and_code, bitwise_or_code, bitwise_xor_code, bitwise_not_code,
+            shift_left_code, arithmetic_shift_right_code, logical_shift_right_code,
+            dup_code, drop_code, swap_code, rot_code, over_code,
+        );
+        assert_same_value_code!(
+            push_argument_code, push_value_code,
+            push_stack_index_code, push_negative_stack_index_code, pop_stack_index_code, pop_negative_stack_index_code,
+            drop_n_code, function_header_code, store_results_code,
+            while_loop_header_code, while_loop_footer_code, if_header_code, if_else_code, jump_code,
+            frame_slot_address_code, counted_loop_header_code, counted_loop_footer_code, call_word_code,
+            word_entry_limit_code, word_stack_limit_code,
+            extern_arg_1_code, extern_arg_2_code, extern_arg_3_code, extern_arg_4_code, extern_arg_5_code, extern_arg_6_code,
+            call_extern_code,
+        );
+        assert_same_abort_code!(
+            signed_add_checked_code, unsigned_add_checked_code, signed_subtract_checked_code, unsigned_subtract_checked_code,
+            signed_multiply_checked_code, unsigned_multiply_checked_code,
+            signed_divide_code, signed_mod_code, signed_divmod_code, unsigned_divide_code, unsigned_mod_code, unsigned_divmod_code,
+            shift_left_checked_code, arithmetic_shift_right_checked_code, logical_shift_right_checked_code,
+            word_entry_code, word_stack_check_code,
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use super::{alu_op, code};
+use crate::commands::StackOp;
+use crate::ir::BinaryOp;
+use crate::emit::{AluOp, Cond, Emitter, Mem, Operand, Reg::{self, *}, ShiftOp, UnaryOp};
+
+/// Registers which hold cached stack values; %rax, %rcx, and %rdx are left free for scratch
+const CACHE_REGISTERS: [Reg; 4] = [R8, R9, R10, R11];
+
+/// The values on top of the data stack which are held in registers instead of memory
+#[derive(Debug, Default)]
+struct StackCache {
+    /// Deepest first. The value below the deepest one is at `(%rsp)`
+    regs: Vec<Reg>,
+}
+
+impl StackCache {
+    /// A register not holding a cached value, spilling the deepest cached value if there is none
+    fn allocate(&mut self, e: &mut Emitter) -> Reg {
+        if let Some(&reg) = CACHE_REGISTERS.iter().find(|reg| !self.regs.contains(reg)) {
+            return reg;
+        }
+        let deepest = self.regs.remove(0);
+        e.push(deepest);
+        deepest
+    }
+
+    /// Pops values from memory until at least `count` (at most 3) are cached
+    fn fill(&mut self, e: &mut Emitter, count: usize) {
+        while self.regs.len() < count {
+            let reg = self.allocate(e);
+            e.pop(reg);
+            self.regs.insert(0, reg);
+        }
+    }
+
+    fn pop(&mut self, e: &mut Emitter) -> Reg {
+        self.fill(e, 1);
+        self.regs.pop().unwrap()
+    }
+
+    fn top(&mut self, e: &mut Emitter) -> Reg {
+        self.fill(e, 1);
+        *self.regs.last().unwrap()
+    }
+
+    /// Where the `index`th (0-based, from the top) value of the stack is
+    fn location(&self, e: &mut Emitter, index: i32) -> Operand {
+        let cached = self.regs.len();
+        match usize::try_from(index) {
+            Ok(index) if index < cached => self.regs[cached - 1 - index].into(),
+            _ => memory_slot(e, Rsp, index - cached as i32).into(),
+        }
+    }
+
+    /// Stores every cached value to memory
+    fn spill(&mut self, e: &mut Emitter) {
+        for reg in self.regs.drain(..) {
+            e.push(reg);
+        }
+    }
+
+    fn apply(&mut self, e: &mut Emitter, op: StackOp) {
+        match op {
+            StackOp::PushArgument(index) => {
+                let reg = self.allocate(e);
+                let index = index as i32;
+                // Arguments past the sixth are passed on the stack, starting at `16(%rbp)`
+                let displacement = if index <= 6 { -8 * index } else { 16 + (index - 7) * 8 };
+                e.mov_load(reg, Mem::disp(Rbp, displacement));
+                self.regs.push(reg);
+            },
+            StackOp::PushValue(value) => {
+                let reg = self.allocate(e);
+                match i32::try_from(value) {
+                    Ok(value) => e.mov_imm(reg, value),
+                    Err(_) => e.movabs(reg, value as i64),
+                }
+                self.regs.push(reg);
+            },
+            StackOp::PushStackIndex(index) => {
+                if index < 0 {
+                    // Indices from the bottom of the stack would need the depth of the stack to find in the cache
+                    self.spill(e);
+                }
+                let reg = self.allocate(e);
+                let source = if index < 0 { memory_slot(e, Rbx, index).into() } else { self.location(e, index) };
+                e.mov_load(reg, source);
+                self.regs.push(reg);
+            },
+            StackOp::PopStackIndex(index) => {
+                let value = self.pop(e);
+                let destination = if index < 0 {
+                    self.spill(e);
+                    memory_slot(e, Rbx, index).into()
+                } else {
+                    self.location(e, index)
+                };
+                e.mov(destination, value);
+            },
+            StackOp::Binary(op) => {
+                self.fill(e, 2);
+                let rhs = self.pop(e);
+                let lhs = self.top(e);
+                binary(e, op, lhs, rhs);
+            },
+            StackOp::BinaryImmediate(op, value) => {
+                let reg = self.top(e);
+                let op = alu_op(op).expect("only ALU operations take immediate operands");
+                e.alu_imm(op, reg, value);
+            },
+            StackOp::Not => {
+                let reg = self.top(e);
+                e.unary(UnaryOp::Not, reg);
+            },
+            StackOp::LogicalNot => {
+                let reg = self.top(e);
+                e.xor32(Rax, Rax);
+                e.test(reg, reg);
+                e.setcc(Cond::E, Rax);
+                e.mov(reg, Rax);
+            },
+            StackOp::Drop(count) => {
+                let cached = self.regs.len().min(count as usize);
+                self.regs.truncate(self.regs.len() - cached);
+                let remaining = count - cached as i32;
+                if remaining > 0 {
+                    let slot = memory_slot(e, Rsp, remaining);
+                    e.lea(Rsp, slot);
+                }
+            },
+            StackOp::Swap => {
+                self.fill(e, 2);
+                let len = self.regs.len();
+                self.regs.swap(len - 1, len - 2);
+            },
+            StackOp::Rot => {
+                self.fill(e, 3);
+                let third = self.regs.remove(self.regs.len() - 3);
+                self.regs.push(third);
+            },
+        }
+    }
+}
+
+/// The `index`th slot above `base`, using %rax as the index if the displacement does not fit
+fn memory_slot(e: &mut Emitter, base: Reg, index: i32) -> Mem {
+    match index.checked_mul(8) {
+        Some(displacement) => Mem::disp(base, displacement),
+        None => {
+            e.mov_imm(Rax, index);
+            Mem::indexed(base, Rax, 8, 0)
+        },
+    }
+}
+
+/// `lhs = lhs op rhs`
+fn binary(e: &mut Emitter, op: BinaryOp, lhs: Reg, rhs: Reg) {
+    let alu_op = match op {
+        BinaryOp::Add => AluOp::Add,
+        BinaryOp::Subtract => AluOp::Sub,
+        BinaryOp::And => AluOp::And,
+        BinaryOp::Or => AluOp::Or,
+        BinaryOp::Xor => AluOp::Xor,
+        BinaryOp::Multiply => return e.imul(lhs, rhs),
+        BinaryOp::ShiftLeft => return shift(e, ShiftOp::Shl, lhs, rhs),
+        BinaryOp::ShiftRight => return shift(e, ShiftOp::Sar, lhs, rhs),
+        BinaryOp::UShiftRight => return shift(e, ShiftOp::Shr, lhs, rhs),
+        BinaryOp::Less => return compare(e, Cond::L, lhs, rhs),
+        BinaryOp::Greater => return compare(e, Cond::G, lhs, rhs),
+        BinaryOp::LessEqual => return compare(e, Cond::Le, lhs, rhs),
+        BinaryOp::GreaterEqual => return compare(e, Cond::Ge, lhs, rhs),
+        BinaryOp::ULess => return compare(e, Cond::B, lhs, rhs),
+        BinaryOp::UGreater => return compare(e, Cond::A, lhs, rhs),
+        BinaryOp::ULessEqual => return compare(e, Cond::Be, lhs, rhs),
+        BinaryOp::UGreaterEqual => return compare(e, Cond::Ae, lhs, rhs),
+        BinaryOp::Equal => return compare(e, Cond::E, lhs, rhs),
+        BinaryOp::NotEqual => return compare(e, Cond::Ne, lhs, rhs),
+    };
+    e.alu(alu_op, lhs, rhs);
+}
+
+/// The shift amount is masked to the word width
+fn shift(e: &mut Emitter, op: ShiftOp, lhs: Reg, rhs: Reg) {
+    e.mov(Rcx, rhs);
+    e.shift_cl(op, lhs);
+}
+
+/// `lhs = 1` if `cond` holds between `lhs` and `rhs`, otherwise 0
+fn compare(e: &mut Emitter, cond: Cond, lhs: Reg, rhs: Reg) {
+    e.xor32(Rax, Rax);
+    e.alu(AluOp::Cmp, lhs, rhs);
+    e.setcc(cond, Rax);
+    e.mov(lhs, Rax);
+}
+
+/// Runs `ops` with the top of the stack cached in registers, starting and ending with every value in memory
+pub(crate) fn stack_cached_code(ops: &[StackOp]) -> Vec<u8> {
+    code(|e| {
+        let mut cache = StackCache::default();
+        for &op in ops {
+            cache.apply(e, op);
+        }
+        cache.spill(e);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn pow_loop_body() {
+        use super::*;
+        // p2 p2 * s1 1 -
+        let code = stack_cached_code(&[
+            StackOp::PushStackIndex(2),
+            StackOp::PushStackIndex(2),
+            StackOp::Binary(BinaryOp::Multiply),
+            StackOp::PopStackIndex(1),
+            StackOp::PushValue(1),
+            StackOp::Binary(BinaryOp::Subtract),
+        ]);
+        // mov 0x10(%rsp),%r8 ; mov 0x8(%rsp),%r9 ; imul %r9,%r8 ; mov %r8,0x8(%rsp)
+        // mov $0x1,%r8 ; pop %r9 ; sub %r8,%r9 ; push %r9
+        assert_eq!(code, [
+            0x4c, 0x8b, 0x44, 0x24, 0x10,
+            0x4c, 0x8b, 0x4c, 0x24, 0x08,
+            0x4d, 0x0f, 0xaf, 0xc1,
+            0x4c, 0x89, 0x44, 0x24, 0x08,
+            0x49, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00,
+            0x41, 0x59,
+            0x4d, 0x29, 0xc1,
+            0x41, 0x51,
+        ]);
+    }
+
+    #[test]
+    fn stack_words_move_no_values() {
+        use super::*;
+        // a b swap c rot
+        let code = stack_cached_code(&[
+            StackOp::PushArgument(1),
+            StackOp::PushArgument(2),
+            StackOp::Swap,
+            StackOp::PushArgument(3),
+            StackOp::Rot,
+        ]);
+        // mov -0x8(%rbp),%r8 ; mov -0x10(%rbp),%r9 ; mov -0x18(%rbp),%r10 ; push %r8 ; push %r10 ; push %r9
+        assert_eq!(code, [
+            0x4c, 0x8b, 0x45, 0xf8,
+            0x4c, 0x8b, 0x4d, 0xf0,
+            0x4c, 0x8b, 0x55, 0xe8,
+            0x41, 0x50,
+            0x41, 0x52,
+            0x41, 0x51,
+        ]);
+    }
+}